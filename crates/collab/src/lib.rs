@@ -11,7 +11,7 @@ pub mod seed;
 #[cfg(test)]
 mod tests;
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context as _};
 use aws_config::{BehaviorVersion, Region};
 use axum::{http::StatusCode, response::IntoResponse};
 use db::{ChannelId, Database};
@@ -19,7 +19,8 @@ use executor::Executor;
 pub use rate_limiter::*;
 use serde::Deserialize;
 use std::{path::PathBuf, sync::Arc};
-use util::ResultExt;
+use supermaven_api::{AdminApi, SupermavenAdminApi};
+use util::{http::IsahcHttpClient, ResultExt};
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -154,6 +155,7 @@ pub struct AppState {
     pub rate_limiter: Arc<RateLimiter>,
     pub executor: Executor,
     pub clickhouse_client: Option<clickhouse::Client>,
+    pub supermaven_client: Option<Arc<dyn AdminApi>>,
     pub config: Config,
 }
 
@@ -190,6 +192,10 @@ impl AppState {
                 .clickhouse_url
                 .as_ref()
                 .and_then(|_| build_clickhouse_client(&config).log_err()),
+            supermaven_client: config
+                .supermaven_admin_api_key
+                .as_ref()
+                .and_then(|_| build_supermaven_client(&config).log_err()),
             config,
         };
         Ok(Arc::new(this))
@@ -258,3 +264,14 @@ fn build_clickhouse_client(config: &Config) -> anyhow::Result<clickhouse::Client
                 .ok_or_else(|| anyhow!("missing clickhouse_database"))?,
         ))
 }
+
+fn build_supermaven_client(config: &Config) -> anyhow::Result<Arc<dyn AdminApi>> {
+    let admin_api_key = config
+        .supermaven_admin_api_key
+        .as_ref()
+        .ok_or_else(|| anyhow!("missing supermaven_admin_api_key"))?;
+    let http_client = IsahcHttpClient::new().context("failed to create HTTP client")?;
+    let client = SupermavenAdminApi::new(admin_api_key.to_string(), Arc::new(http_client))
+        .context("invalid Supermaven admin API key")?;
+    Ok(Arc::new(client))
+}
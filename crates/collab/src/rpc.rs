@@ -34,7 +34,7 @@ pub use connection_pool::{ConnectionPool, ZedVersion};
 use core::fmt::{self, Debug, Formatter};
 use open_ai::{OpenAiEmbeddingModel, OPEN_AI_API_URL};
 use sha2::Digest;
-use supermaven_api::{CreateExternalUserRequest, SupermavenAdminApi};
+use supermaven_api::{AdminApi, CreateExternalUserRequest};
 
 use futures::{
     channel::oneshot,
@@ -149,7 +149,7 @@ struct Session {
     peer: Arc<Peer>,
     connection_pool: Arc<parking_lot::Mutex<ConnectionPool>>,
     live_kit_client: Option<Arc<dyn live_kit_server::api::Client>>,
-    supermaven_client: Option<Arc<SupermavenAdminApi>>,
+    supermaven_client: Option<Arc<dyn AdminApi>>,
     http_client: Arc<IsahcHttpClient>,
     rate_limiter: Arc<RateLimiter>,
     _executor: Executor,
@@ -964,15 +964,6 @@ impl Server {
                 }
             };
 
-            let supermaven_client = if let Some(supermaven_admin_api_key) = this.app_state.config.supermaven_admin_api_key.clone() {
-                Some(Arc::new(SupermavenAdminApi::new(
-                    supermaven_admin_api_key.to_string(),
-                    http_client.clone(),
-                )))
-            } else {
-                None
-            };
-
             let session = Session {
                 principal: principal.clone(),
                 connection_id,
@@ -983,7 +974,7 @@ impl Server {
                 http_client,
                 rate_limiter: this.app_state.rate_limiter.clone(),
                 _executor: executor.clone(),
-                supermaven_client,
+                supermaven_client: this.app_state.supermaven_client.clone(),
             };
 
             if let Err(error) = this.send_initial_client_update(connection_id, &principal, zed_version, send_connection_id, &session).await {
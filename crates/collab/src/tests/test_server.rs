@@ -626,6 +626,7 @@ impl TestServer {
             rate_limiter: Arc::new(RateLimiter::new(test_db.db().clone())),
             executor,
             clickhouse_client: None,
+            supermaven_client: None,
             config: Config {
                 http_port: 0,
                 database_url: "".into(),
@@ -0,0 +1,61 @@
+use postage::watch;
+
+/// The repository the agent considers itself to be working in, as reported by its
+/// `active_repo` messages. This can be set before any file has been sent to the agent.
+pub struct ActiveRepoState {
+    tx: watch::Sender<Option<String>>,
+    rx: watch::Receiver<Option<String>>,
+}
+
+impl ActiveRepoState {
+    pub fn new() -> Self {
+        let (tx, rx) = watch::channel();
+        Self { tx, rx }
+    }
+
+    pub fn repo_simple_name(&self) -> Option<String> {
+        self.rx.borrow().clone()
+    }
+
+    /// Records the latest repo name, notifying subscribers if it changed.
+    pub fn update(&mut self, repo_simple_name: Option<String>) {
+        if *self.rx.borrow() != repo_simple_name {
+            *self.tx.borrow_mut() = repo_simple_name;
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<String>> {
+        self.rx.clone()
+    }
+}
+
+impl Default for ActiveRepoState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postage::stream::Stream;
+
+    #[test]
+    fn test_active_repo_updates_notify() {
+        let mut state = ActiveRepoState::new();
+        assert_eq!(state.repo_simple_name(), None);
+
+        let mut rx = state.subscribe();
+        while rx.try_recv().is_ok() {}
+
+        state.update(Some("zed".into()));
+        assert_eq!(state.repo_simple_name().as_deref(), Some("zed"));
+        assert_eq!(rx.try_recv().ok(), Some(Some("zed".to_string())));
+
+        state.update(Some("zed".into()));
+        assert!(rx.try_recv().is_err());
+
+        state.update(None);
+        assert_eq!(rx.try_recv().ok(), Some(None));
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+/// How long an apology from the agent is considered current.
+pub const APOLOGY_TTL: Duration = Duration::from_secs(30);
+
+/// The agent's latest apology, which usually means completions are temporarily unavailable.
+/// It is cleared by the next response, or once it is older than the TTL.
+#[derive(Debug)]
+pub struct ApologyState {
+    ttl: Duration,
+    latest: Option<Apology>,
+}
+
+#[derive(Debug)]
+struct Apology {
+    message: Option<String>,
+    received_at: Instant,
+}
+
+impl ApologyState {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, latest: None }
+    }
+
+    pub fn record(&mut self, message: Option<String>, now: Instant) {
+        self.latest = Some(Apology {
+            message,
+            received_at: now,
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+    }
+
+    pub fn is_apologizing(&self, now: Instant) -> bool {
+        self.latest.as_ref().map_or(false, |apology| {
+            now.duration_since(apology.received_at) < self.ttl
+        })
+    }
+
+    /// The reason given by a current apology, if the agent gave one.
+    pub fn message(&self, now: Instant) -> Option<&str> {
+        if self.is_apologizing(now) {
+            self.latest.as_ref()?.message.as_deref()
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for ApologyState {
+    fn default() -> Self {
+        Self::new(APOLOGY_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apology_state() {
+        let start = Instant::now();
+        let mut state = ApologyState::new(Duration::from_secs(10));
+        assert!(!state.is_apologizing(start));
+
+        state.record(Some("servers are overloaded".into()), start);
+        assert!(state.is_apologizing(start + Duration::from_secs(9)));
+        assert_eq!(
+            state.message(start + Duration::from_secs(9)),
+            Some("servers are overloaded")
+        );
+
+        assert!(!state.is_apologizing(start + Duration::from_secs(10)));
+        assert_eq!(state.message(start + Duration::from_secs(10)), None);
+
+        state.record(None, start + Duration::from_secs(20));
+        assert!(state.is_apologizing(start + Duration::from_secs(21)));
+        assert_eq!(state.message(start + Duration::from_secs(21)), None);
+
+        state.clear();
+        assert!(!state.is_apologizing(start + Duration::from_secs(21)));
+    }
+}
@@ -0,0 +1,372 @@
+use crate::messages::ResponseItem;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Assembles the text of a completion from the [`ResponseItem`]s the agent streams for a
+/// state.
+///
+/// - `Text` appends to the completion.
+/// - `Del` removes previously streamed text from the end of the completion, if it matches.
+/// - `Dedent` records whitespace before the cursor that the completion replaces.
+/// - `End` and `Barrier` finish the completion, and any later items are ignored.
+///
+/// The agent's protocol has no positional inserts: text is only ever appended at the end of
+/// the completion, and the only ways to affect text before it are `Del` (of text the agent
+/// streamed itself) and `Dedent` (of whitespace before the cursor). A `Del` that doesn't match
+/// the end of the completion is ignored and marks the completion as malformed.
+///
+/// Optional limits on the number of characters and lines cap how long a completion can grow.
+/// Text past a limit is dropped, along with any later `Text` and `Del` items.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompletionBuilder {
+    text: String,
+    char_count: usize,
+    newline_count: usize,
+    max_chars: Option<usize>,
+    max_lines: Option<usize>,
+    dedent: String,
+    finished: bool,
+    stopped_at_barrier: bool,
+    malformed: bool,
+    truncated: bool,
+}
+
+impl CompletionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Caps the completion at `max_chars` characters.
+    pub fn with_max_chars(mut self, max_chars: usize) -> Self {
+        self.max_chars = Some(max_chars);
+        self
+    }
+
+    /// Caps the completion at `max_lines` lines, cutting it off before the newline that
+    /// would start the next one. The first line is always kept.
+    pub fn with_max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = Some(max_lines);
+        self
+    }
+
+    pub fn from_items<'a>(items: impl IntoIterator<Item = &'a ResponseItem>) -> Self {
+        let mut builder = Self::new();
+        builder.extend(items);
+        builder
+    }
+
+    pub fn push(&mut self, item: &ResponseItem) {
+        if self.finished {
+            return;
+        }
+
+        match item {
+            // Once truncated, the agent's view of the text no longer matches ours.
+            ResponseItem::Text { .. } | ResponseItem::Del { .. } if self.truncated => {}
+            ResponseItem::Text { text } => self.push_text(text),
+            ResponseItem::Del { text } => {
+                if self.text.ends_with(text.as_str()) {
+                    self.text.truncate(self.text.len() - text.len());
+                    self.char_count -= text.chars().count();
+                    self.newline_count -= text.matches('\n').count();
+                } else {
+                    self.malformed = true;
+                }
+            }
+            ResponseItem::Dedent { text } => self.dedent.push_str(text),
+            ResponseItem::End => self.finished = true,
+            ResponseItem::Barrier => {
+                self.finished = true;
+                self.stopped_at_barrier = true;
+            }
+        }
+    }
+
+    fn push_text(&mut self, text: &str) {
+        let mut end = text.len();
+        for (ix, ch) in text.char_indices() {
+            let exceeds_chars = self
+                .max_chars
+                .map_or(false, |max_chars| self.char_count >= max_chars);
+            let exceeds_lines = ch == '\n'
+                && self
+                    .max_lines
+                    .map_or(false, |max_lines| self.newline_count + 1 >= max_lines);
+            if exceeds_chars || exceeds_lines {
+                end = ix;
+                self.truncated = true;
+                break;
+            }
+            self.char_count += 1;
+            if ch == '\n' {
+                self.newline_count += 1;
+            }
+        }
+        self.text.push_str(&text[..end]);
+    }
+
+    pub fn extend<'a>(&mut self, items: impl IntoIterator<Item = &'a ResponseItem>) {
+        for item in items {
+            self.push(item);
+        }
+    }
+
+    /// The completion text assembled so far.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Whitespace preceding the cursor that should be removed when the completion is
+    /// accepted.
+    pub fn dedent(&self) -> &str {
+        &self.dedent
+    }
+
+    /// Whether an `End` or `Barrier` has been seen.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Whether the agent tried to delete text it hadn't streamed, in which case the completion
+    /// shouldn't be trusted.
+    pub fn is_malformed(&self) -> bool {
+        self.malformed
+    }
+
+    /// Whether the completion spans more than one line. A single trailing newline ends the
+    /// first line rather than starting a second one.
+    pub fn is_multiline(&self) -> bool {
+        let text = self.text.strip_suffix('\n').unwrap_or(&self.text);
+        text.contains('\n')
+    }
+
+    /// The completion up to its first newline, e.g. for rendering it as inline ghost text.
+    pub fn first_line(&self) -> &str {
+        self.text.split('\n').next().unwrap_or_default()
+    }
+
+    /// Splits the completion after its next word, for accepting it one word at a time. Returns
+    /// the text to insert, which includes any whitespace before the word, and the rest of the
+    /// completion. Words follow Unicode word boundaries, so punctuation such as the `.` in
+    /// `foo.bar` is a word of its own.
+    pub fn accept_next_word(&self) -> (&str, &str) {
+        let mut end = 0;
+        for (ix, segment) in self.text.split_word_bound_indices() {
+            end = ix + segment.len();
+            if !segment.chars().all(char::is_whitespace) {
+                break;
+            }
+        }
+        self.text.split_at(end)
+    }
+
+    /// Splits the completion after its first line break, for accepting it one line at a time.
+    /// Returns the text to insert, which ends with the line break (`\n` or `\r\n`), and the rest
+    /// of the completion. Without a line break, the whole completion is accepted.
+    pub fn accept_next_line(&self) -> (&str, &str) {
+        let end = self.text.find('\n').map_or(self.text.len(), |ix| ix + 1);
+        self.text.split_at(end)
+    }
+
+    /// Whether text was dropped because the completion reached its character or line limit.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Whether the completion was cut off by a `Barrier` rather than an `End`.
+    pub fn stopped_at_barrier(&self) -> bool {
+        self.stopped_at_barrier
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> ResponseItem {
+        ResponseItem::Text { text: text.into() }
+    }
+
+    #[test]
+    fn test_text() {
+        let builder = CompletionBuilder::from_items(&[text("fn main"), text("() {}")]);
+        assert_eq!(builder.text(), "fn main() {}");
+        assert!(!builder.is_finished());
+    }
+
+    #[test]
+    fn test_del() {
+        let builder = CompletionBuilder::from_items(&[
+            text("let x = 1;"),
+            ResponseItem::Del { text: "1;".into() },
+            ResponseItem::Del {
+                text: "missing".into(),
+            },
+            text("2;"),
+        ]);
+        assert_eq!(builder.text(), "let x = 2;");
+        assert!(builder.is_malformed());
+
+        let builder = CompletionBuilder::from_items(&[
+            text("let x = 1;"),
+            ResponseItem::Del { text: "1;".into() },
+        ]);
+        assert!(!builder.is_malformed());
+    }
+
+    #[test]
+    fn test_dedent() {
+        let builder = CompletionBuilder::from_items(&[
+            ResponseItem::Dedent {
+                text: "    ".into(),
+            },
+            text("}"),
+        ]);
+        assert_eq!(builder.text(), "}");
+        assert_eq!(builder.dedent(), "    ");
+    }
+
+    #[test]
+    fn test_end() {
+        let builder = CompletionBuilder::from_items(&[text("a"), ResponseItem::End, text("b")]);
+        assert_eq!(builder.text(), "a");
+        assert!(builder.is_finished());
+        assert!(!builder.stopped_at_barrier());
+    }
+
+    #[test]
+    fn test_barrier() {
+        let builder =
+            CompletionBuilder::from_items(&[text("a)"), ResponseItem::Barrier, text(";")]);
+        assert_eq!(builder.text(), "a)");
+        assert!(builder.is_finished());
+        assert!(builder.stopped_at_barrier());
+    }
+
+    #[test]
+    fn test_multiline() {
+        let builder = CompletionBuilder::from_items(&[text("let x = 1;")]);
+        assert!(!builder.is_multiline());
+        assert_eq!(builder.first_line(), "let x = 1;");
+
+        let builder = CompletionBuilder::from_items(&[text("let x = 1;"), text("\n")]);
+        assert!(!builder.is_multiline());
+        assert_eq!(builder.first_line(), "let x = 1;");
+
+        let builder = CompletionBuilder::from_items(&[text("if x {\n"), text("    y();\n}")]);
+        assert!(builder.is_multiline());
+        assert_eq!(builder.first_line(), "if x {");
+
+        let builder = CompletionBuilder::from_items(&[text("\n\n")]);
+        assert!(builder.is_multiline());
+        assert_eq!(builder.first_line(), "");
+
+        assert!(!CompletionBuilder::new().is_multiline());
+        assert_eq!(CompletionBuilder::new().first_line(), "");
+    }
+
+    #[test]
+    fn test_accept_next_word() {
+        let builder = CompletionBuilder::from_items(&[text("foo bar  baz")]);
+        assert_eq!(builder.accept_next_word(), ("foo", " bar  baz"));
+
+        let builder = CompletionBuilder::from_items(&[text("  bar  baz")]);
+        assert_eq!(builder.accept_next_word(), ("  bar", "  baz"));
+
+        let builder = CompletionBuilder::from_items(&[text("self.naïve_café(x)")]);
+        assert_eq!(builder.accept_next_word(), ("self", ".naïve_café(x)"));
+        let builder = CompletionBuilder::from_items(&[text(".naïve_café(x)")]);
+        assert_eq!(builder.accept_next_word(), (".", "naïve_café(x)"));
+        let builder = CompletionBuilder::from_items(&[text("naïve_café(x)")]);
+        assert_eq!(builder.accept_next_word(), ("naïve_café", "(x)"));
+
+        let builder = CompletionBuilder::from_items(&[text("don't stop")]);
+        assert_eq!(builder.accept_next_word(), ("don't", " stop"));
+
+        let builder = CompletionBuilder::from_items(&[text(" \n")]);
+        assert_eq!(builder.accept_next_word(), (" \n", ""));
+        assert_eq!(CompletionBuilder::new().accept_next_word(), ("", ""));
+    }
+
+    #[test]
+    fn test_accept_next_line() {
+        let builder = CompletionBuilder::from_items(&[text("if x {\n    y();\n}")]);
+        let (line, rest) = builder.accept_next_line();
+        assert_eq!((line, rest), ("if x {\n", "    y();\n}"));
+        let builder = CompletionBuilder::from_items(&[text(rest)]);
+        assert_eq!(builder.accept_next_line(), ("    y();\n", "}"));
+
+        let builder = CompletionBuilder::from_items(&[text("if x {\r\n    y();\r\n}")]);
+        assert_eq!(builder.accept_next_line(), ("if x {\r\n", "    y();\r\n}"));
+
+        let builder = CompletionBuilder::from_items(&[text("\n}")]);
+        assert_eq!(builder.accept_next_line(), ("\n", "}"));
+
+        let builder = CompletionBuilder::from_items(&[text("y();")]);
+        assert_eq!(builder.accept_next_line(), ("y();", ""));
+    }
+
+    #[test]
+    fn test_max_chars() {
+        let mut builder = CompletionBuilder::new().with_max_chars(5);
+        builder.extend(&[text("añ"), text("bÿ€ç"), text("d")]);
+        assert_eq!(builder.text(), "añbÿ€");
+        assert!(builder.is_truncated());
+
+        builder.extend(&[ResponseItem::Del { text: "ç".into() }, ResponseItem::End]);
+        assert!(!builder.is_malformed());
+        assert!(builder.is_finished());
+
+        let mut builder = CompletionBuilder::new().with_max_chars(3);
+        builder.extend(&[text("ab"), text("c")]);
+        assert_eq!(builder.text(), "abc");
+        assert!(!builder.is_truncated());
+
+        let mut builder = CompletionBuilder::new().with_max_chars(3);
+        builder.extend(&[
+            text("abc"),
+            ResponseItem::Del { text: "bc".into() },
+            text("de"),
+        ]);
+        assert_eq!(builder.text(), "ade");
+        assert!(!builder.is_truncated());
+    }
+
+    #[test]
+    fn test_max_lines() {
+        let mut builder = CompletionBuilder::new().with_max_lines(2);
+        builder.extend(&[text("if x {\n    y();"), text("\n}\n")]);
+        assert_eq!(builder.text(), "if x {\n    y();");
+        assert!(builder.is_truncated());
+
+        let mut builder = CompletionBuilder::new().with_max_lines(2).with_max_chars(8);
+        builder.extend(&[text("ab\ncdéfghij")]);
+        assert_eq!(builder.text(), "ab\ncdéfg");
+        assert!(builder.is_truncated());
+
+        let mut builder = CompletionBuilder::new().with_max_lines(2);
+        builder.extend(&[text("a\nb")]);
+        assert_eq!(builder.text(), "a\nb");
+        assert!(!builder.is_truncated());
+    }
+
+    #[test]
+    fn test_combined() {
+        let mut builder = CompletionBuilder::new();
+        builder.extend(&[
+            ResponseItem::Dedent { text: "  ".into() },
+            text("if x {\n"),
+            text("    retrun"),
+        ]);
+        builder.extend(&[
+            ResponseItem::Del {
+                text: "retrun".into(),
+            },
+            text("return;\n}"),
+            ResponseItem::End,
+            text("ignored"),
+        ]);
+        assert_eq!(builder.text(), "if x {\n    return;\n}");
+        assert_eq!(builder.dedent(), "  ");
+        assert!(builder.is_finished());
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{messages::SupermavenResponse, CompletionBuilder};
+
+/// Renders what accepting `response` at the cursor would do to the cursor's line as a
+/// line-based diff, for previewing a completion before it is accepted. `before_cursor` and
+/// `after_cursor` are the buffer text on either side of the cursor; only the cursor's line is
+/// affected, so other lines in them are ignored.
+///
+/// Unchanged lines are prefixed with a space, removed lines with `-` and added lines with `+`.
+/// `Dedent` whitespace is removed from before the cursor, and `Del` items only ever retract
+/// text the agent streamed itself, so they are reflected in the inserted text.
+pub fn completion_diff(
+    before_cursor: &str,
+    after_cursor: &str,
+    response: &SupermavenResponse,
+) -> String {
+    let completion = CompletionBuilder::from_items(&response.items);
+
+    let prefix = &before_cursor[before_cursor.rfind('\n').map_or(0, |ix| ix + 1)..];
+    let suffix = &after_cursor[..after_cursor.find('\n').unwrap_or(after_cursor.len())];
+    let dedented_prefix = prefix.strip_suffix(completion.dedent()).unwrap_or(prefix);
+
+    let old = format!("{prefix}{suffix}");
+    let new = format!("{dedented_prefix}{}{suffix}", completion.text());
+    let old_lines = old.split('\n').collect::<Vec<_>>();
+    let new_lines = new.split('\n').collect::<Vec<_>>();
+
+    let common_prefix = old_lines
+        .iter()
+        .zip(&new_lines)
+        .take_while(|(old, new)| old == new)
+        .count();
+    let common_suffix = old_lines[common_prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[common_prefix..].iter().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+
+    let mut diff = String::new();
+    let mut push_line = |marker: char, line: &str| {
+        diff.push(marker);
+        diff.push_str(line);
+        diff.push('\n');
+    };
+    for line in &old_lines[..common_prefix] {
+        push_line(' ', line);
+    }
+    for line in &old_lines[common_prefix..old_lines.len() - common_suffix] {
+        push_line('-', line);
+    }
+    for line in &new_lines[common_prefix..new_lines.len() - common_suffix] {
+        push_line('+', line);
+    }
+    for line in &old_lines[old_lines.len() - common_suffix..] {
+        push_line(' ', line);
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::ResponseItem;
+
+    fn text(text: &str) -> ResponseItem {
+        ResponseItem::Text { text: text.into() }
+    }
+
+    fn response(items: Vec<ResponseItem>) -> SupermavenResponse {
+        SupermavenResponse {
+            state_id: "1".into(),
+            items,
+        }
+    }
+
+    #[test]
+    fn test_insert() {
+        let diff = completion_diff(
+            "fn main() {\n    let x",
+            "\n}\n",
+            &response(vec![text(" = 1;"), ResponseItem::End]),
+        );
+        assert_eq!(diff, "-    let x\n+    let x = 1;\n");
+
+        let diff = completion_diff(
+            "fn main() {",
+            "}",
+            &response(vec![text("\n    body();\n"), ResponseItem::End]),
+        );
+        assert_eq!(diff, "-fn main() {}\n+fn main() {\n+    body();\n+}\n");
+
+        let diff = completion_diff("a", "", &response(vec![text("\nb")]));
+        assert_eq!(diff, " a\n+b\n");
+    }
+
+    #[test]
+    fn test_insert_and_delete() {
+        let diff = completion_diff(
+            "    ",
+            "",
+            &response(vec![
+                text("let y"),
+                ResponseItem::Del { text: "y".into() },
+                text("x = 2;"),
+                ResponseItem::End,
+            ]),
+        );
+        assert_eq!(diff, "-    \n+    let x = 2;\n");
+    }
+
+    #[test]
+    fn test_dedent() {
+        let diff = completion_diff(
+            "if x {\n        ",
+            "",
+            &response(vec![
+                ResponseItem::Dedent {
+                    text: "    ".into(),
+                },
+                text("}"),
+                ResponseItem::End,
+            ]),
+        );
+        assert_eq!(diff, "-        \n+    }\n");
+
+        // Whitespace that isn't actually before the cursor can't be removed.
+        let diff = completion_diff(
+            "x",
+            "",
+            &response(vec![ResponseItem::Dedent { text: "  ".into() }, text(";")]),
+        );
+        assert_eq!(diff, "-x\n+x;\n");
+    }
+}
@@ -0,0 +1,81 @@
+use crate::messages::SupermavenMetadataMessage;
+
+/// Rejects completions made up entirely of the "dust" strings announced by the agent's
+/// metadata message, interleaved with whitespace.
+#[derive(Clone, Debug, Default)]
+pub struct DustFilter {
+    dust_strings: Vec<String>,
+}
+
+impl DustFilter {
+    pub fn new(dust_strings: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            dust_strings: dust_strings
+                .into_iter()
+                .filter(|dust| !dust.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn from_metadata(metadata: &SupermavenMetadataMessage) -> Self {
+        Self::new(metadata.dust_strings.iter().flatten().cloned())
+    }
+
+    /// Whether `completion` contains nothing but dust strings and whitespace. A completion
+    /// with no dust at all isn't considered dust, even if it is blank.
+    pub fn is_dust(&self, completion: &str) -> bool {
+        let completion = completion.trim();
+        if completion.is_empty() || self.dust_strings.is_empty() {
+            return false;
+        }
+
+        // `reachable[i]` is true when `completion[..i]` can be made of dust and whitespace.
+        let mut reachable = vec![false; completion.len() + 1];
+        reachable[0] = true;
+        for start in 0..completion.len() {
+            if !reachable[start] || !completion.is_char_boundary(start) {
+                continue;
+            }
+            let rest = &completion[start..];
+            if let Some(whitespace) = rest.chars().next().filter(|c| c.is_whitespace()) {
+                reachable[start + whitespace.len_utf8()] = true;
+            }
+            for dust in &self.dust_strings {
+                if rest.starts_with(dust.as_str()) {
+                    reachable[start + dust.len()] = true;
+                }
+            }
+        }
+        reachable[completion.len()]
+    }
+
+    /// Returns the completion unless it is dust.
+    pub fn filter<'a>(&self, completion: &'a str) -> Option<&'a str> {
+        (!self.is_dust(completion)).then_some(completion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dust_filter() {
+        let filter = DustFilter::from_metadata(&SupermavenMetadataMessage {
+            dust_strings: Some(vec!["}".into(), ");".into(), String::new()]),
+        });
+
+        assert!(filter.is_dust("}"));
+        assert!(filter.is_dust("  );\n}"));
+        assert!(filter.is_dust("}}"));
+        assert_eq!(filter.filter("}"), None);
+
+        assert!(!filter.is_dust("x }"));
+        assert!(!filter.is_dust(")"));
+        assert!(!filter.is_dust(""));
+        assert_eq!(filter.filter("return x;"), Some("return x;"));
+
+        let filter = DustFilter::default();
+        assert!(!filter.is_dust("}"));
+    }
+}
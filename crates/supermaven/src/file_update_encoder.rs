@@ -0,0 +1,463 @@
+#[cfg(feature = "file-deltas")]
+use crate::messages::{FileDeltaUpdateMessage, FileEdit};
+use crate::{
+    messages::{FileUpdateMessage, StateUpdate},
+    SupermavenCompletionStateId,
+};
+use collections::{FxHasher, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// When deltas are enabled, files smaller than this are sent in full by default, since a
+/// delta saves little for them.
+#[cfg(feature = "file-deltas")]
+pub const DEFAULT_FULL_UPDATE_THRESHOLD: usize = 4 * 1024;
+
+/// Encodes the file updates sent to the agent, skipping the update entirely when the content
+/// hasn't changed.
+///
+/// Content is always sent in full. The agent has no `file_delta_update` message, and it
+/// ignores messages it can't parse, so sending it a delta would leave it with a stale copy of
+/// the file. Compressing the content isn't an option for the same reason. Agents that do
+/// understand deltas can be sent them with the `file-deltas` feature and [`Self::with_deltas`].
+pub struct FileUpdateEncoder {
+    snapshots: HashMap<String, Snapshot>,
+    #[cfg(feature = "file-deltas")]
+    send_deltas: bool,
+    #[cfg(feature = "file-deltas")]
+    full_update_threshold: usize,
+}
+
+impl Default for FileUpdateEncoder {
+    fn default() -> Self {
+        Self {
+            snapshots: HashMap::default(),
+            #[cfg(feature = "file-deltas")]
+            send_deltas: false,
+            #[cfg(feature = "file-deltas")]
+            full_update_threshold: DEFAULT_FULL_UPDATE_THRESHOLD,
+        }
+    }
+}
+
+struct Snapshot {
+    #[cfg(feature = "file-deltas")]
+    state_id: SupermavenCompletionStateId,
+    #[cfg(feature = "file-deltas")]
+    content: String,
+    content_hash: u64,
+}
+
+impl FileUpdateEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends a `FileDeltaUpdate` against the content last sent for a path when one can be
+    /// computed. Only for agents that understand deltas, which the released agent doesn't.
+    #[cfg(feature = "file-deltas")]
+    pub fn with_deltas(mut self) -> Self {
+        self.send_deltas = true;
+        self
+    }
+
+    /// Sends files whose content is smaller than `threshold` bytes in full, even when a delta
+    /// could be sent. A threshold of zero sends a delta whenever possible.
+    #[cfg(feature = "file-deltas")]
+    pub fn with_full_update_threshold(mut self, threshold: usize) -> Self {
+        self.full_update_threshold = threshold;
+        self
+    }
+
+    /// Encodes the update for `path` that will be sent as part of `state_id`. With deltas
+    /// enabled, falls back to a full `FileUpdate` when nothing has been sent for the path yet
+    /// or the content is below the full update threshold. Returns `None` when the content is
+    /// the same as what was last sent.
+    pub fn encode(
+        &mut self,
+        state_id: SupermavenCompletionStateId,
+        path: String,
+        content: String,
+        language: Option<String>,
+    ) -> Option<StateUpdate> {
+        let content_hash = content_hash(&content);
+        if self.is_unchanged(&path, content_hash) {
+            return None;
+        }
+        Some(self.encode_changed(state_id, path, content, content_hash, language))
+    }
+
+    /// Like [`Self::encode`], but sends the full content when it is the same as what was last
+    /// sent instead of skipping the update, so that the state carries the file either way.
+    /// Used for the buffer being edited, which every state a completion is requested for must
+    /// include.
+    pub fn encode_current(
+        &mut self,
+        state_id: SupermavenCompletionStateId,
+        path: String,
+        content: String,
+        language: Option<String>,
+    ) -> StateUpdate {
+        let content_hash = content_hash(&content);
+        if self.is_unchanged(&path, content_hash) {
+            self.snapshots.remove(&path);
+        }
+        self.encode_changed(state_id, path, content, content_hash, language)
+    }
+
+    fn is_unchanged(&self, path: &str, content_hash: u64) -> bool {
+        self.snapshots
+            .get(path)
+            .map_or(false, |snapshot| snapshot.content_hash == content_hash)
+    }
+
+    fn encode_changed(
+        &mut self,
+        state_id: SupermavenCompletionStateId,
+        path: String,
+        content: String,
+        content_hash: u64,
+        language: Option<String>,
+    ) -> StateUpdate {
+        #[cfg(feature = "file-deltas")]
+        {
+            let delta = self
+                .snapshots
+                .get(&path)
+                .filter(|_| self.send_deltas && content.len() >= self.full_update_threshold)
+                .map(|snapshot| {
+                    StateUpdate::FileDeltaUpdate(FileDeltaUpdateMessage {
+                        path: path.clone(),
+                        base_state_id: snapshot.state_id,
+                        edits: diff(&snapshot.content, &content).into_iter().collect(),
+                    })
+                });
+            self.snapshots.insert(
+                path.clone(),
+                Snapshot {
+                    state_id,
+                    content: content.clone(),
+                    content_hash,
+                },
+            );
+            if let Some(delta) = delta {
+                return delta;
+            }
+        }
+        #[cfg(not(feature = "file-deltas"))]
+        {
+            let _ = state_id;
+            self.snapshots
+                .insert(path.clone(), Snapshot { content_hash });
+        }
+
+        StateUpdate::FileUpdate(FileUpdateMessage {
+            path,
+            content,
+            language,
+        })
+    }
+
+    /// Forgets the content sent for `path`, e.g. because the file changed on disk, so that the
+    /// next update for it carries the full content.
+    pub fn invalidate(&mut self, path: &str) {
+        self.snapshots.remove(path);
+    }
+
+    /// Forgets the content sent for every path, so that each file's next update carries its
+    /// full content.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+}
+
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = FxHasher::default();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Computes a single edit replacing the region between the common prefix and suffix of the two
+/// strings, or `None` when they are equal.
+#[cfg(feature = "file-deltas")]
+fn diff(old: &str, new: &str) -> Option<FileEdit> {
+    if old == new {
+        return None;
+    }
+
+    let mut prefix = old
+        .bytes()
+        .zip(new.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(prefix) || !new.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let mut suffix = old
+        .bytes()
+        .rev()
+        .zip(new.bytes().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+
+    Some(FileEdit {
+        range: prefix..old.len() - suffix,
+        replacement: new[prefix..new.len() - suffix].to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "file-deltas")]
+    fn encode_both(old: &str, new: &str) -> Option<StateUpdate> {
+        let mut encoder = FileUpdateEncoder::new()
+            .with_deltas()
+            .with_full_update_threshold(0);
+        encoder.encode(
+            SupermavenCompletionStateId(0),
+            "a.rs".into(),
+            old.to_string(),
+            None,
+        );
+        encoder.encode(
+            SupermavenCompletionStateId(1),
+            "a.rs".into(),
+            new.to_string(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_deltas_are_opt_in() {
+        let large = "x".repeat(16 * 1024);
+        let mut encoder = FileUpdateEncoder::new();
+        for id in 0..3 {
+            let update = encoder.encode(
+                SupermavenCompletionStateId(id),
+                "large.rs".into(),
+                format!("{large}{id}"),
+                None,
+            );
+            assert!(
+                matches!(update, Some(StateUpdate::FileUpdate(_))),
+                "{update:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_first_update_is_full() {
+        let mut encoder = FileUpdateEncoder::new();
+        let update = encoder.encode(
+            SupermavenCompletionStateId(0),
+            "a.rs".into(),
+            "fn".into(),
+            None,
+        );
+        assert!(matches!(update, Some(StateUpdate::FileUpdate(_))));
+    }
+
+    #[cfg(feature = "file-deltas")]
+    #[test]
+    fn test_delta_reproduces_content() {
+        for (old, new) in [
+            ("fn main() {}", "fn main() { x }"),
+            ("", "hello"),
+            ("hello", ""),
+            ("aaaa", "aa"),
+            ("héllo", "hëllo"),
+            ("let x = 1;\n", "let y = 1;\nlet x = 1;\n"),
+        ] {
+            let Some(StateUpdate::FileDeltaUpdate(delta)) = encode_both(old, new) else {
+                panic!("expected a delta update");
+            };
+            assert_eq!(delta.base_state_id, SupermavenCompletionStateId(0));
+            assert_eq!(delta.apply(old).unwrap(), new, "{old:?} -> {new:?}");
+        }
+    }
+
+    #[test]
+    fn test_invalidate_sends_full_content() {
+        let mut encoder = FileUpdateEncoder::new();
+        encoder.encode(
+            SupermavenCompletionStateId(0),
+            "a.rs".into(),
+            "a".into(),
+            None,
+        );
+        encoder.invalidate("a.rs");
+        let update = encoder.encode(
+            SupermavenCompletionStateId(1),
+            "a.rs".into(),
+            "b".into(),
+            None,
+        );
+        assert!(matches!(update, Some(StateUpdate::FileUpdate(_))));
+
+        encoder.clear();
+        let update = encoder.encode(
+            SupermavenCompletionStateId(2),
+            "a.rs".into(),
+            "b".into(),
+            None,
+        );
+        assert!(matches!(update, Some(StateUpdate::FileUpdate(_))));
+    }
+
+    #[test]
+    fn test_unchanged_content_is_suppressed() {
+        let mut encoder = FileUpdateEncoder::new();
+        for (id, expected) in [(0, true), (1, false)] {
+            let update = encoder.encode(
+                SupermavenCompletionStateId(id),
+                "a.rs".into(),
+                "abc".into(),
+                None,
+            );
+            assert_eq!(update.is_some(), expected);
+        }
+    }
+
+    #[cfg(feature = "file-deltas")]
+    #[test]
+    fn test_unchanged_content_keeps_delta_base() {
+        assert!(encode_both("abc", "abc").is_none());
+
+        let mut encoder = FileUpdateEncoder::new()
+            .with_deltas()
+            .with_full_update_threshold(0);
+        for id in 0..2 {
+            encoder.encode(
+                SupermavenCompletionStateId(id),
+                "a.rs".into(),
+                "a".into(),
+                None,
+            );
+        }
+        let Some(StateUpdate::FileDeltaUpdate(delta)) = encoder.encode(
+            SupermavenCompletionStateId(2),
+            "a.rs".into(),
+            "ab".into(),
+            None,
+        ) else {
+            panic!("expected a delta update");
+        };
+        assert_eq!(delta.base_state_id, SupermavenCompletionStateId(0));
+    }
+
+    #[test]
+    fn test_encode_current_always_includes_file() {
+        let mut encoder = FileUpdateEncoder::new();
+        for id in 0..2 {
+            let update = encoder.encode_current(
+                SupermavenCompletionStateId(id),
+                "a.rs".into(),
+                "a".into(),
+                None,
+            );
+            let StateUpdate::FileUpdate(file) = update else {
+                panic!("expected a full update, got {update:?}");
+            };
+            assert_eq!(file.content, "a");
+        }
+        assert!(encoder
+            .encode(
+                SupermavenCompletionStateId(2),
+                "a.rs".into(),
+                "a".into(),
+                None
+            )
+            .is_none());
+    }
+
+    #[cfg(feature = "file-deltas")]
+    #[test]
+    fn test_encode_current_updates_delta_base() {
+        let mut encoder = FileUpdateEncoder::new()
+            .with_deltas()
+            .with_full_update_threshold(0);
+        for id in 0..2 {
+            let update = encoder.encode_current(
+                SupermavenCompletionStateId(id),
+                "a.rs".into(),
+                "a".into(),
+                None,
+            );
+            let StateUpdate::FileUpdate(file) = update else {
+                panic!("expected a full update, got {update:?}");
+            };
+            assert_eq!(file.content, "a");
+        }
+
+        let Some(StateUpdate::FileDeltaUpdate(delta)) = encoder.encode(
+            SupermavenCompletionStateId(2),
+            "a.rs".into(),
+            "ab".into(),
+            None,
+        ) else {
+            panic!("expected a delta update");
+        };
+        assert_eq!(delta.base_state_id, SupermavenCompletionStateId(1));
+    }
+
+    #[cfg(feature = "file-deltas")]
+    #[test]
+    fn test_full_update_threshold() {
+        let small = "fn main() {}\n".to_string();
+        let large = "x".repeat(DEFAULT_FULL_UPDATE_THRESHOLD);
+        let mut encoder = FileUpdateEncoder::new().with_deltas();
+
+        encoder.encode(
+            SupermavenCompletionStateId(0),
+            "small.rs".into(),
+            small.clone(),
+            None,
+        );
+        let update = encoder.encode(
+            SupermavenCompletionStateId(1),
+            "small.rs".into(),
+            small + "// done\n",
+            None,
+        );
+        assert!(matches!(update, Some(StateUpdate::FileUpdate(_))));
+
+        let update = encoder.encode(
+            SupermavenCompletionStateId(2),
+            "large.rs".into(),
+            large.clone(),
+            None,
+        );
+        assert!(matches!(update, Some(StateUpdate::FileUpdate(_))));
+
+        let update = encoder.encode(
+            SupermavenCompletionStateId(3),
+            "large.rs".into(),
+            large.clone() + "y",
+            None,
+        );
+        let Some(StateUpdate::FileDeltaUpdate(delta)) = update else {
+            panic!("expected a delta update, got {update:?}");
+        };
+        assert_eq!(delta.base_state_id, SupermavenCompletionStateId(2));
+
+        let mut encoder = FileUpdateEncoder::new()
+            .with_deltas()
+            .with_full_update_threshold(usize::MAX);
+        for id in 0..2 {
+            let update = encoder.encode(
+                SupermavenCompletionStateId(id),
+                "large.rs".into(),
+                format!("{large}{id}"),
+                None,
+            );
+            assert!(matches!(update, Some(StateUpdate::FileUpdate(_))));
+        }
+    }
+}
@@ -1,4 +1,8 @@
+use crate::{CompletionBuilder, SupermavenCompletionStateId};
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "file-deltas")]
+use std::ops::Range;
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -16,10 +20,29 @@ pub enum OutboundMessage {
     UseFreeVersion,
 }
 
+impl OutboundMessage {
+    /// The message's `kind` on the wire, for logging without the message's contents.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OutboundMessage::SetApiKey(_) => "set_api_key",
+            OutboundMessage::StateUpdate(_) => "state_update",
+            OutboundMessage::UseFreeVersion => "use_free_version",
+        }
+    }
+
+    /// The state the message creates, if any.
+    pub fn state_id(&self) -> Option<SupermavenCompletionStateId> {
+        match self {
+            OutboundMessage::StateUpdate(message) => Some(message.new_id),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StateUpdateMessage {
-    pub new_id: String,
+    pub new_id: SupermavenCompletionStateId,
     pub updates: Vec<StateUpdate>,
 }
 
@@ -27,7 +50,17 @@ pub struct StateUpdateMessage {
 #[serde(tag = "kind", rename_all = "snake_case")]
 pub enum StateUpdate {
     FileUpdate(FileUpdateMessage),
+    #[cfg(feature = "file-deltas")]
+    FileDeltaUpdate(FileDeltaUpdateMessage),
     CursorUpdate(CursorPositionUpdateMessage),
+    SelectionsUpdate(SelectionsUpdateMessage),
+    RenameFile {
+        from: String,
+        to: String,
+    },
+    DeleteFile {
+        path: String,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -35,6 +68,64 @@ pub enum StateUpdate {
 pub struct FileUpdateMessage {
     pub path: String,
     pub content: String,
+    /// The name of the buffer's language, when one was detected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+/// The edits that turn the content sent for `base_state_id` into the current content of
+/// `path`. Ranges are byte offsets into the base content.
+///
+/// The released agent doesn't accept this message, so it only exists with the `file-deltas`
+/// feature, and even then [`FileUpdateEncoder`] only produces it when deltas are explicitly
+/// enabled.
+///
+/// [`FileUpdateEncoder`]: crate::FileUpdateEncoder
+#[cfg(feature = "file-deltas")]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FileDeltaUpdateMessage {
+    pub path: String,
+    pub base_state_id: SupermavenCompletionStateId,
+    pub edits: Vec<FileEdit>,
+}
+
+#[cfg(feature = "file-deltas")]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FileEdit {
+    pub range: Range<usize>,
+    pub replacement: String,
+}
+
+#[cfg(feature = "file-deltas")]
+impl FileDeltaUpdateMessage {
+    /// Applies the edits to `base`. Fails unless the edits are in ascending, non-overlapping
+    /// order, and every range is within `base` and starts and ends on a char boundary.
+    pub fn apply(&self, base: &str) -> Result<String> {
+        let mut content = String::with_capacity(base.len());
+        let mut offset = 0;
+        for edit in &self.edits {
+            let range = &edit.range;
+            if range.start < offset || range.end < range.start {
+                return Err(anyhow!(
+                    "edit {range:?} in {:?} overlaps or precedes the previous edit",
+                    self.path
+                ));
+            }
+            if !base.is_char_boundary(range.start) || !base.is_char_boundary(range.end) {
+                return Err(anyhow!(
+                    "edit {range:?} in {:?} is out of bounds or not on a char boundary",
+                    self.path
+                ));
+            }
+            content.push_str(&base[offset..range.start]);
+            content.push_str(&edit.replacement);
+            offset = range.end;
+        }
+        content.push_str(&base[offset..]);
+        Ok(content)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,10 +135,89 @@ pub struct CursorPositionUpdateMessage {
     pub offset: usize,
 }
 
+impl CursorPositionUpdateMessage {
+    /// Creates a cursor update, checking that `offset` is a char boundary in `content`.
+    pub fn new(path: String, content: &str, offset: usize) -> Result<Self> {
+        if !content.is_char_boundary(offset) {
+            return Err(anyhow!(
+                "cursor offset {offset} is not a char boundary in {path:?}"
+            ));
+        }
+        Ok(Self { path, offset })
+    }
+
+    /// Creates a cursor update from a zero-based line and character column, e.g. as reported
+    /// by an editor. See [`offset_from_line_col`].
+    pub fn from_line_col(path: String, content: &str, line: usize, col: usize) -> Result<Self> {
+        let offset = offset_from_line_col(content, line, col)
+            .ok_or_else(|| anyhow!("cursor position {line}:{col} is out of range in {path:?}"))?;
+        Ok(Self { path, offset })
+    }
+
+    /// Snaps `offset` back to the closest char boundary at or before it, clamped to the end of
+    /// `content`.
+    pub fn clamp_to_boundary(content: &str, offset: usize) -> usize {
+        let mut offset = offset.min(content.len());
+        while !content.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        offset
+    }
+}
+
+/// Converts a zero-based line and column into a byte offset in `content`. The column counts
+/// characters rather than bytes, and may be one past the last character of the line to point
+/// at its end. Returns `None` if the line or column doesn't exist.
+pub fn offset_from_line_col(content: &str, line: usize, col: usize) -> Option<usize> {
+    let mut line_start = 0;
+    for _ in 0..line {
+        line_start += content[line_start..].find('\n')? + 1;
+    }
+    let line_text = content[line_start..].split('\n').next().unwrap_or_default();
+    let line_text = line_text.strip_suffix('\r').unwrap_or(line_text);
+    line_text
+        .char_indices()
+        .map(|(ix, _)| ix)
+        .chain([line_text.len()])
+        .nth(col)
+        .map(|ix| line_start + ix)
+}
+
+/// Every selection in a file, with the primary selection first. Offsets are in bytes.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SelectionsUpdateMessage {
+    pub path: String,
+    pub selections: Vec<CursorRange>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CursorRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl SelectionsUpdateMessage {
+    /// The single-cursor form of this update, for agents that only understand
+    /// `cursor_update`. The cursor is placed at the end of the primary selection.
+    pub fn primary_cursor(&self) -> Option<CursorPositionUpdateMessage> {
+        let primary = self.selections.first()?;
+        Some(CursorPositionUpdateMessage {
+            path: self.path.clone(),
+            offset: primary.end,
+        })
+    }
+}
+
 // Inbound messages coming in on stdout
+//
+// With the `strict-protocol` feature, a field we don't model fails the message it appears in.
+// Unit variants such as `end` and `activation_success` still accept extra fields, because serde
+// doesn't check them, and a response item that fails is skipped like any other invalid item.
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "kind", rename_all = "snake_case")]
+#[cfg_attr(feature = "strict-protocol", serde(deny_unknown_fields))]
 pub enum ResponseItem {
     // A completion
     Text { text: String },
@@ -57,29 +227,117 @@ pub enum ResponseItem {
     Dedent { text: String },
     // When the completion is over
     End,
-    // Got the closing parentheses and shouldn't show any more after
+    // Got the closing parentheses and shouldn't show any more after. Separates the
+    // inline suggestion from the longer continuation that follows it.
     Barrier,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict-protocol", serde(deny_unknown_fields))]
 pub struct SupermavenResponse {
     pub state_id: String,
+    #[serde(deserialize_with = "deserialize_response_items")]
     pub items: Vec<ResponseItem>,
 }
 
+/// Skips items we can't parse instead of failing the whole response, so the valid ones can
+/// still be shown.
+fn deserialize_response_items<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<ResponseItem>, D::Error> {
+    let items = Vec::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(items
+        .iter()
+        .enumerate()
+        .filter_map(|(ix, item)| match ResponseItem::deserialize(item) {
+            Ok(item) => Some(item),
+            Err(error) => {
+                // The item holds completion text, so only its kind is logged.
+                let kind = item.get("kind").and_then(serde_json::Value::as_str);
+                log::warn!(
+                    "skipping invalid supermaven response item {ix} (kind {kind:?}): {error}"
+                );
+                None
+            }
+        })
+        .collect())
+}
+
+impl SupermavenResponse {
+    /// Splits the items at the first `Barrier` into the primary completion, which is shown
+    /// inline, and the extended continuation after it. The barrier itself is in neither half,
+    /// and any later barriers stay in the continuation.
+    pub fn split_at_barrier(&self) -> (Vec<&ResponseItem>, Vec<&ResponseItem>) {
+        match self
+            .items
+            .iter()
+            .position(|item| matches!(item, ResponseItem::Barrier))
+        {
+            Some(ix) => (
+                self.items[..ix].iter().collect(),
+                self.items[ix + 1..].iter().collect(),
+            ),
+            None => (self.items.iter().collect(), Vec::new()),
+        }
+    }
+
+    /// Appends the items of a later response for the same state, which the agent streams in
+    /// several slices.
+    pub fn merge(&mut self, other: SupermavenResponse) -> Result<()> {
+        if other.state_id != self.state_id {
+            return Err(anyhow!(
+                "cannot merge response for state {} into response for state {}",
+                other.state_id,
+                self.state_id
+            ));
+        }
+        self.items.extend(other.items);
+        Ok(())
+    }
+
+    /// Whether the completion assembled from these items spans more than one line. See
+    /// [`CompletionBuilder::is_multiline`].
+    pub fn is_multiline(&self) -> bool {
+        CompletionBuilder::from_items(&self.items).is_multiline()
+    }
+
+    /// Whether the agent has finished this state's completion.
+    pub fn is_final(&self) -> bool {
+        self.items
+            .iter()
+            .any(|item| matches!(item, ResponseItem::End))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "strict-protocol", serde(deny_unknown_fields))]
 pub struct SupermavenMetadataMessage {
     pub dust_strings: Option<Vec<String>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "strict-protocol", serde(deny_unknown_fields))]
 pub struct SupermavenTaskUpdateMessage {
     pub task: String,
     pub status: TaskStatus,
+    #[serde(default)]
     pub percent_complete: Option<f32>,
 }
 
+impl SupermavenTaskUpdateMessage {
+    /// Progress of the task in `[0.0, 100.0]`. Completed tasks always report 100.
+    pub fn percent(&self) -> f32 {
+        match self.status {
+            TaskStatus::Complete => 100.0,
+            TaskStatus::InProgress => match self.percent_complete {
+                Some(percent) if !percent.is_nan() => percent.clamp(0.0, 100.0),
+                _ => 0.0,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum TaskStatus {
@@ -88,37 +346,147 @@ pub enum TaskStatus {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "strict-protocol", serde(deny_unknown_fields))]
 pub struct SupermavenActiveRepoMessage {
     pub repo_simple_name: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(tag = "kind", rename_all = "snake_case")]
+#[cfg_attr(feature = "strict-protocol", serde(deny_unknown_fields))]
 pub enum SupermavenPopupAction {
-    OpenUrl { label: String, url: String },
-    NoOp { label: String },
+    OpenUrl {
+        label: String,
+        #[serde(deserialize_with = "deserialize_popup_url")]
+        url: String,
+    },
+    NoOp {
+        label: String,
+    },
+}
+
+/// What should happen when a popup action is clicked.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PopupEffect {
+    OpenUrl(String),
+    Nothing,
+}
+
+impl SupermavenPopupAction {
+    pub fn open_url(label: String, url: String) -> Result<Self> {
+        validate_popup_url(&url)?;
+        Ok(Self::OpenUrl { label, url })
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            Self::OpenUrl { label, .. } | Self::NoOp { label } => label,
+        }
+    }
+
+    pub fn on_activate(&self) -> PopupEffect {
+        match self {
+            Self::OpenUrl { url, .. } => PopupEffect::OpenUrl(url.clone()),
+            Self::NoOp { .. } => PopupEffect::Nothing,
+        }
+    }
+
+    /// Whether this action may run without asking the user first, because its URL is on one
+    /// of the `allowlist` hosts or a subdomain of one. Hosts are compared case-insensitively,
+    /// and actions that don't open a URL are always allowed.
+    pub fn is_allowed(&self, allowlist: &[impl AsRef<str>]) -> bool {
+        match self {
+            Self::OpenUrl { url, .. } => {
+                let host = url::Url::parse(url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_ascii_lowercase));
+                host.map_or(false, |host| {
+                    allowlist.iter().any(|allowed| {
+                        let allowed = allowed.as_ref().to_ascii_lowercase();
+                        host == allowed
+                            || host
+                                .strip_suffix(&allowed)
+                                .map_or(false, |subdomain| subdomain.ends_with('.'))
+                    })
+                })
+            }
+            Self::NoOp { .. } => true,
+        }
+    }
+
+    /// Whether the user should confirm before this action opens its URL, because the URL
+    /// leaves Supermaven's own site, i.e. isn't allowed by [`DEFAULT_POPUP_HOST_ALLOWLIST`].
+    pub fn requires_confirmation(&self) -> bool {
+        !self.is_allowed(DEFAULT_POPUP_HOST_ALLOWLIST)
+    }
+}
+
+/// Hosts that popup actions may open without asking the user first, unless the editor
+/// configures its own list for [`SupermavenPopupAction::is_allowed`].
+pub const DEFAULT_POPUP_HOST_ALLOWLIST: &[&str] = &["supermaven.com"];
+
+/// Popups come from the agent, so only let them open absolute http(s) URLs.
+fn validate_popup_url(url: &str) -> Result<()> {
+    let parsed =
+        url::Url::parse(url).map_err(|error| anyhow!("invalid popup url {url:?}: {error}"))?;
+    match parsed.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(anyhow!(
+            "popup url {url:?} has unsupported scheme {scheme:?}"
+        )),
+    }
+}
+
+fn deserialize_popup_url<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let url = String::deserialize(deserializer)?;
+    validate_popup_url(&url).map_err(serde::de::Error::custom)?;
+    Ok(url)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "strict-protocol", serde(deny_unknown_fields))]
 pub struct SupermavenPopupMessage {
     pub message: String,
     pub actions: Vec<SupermavenPopupAction>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "kind", rename_all = "camelCase")]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict-protocol", serde(deny_unknown_fields))]
 pub struct ActivationRequest {
     pub activate_url: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict-protocol", serde(deny_unknown_fields))]
 pub struct SupermavenSetMessage {
     pub key: String,
     pub value: serde_json::Value,
 }
 
+/// Configuration pushed by the agent. Every key is optional, since the agent only sends the
+/// settings that changed. Its keys aren't all documented, so the ones we don't know about are
+/// kept as-is in `extra` rather than dropped.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupermavenConfigMessage {
+    /// Whether inline completions should be shown at all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable_inline_completions: Option<bool>,
+    /// Languages, by name, that shouldn't get completions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disabled_languages: Option<Vec<String>>,
+    /// The longest completion the agent will send, in characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_completion_length: Option<usize>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ServiceTier {
     FreeNoLicense,
@@ -128,6 +496,7 @@ pub enum ServiceTier {
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "kind", rename_all = "snake_case")]
+#[cfg_attr(feature = "strict-protocol", serde(deny_unknown_fields))]
 pub enum SupermavenMessage {
     Response(SupermavenResponse),
     Metadata(SupermavenMetadataMessage),
@@ -147,6 +516,845 @@ pub enum SupermavenMessage {
     },
 
     Set(SupermavenSetMessage),
+    ConfigUpdate(SupermavenConfigMessage),
     #[serde(other)]
     Unknown,
 }
+
+/// How many `passthrough` layers we unwrap before giving up on a message.
+pub const MAX_PASSTHROUGH_DEPTH: usize = 32;
+
+impl SupermavenMessage {
+    /// The message's `kind` on the wire, for logging without the message's contents.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SupermavenMessage::Response(_) => "response",
+            SupermavenMessage::Metadata(_) => "metadata",
+            SupermavenMessage::Apology { .. } => "apology",
+            SupermavenMessage::ActivationRequest(_) => "activation_request",
+            SupermavenMessage::ActivationSuccess => "activation_success",
+            SupermavenMessage::Passthrough { .. } => "passthrough",
+            SupermavenMessage::Popup(_) => "popup",
+            SupermavenMessage::TaskStatus(_) => "task_status",
+            SupermavenMessage::ActiveRepo(_) => "active_repo",
+            SupermavenMessage::ServiceTier { .. } => "service_tier",
+            SupermavenMessage::Set(_) => "set",
+            SupermavenMessage::ConfigUpdate(_) => "config_update",
+            SupermavenMessage::Unknown => "unknown",
+        }
+    }
+
+    /// The state the message is about, if any.
+    pub fn state_id(&self) -> Option<&str> {
+        match self {
+            SupermavenMessage::Response(response) => Some(&response.state_id),
+            _ => None,
+        }
+    }
+
+    /// Unwraps any `Passthrough` layers around the message, iteratively, failing if they nest
+    /// deeper than [`MAX_PASSTHROUGH_DEPTH`].
+    pub fn flatten_passthrough(self) -> Result<SupermavenMessage> {
+        let mut message = self;
+        let mut depth = 0;
+        while let SupermavenMessage::Passthrough { passthrough } = message {
+            depth += 1;
+            if depth > MAX_PASSTHROUGH_DEPTH {
+                return Err(anyhow!(
+                    "passthrough messages nested more than {MAX_PASSTHROUGH_DEPTH} levels deep"
+                ));
+            }
+            message = *passthrough;
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_update_wire_format() {
+        let message = OutboundMessage::StateUpdate(StateUpdateMessage {
+            new_id: SupermavenCompletionStateId(1),
+            updates: vec![
+                StateUpdate::FileUpdate(FileUpdateMessage {
+                    path: "/src/main.rs".into(),
+                    content: "fn main() {}".into(),
+                    language: None,
+                }),
+                StateUpdate::CursorUpdate(CursorPositionUpdateMessage {
+                    path: "/src/main.rs".into(),
+                    offset: 3,
+                }),
+            ],
+        });
+
+        // The agent silently ignores messages it can't parse, so pin the exact wire form.
+        // Note that the agent calls cursor updates `cursor_update`.
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            serde_json::json!({
+                "kind": "state_update",
+                "newId": "1",
+                "updates": [
+                    {"kind": "file_update", "path": "/src/main.rs", "content": "fn main() {}"},
+                    {"kind": "cursor_update", "path": "/src/main.rs", "offset": 3},
+                ],
+            })
+        );
+
+        let message = OutboundMessage::SetApiKey(SetApiKey {
+            api_key: "key".into(),
+        });
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            serde_json::json!({"kind": "set_api_key", "apiKey": "key"})
+        );
+    }
+
+    #[test]
+    fn test_split_at_barrier() {
+        fn text(text: &str) -> ResponseItem {
+            ResponseItem::Text { text: text.into() }
+        }
+
+        fn texts(items: Vec<&ResponseItem>) -> Vec<&str> {
+            items
+                .into_iter()
+                .map(|item| match item {
+                    ResponseItem::Text { text } => text.as_str(),
+                    ResponseItem::Barrier => "|",
+                    _ => "?",
+                })
+                .collect()
+        }
+
+        let response = SupermavenResponse {
+            state_id: "1".into(),
+            items: vec![text("a"), text("b")],
+        };
+        let (primary, extended) = response.split_at_barrier();
+        assert_eq!(texts(primary), ["a", "b"]);
+        assert!(extended.is_empty());
+
+        let response = SupermavenResponse {
+            state_id: "1".into(),
+            items: vec![
+                text("a"),
+                ResponseItem::Barrier,
+                text("b"),
+                ResponseItem::Barrier,
+                text("c"),
+            ],
+        };
+        let (primary, extended) = response.split_at_barrier();
+        assert_eq!(texts(primary), ["a"]);
+        assert_eq!(texts(extended), ["b", "|", "c"]);
+
+        let response = SupermavenResponse {
+            state_id: "1".into(),
+            items: vec![ResponseItem::Barrier, text("a")],
+        };
+        let (primary, extended) = response.split_at_barrier();
+        assert!(primary.is_empty());
+        assert_eq!(texts(extended), ["a"]);
+    }
+
+    #[test]
+    fn test_merge_responses() {
+        let mut response = SupermavenResponse {
+            state_id: "1".into(),
+            items: vec![ResponseItem::Text { text: "fn ".into() }],
+        };
+        assert!(!response.is_final());
+
+        response
+            .merge(SupermavenResponse {
+                state_id: "1".into(),
+                items: vec![ResponseItem::Text {
+                    text: "main".into(),
+                }],
+            })
+            .unwrap();
+        assert!(!response.is_final());
+
+        response
+            .merge(SupermavenResponse {
+                state_id: "1".into(),
+                items: vec![ResponseItem::Text { text: "()".into() }, ResponseItem::End],
+            })
+            .unwrap();
+        assert!(response.is_final());
+        let text = response
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                ResponseItem::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<String>();
+        assert_eq!(text, "fn main()");
+
+        let error = response
+            .merge(SupermavenResponse {
+                state_id: "2".into(),
+                items: vec![ResponseItem::Text { text: "x".into() }],
+            })
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot merge response for state 2 into response for state 1"
+        );
+        assert_eq!(response.items.len(), 4);
+    }
+
+    #[test]
+    fn test_response_is_multiline() {
+        let response = |items| SupermavenResponse {
+            state_id: "1".into(),
+            items,
+        };
+        assert!(!response(vec![ResponseItem::Text {
+            text: "foo();\n".into()
+        }])
+        .is_multiline());
+        assert!(response(vec![
+            ResponseItem::Text {
+                text: "foo();\n".into()
+            },
+            ResponseItem::Text {
+                text: "bar();".into()
+            },
+        ])
+        .is_multiline());
+    }
+
+    #[test]
+    fn test_unknown_message_kind() {
+        let message = serde_json::from_str::<SupermavenMessage>(r#"{"kind":"future_thing"}"#);
+        assert!(matches!(message, Ok(SupermavenMessage::Unknown)));
+
+        let message = serde_json::from_str::<SupermavenMessage>(
+            r#"{"kind":"future_thing","payload":{"nested":[1,2,3]}}"#,
+        );
+        assert!(matches!(message, Ok(SupermavenMessage::Unknown)));
+    }
+
+    #[test]
+    fn test_config_update_round_trip() {
+        let json = r#"{"kind":"config_update","enableInlineCompletions":false,"disabledLanguages":["Markdown"],"maxCompletionLength":512,"enableFeature":true,"limits":{"maxTokens":128}}"#;
+        let message = serde_json::from_str::<SupermavenMessage>(json).unwrap();
+        let SupermavenMessage::ConfigUpdate(config) = &message else {
+            panic!("expected a config update, got {:?}", message);
+        };
+        assert_eq!(config.enable_inline_completions, Some(false));
+        assert_eq!(
+            config.disabled_languages.as_deref(),
+            Some(&["Markdown".to_string()][..])
+        );
+        assert_eq!(config.max_completion_length, Some(512));
+        assert_eq!(config.extra["enableFeature"], serde_json::json!(true));
+        assert_eq!(
+            config.extra["limits"],
+            serde_json::json!({"maxTokens": 128})
+        );
+        assert_eq!(config.extra.len(), 2);
+
+        let serialized = serde_json::to_value(&message).unwrap();
+        assert_eq!(
+            serialized,
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        );
+
+        let message =
+            serde_json::from_str::<SupermavenMessage>(r#"{"kind":"config_update"}"#).unwrap();
+        let SupermavenMessage::ConfigUpdate(config) = message else {
+            panic!("expected a config update, got {:?}", message);
+        };
+        assert_eq!(config, SupermavenConfigMessage::default());
+    }
+
+    #[test]
+    fn test_offset_from_line_col() {
+        let content = "fn main() {\n    x\n}";
+        assert_eq!(offset_from_line_col(content, 0, 0), Some(0));
+        assert_eq!(offset_from_line_col(content, 0, 11), Some(11));
+        assert_eq!(offset_from_line_col(content, 0, 12), None);
+        assert_eq!(offset_from_line_col(content, 1, 4), Some(16));
+        assert_eq!(offset_from_line_col(content, 2, 1), Some(content.len()));
+        assert_eq!(offset_from_line_col(content, 3, 0), None);
+        assert_eq!(offset_from_line_col("a\r\nb", 0, 1), Some(1));
+        assert_eq!(offset_from_line_col("a\r\nb", 0, 2), None);
+        assert_eq!(offset_from_line_col("a\n", 1, 0), Some(2));
+        assert_eq!(offset_from_line_col("", 0, 0), Some(0));
+
+        let content = "let é = \"😀\";\naé😀b";
+        assert_eq!(offset_from_line_col(content, 0, 5), Some(6));
+        assert_eq!(offset_from_line_col(content, 0, 10), Some(14));
+        assert_eq!(offset_from_line_col(content, 0, 12), Some(16));
+        assert_eq!(offset_from_line_col(content, 1, 2), Some(20));
+        assert_eq!(offset_from_line_col(content, 1, 3), Some(24));
+        assert_eq!(offset_from_line_col(content, 1, 4), Some(25));
+        assert_eq!(offset_from_line_col(content, 1, 5), None);
+
+        let update =
+            CursorPositionUpdateMessage::from_line_col("a.rs".into(), content, 1, 3).unwrap();
+        assert_eq!(update.offset, 24);
+        assert!(content.is_char_boundary(update.offset));
+        assert!(CursorPositionUpdateMessage::from_line_col("a.rs".into(), content, 2, 0).is_err());
+    }
+
+    #[test]
+    fn test_cursor_offset_char_boundary() {
+        let content = "aé😀b";
+        assert!(CursorPositionUpdateMessage::new("a.rs".into(), content, 1).is_ok());
+        assert!(CursorPositionUpdateMessage::new("a.rs".into(), content, 2).is_err());
+        assert!(CursorPositionUpdateMessage::new("a.rs".into(), content, 3).is_ok());
+        assert!(CursorPositionUpdateMessage::new("a.rs".into(), content, 5).is_err());
+        assert!(CursorPositionUpdateMessage::new("a.rs".into(), content, 8).is_ok());
+        assert!(CursorPositionUpdateMessage::new("a.rs".into(), content, 9).is_err());
+
+        assert_eq!(
+            CursorPositionUpdateMessage::clamp_to_boundary(content, 2),
+            1
+        );
+        assert_eq!(
+            CursorPositionUpdateMessage::clamp_to_boundary(content, 6),
+            3
+        );
+        assert_eq!(
+            CursorPositionUpdateMessage::clamp_to_boundary(content, 7),
+            7
+        );
+        assert_eq!(
+            CursorPositionUpdateMessage::clamp_to_boundary(content, 100),
+            8
+        );
+    }
+
+    #[test]
+    fn test_rename_and_delete_file_round_trip() {
+        let rename = StateUpdate::RenameFile {
+            from: "/src/a.rs".into(),
+            to: "/src/b.rs".into(),
+        };
+        let json = serde_json::to_value(&rename).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"kind": "rename_file", "from": "/src/a.rs", "to": "/src/b.rs"})
+        );
+        let StateUpdate::RenameFile { from, to } = serde_json::from_value(json).unwrap() else {
+            panic!("expected a rename");
+        };
+        assert_eq!((from.as_str(), to.as_str()), ("/src/a.rs", "/src/b.rs"));
+
+        let delete = StateUpdate::DeleteFile {
+            path: "/src/a.rs".into(),
+        };
+        let json = serde_json::to_value(&delete).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"kind": "delete_file", "path": "/src/a.rs"})
+        );
+        let StateUpdate::DeleteFile { path } = serde_json::from_value(json).unwrap() else {
+            panic!("expected a delete");
+        };
+        assert_eq!(path, "/src/a.rs");
+    }
+
+    #[test]
+    fn test_selections_update_wire_format() {
+        let selections = SelectionsUpdateMessage {
+            path: "/src/main.rs".into(),
+            selections: vec![
+                CursorRange { start: 4, end: 8 },
+                CursorRange { start: 12, end: 12 },
+            ],
+        };
+
+        let cursor = selections.primary_cursor().unwrap();
+        assert_eq!(
+            serde_json::to_value(StateUpdate::CursorUpdate(cursor)).unwrap(),
+            serde_json::json!({"kind": "cursor_update", "path": "/src/main.rs", "offset": 8})
+        );
+
+        assert_eq!(
+            serde_json::to_value(StateUpdate::SelectionsUpdate(selections)).unwrap(),
+            serde_json::json!({
+                "kind": "selections_update",
+                "path": "/src/main.rs",
+                "selections": [{"start": 4, "end": 8}, {"start": 12, "end": 12}],
+            })
+        );
+
+        let empty = SelectionsUpdateMessage {
+            path: "/src/main.rs".into(),
+            selections: Vec::new(),
+        };
+        assert!(empty.primary_cursor().is_none());
+    }
+
+    #[test]
+    fn test_file_update_language() {
+        let update = StateUpdate::FileUpdate(FileUpdateMessage {
+            path: "/src/main.rs".into(),
+            content: "fn main() {}".into(),
+            language: Some("Rust".into()),
+        });
+        assert_eq!(
+            serde_json::to_value(&update).unwrap(),
+            serde_json::json!({
+                "kind": "file_update",
+                "path": "/src/main.rs",
+                "content": "fn main() {}",
+                "language": "Rust",
+            })
+        );
+
+        let update = StateUpdate::FileUpdate(FileUpdateMessage {
+            path: "/src/main.rs".into(),
+            content: "fn main() {}".into(),
+            language: None,
+        });
+        let json = serde_json::to_value(&update).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"kind": "file_update", "path": "/src/main.rs", "content": "fn main() {}"})
+        );
+        let StateUpdate::FileUpdate(update) = serde_json::from_value(json).unwrap() else {
+            panic!("expected a file update");
+        };
+        assert!(update.language.is_none());
+    }
+
+    #[test]
+    fn test_task_update_percent() {
+        let task = |json: &str| match serde_json::from_str::<SupermavenMessage>(json).unwrap() {
+            SupermavenMessage::TaskStatus(task) => task,
+            message => panic!("expected a task status, got {:?}", message),
+        };
+
+        let absent = task(r#"{"kind":"task_status","task":"index","status":"in_progress"}"#);
+        assert_eq!(absent.percent(), 0.0);
+
+        let halfway = task(
+            r#"{"kind":"task_status","task":"index","status":"in_progress","percent_complete":50}"#,
+        );
+        assert_eq!(halfway.percent(), 50.0);
+
+        let too_high = task(
+            r#"{"kind":"task_status","task":"index","status":"in_progress","percent_complete":250}"#,
+        );
+        assert_eq!(too_high.percent(), 100.0);
+
+        let negative = task(
+            r#"{"kind":"task_status","task":"index","status":"in_progress","percent_complete":-3}"#,
+        );
+        assert_eq!(negative.percent(), 0.0);
+
+        let complete = task(
+            r#"{"kind":"task_status","task":"index","status":"complete","percent_complete":10}"#,
+        );
+        assert_eq!(complete.percent(), 100.0);
+    }
+
+    #[test]
+    fn test_popup_action_requires_confirmation() {
+        let open_url =
+            |url: &str| SupermavenPopupAction::open_url("Open".into(), url.into()).unwrap();
+        assert!(!open_url("https://supermaven.com/activate").requires_confirmation());
+        assert!(!open_url("https://www.Supermaven.com/account").requires_confirmation());
+        assert!(open_url("https://example.com/supermaven.com").requires_confirmation());
+        assert!(open_url("https://notsupermaven.com").requires_confirmation());
+        assert!(open_url("https://supermaven.com.example.com").requires_confirmation());
+        assert!(!SupermavenPopupAction::NoOp {
+            label: "Close".into()
+        }
+        .requires_confirmation());
+    }
+
+    #[test]
+    fn test_popup_action_is_allowed() {
+        let open_url =
+            |url: &str| SupermavenPopupAction::open_url("Open".into(), url.into()).unwrap();
+        let allowlist = vec!["supermaven.com".to_string(), "Docs.Example.org".to_string()];
+
+        assert!(open_url("https://supermaven.com/pricing").is_allowed(&allowlist));
+        assert!(open_url("https://docs.example.org/setup").is_allowed(&allowlist));
+
+        assert!(open_url("https://app.supermaven.com/account").is_allowed(&allowlist));
+        assert!(open_url("https://v2.docs.example.org").is_allowed(&allowlist));
+        assert!(!open_url("https://example.org").is_allowed(&allowlist));
+
+        assert!(!open_url("https://evil.com/supermaven.com").is_allowed(&allowlist));
+        assert!(!open_url("https://evilsupermaven.com").is_allowed(&allowlist));
+        assert!(!open_url("https://supermaven.com.evil.com").is_allowed(&allowlist));
+        assert!(!open_url("https://supermaven.com").is_allowed(&[] as &[&str]));
+        assert!(open_url("https://supermaven.com").is_allowed(DEFAULT_POPUP_HOST_ALLOWLIST));
+
+        let no_op = SupermavenPopupAction::NoOp {
+            label: "Close".into(),
+        };
+        assert!(no_op.is_allowed(&[] as &[&str]));
+    }
+
+    #[test]
+    fn test_popup_action_url_validation() {
+        let action = SupermavenPopupAction::open_url(
+            "Upgrade".into(),
+            "https://supermaven.com/pricing".into(),
+        )
+        .unwrap();
+        assert_eq!(action.label(), "Upgrade");
+        assert_eq!(
+            action.on_activate(),
+            PopupEffect::OpenUrl("https://supermaven.com/pricing".into())
+        );
+
+        for url in [
+            "javascript:alert(1)",
+            "/pricing",
+            "supermaven.com",
+            "file:///etc/passwd",
+        ] {
+            assert!(
+                SupermavenPopupAction::open_url("Upgrade".into(), url.into()).is_err(),
+                "{url}"
+            );
+        }
+
+        let popup = serde_json::from_str::<SupermavenPopupMessage>(
+            r#"{"message":"hi","actions":[{"kind":"open_url","label":"Go","url":"http://example.com"},{"kind":"no_op","label":"Close"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(popup.actions[1].label(), "Close");
+        assert_eq!(popup.actions[1].on_activate(), PopupEffect::Nothing);
+
+        assert!(serde_json::from_str::<SupermavenPopupMessage>(
+            r#"{"message":"hi","actions":[{"kind":"open_url","label":"Go","url":"javascript:alert(1)"}]}"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_inbound_message_round_trip() {
+        let cases: &[(&str, &str, fn(&SupermavenMessage) -> bool)] = &[
+            (
+                "response",
+                r#"{"kind":"response","stateId":"3","items":[{"kind":"text","text":"fn"},{"kind":"barrier"},{"kind":"end"}]}"#,
+                |message| {
+                    matches!(message, SupermavenMessage::Response(response)
+                        if response.state_id == "3" && response.items.len() == 3)
+                },
+            ),
+            (
+                "metadata",
+                r#"{"kind":"metadata","dust_strings":["}"]}"#,
+                |message| {
+                    matches!(message, SupermavenMessage::Metadata(metadata)
+                        if metadata.dust_strings.as_deref() == Some(&["}".to_string()][..]))
+                },
+            ),
+            (
+                "apology",
+                r#"{"kind":"apology","message":"sorry"}"#,
+                |message| {
+                    matches!(message, SupermavenMessage::Apology { message: Some(message) }
+                        if message == "sorry")
+                },
+            ),
+            (
+                "activation_request",
+                r#"{"kind":"activation_request","activateUrl":"https://supermaven.com/activate"}"#,
+                |message| {
+                    matches!(message, SupermavenMessage::ActivationRequest(request)
+                        if request.activate_url.as_deref() == Some("https://supermaven.com/activate"))
+                },
+            ),
+            (
+                "activation_success",
+                r#"{"kind":"activation_success"}"#,
+                |message| matches!(message, SupermavenMessage::ActivationSuccess),
+            ),
+            (
+                "passthrough",
+                r#"{"kind":"passthrough","passthrough":{"kind":"activation_success"}}"#,
+                |message| {
+                    matches!(message, SupermavenMessage::Passthrough { passthrough }
+                        if matches!(**passthrough, SupermavenMessage::ActivationSuccess))
+                },
+            ),
+            (
+                "popup",
+                r#"{"kind":"popup","message":"Upgrade?","actions":[{"kind":"open_url","label":"Yes","url":"https://supermaven.com"},{"kind":"no_op","label":"No"}]}"#,
+                |message| {
+                    matches!(message, SupermavenMessage::Popup(popup)
+                        if popup.message == "Upgrade?" && popup.actions.len() == 2)
+                },
+            ),
+            (
+                "task_status",
+                r#"{"kind":"task_status","task":"indexing","status":"in_progress","percent_complete":12.5}"#,
+                |message| {
+                    matches!(message, SupermavenMessage::TaskStatus(task)
+                        if task.task == "indexing" && task.percent_complete == Some(12.5))
+                },
+            ),
+            (
+                "active_repo",
+                r#"{"kind":"active_repo","repo_simple_name":"zed"}"#,
+                |message| {
+                    matches!(message, SupermavenMessage::ActiveRepo(repo)
+                        if repo.repo_simple_name.as_deref() == Some("zed"))
+                },
+            ),
+            (
+                "service_tier",
+                r#"{"kind":"service_tier","service_tier":"FreeNoLicense"}"#,
+                |message| {
+                    matches!(
+                        message,
+                        SupermavenMessage::ServiceTier {
+                            service_tier: ServiceTier::FreeNoLicense
+                        }
+                    )
+                },
+            ),
+            (
+                "set",
+                r#"{"kind":"set","key":"theme","value":{"dark":true}}"#,
+                |message| {
+                    matches!(message, SupermavenMessage::Set(set)
+                        if set.key == "theme" && set.value["dark"] == serde_json::json!(true))
+                },
+            ),
+        ];
+
+        for (kind, json, is_expected) in cases {
+            let message = serde_json::from_str::<SupermavenMessage>(json)
+                .unwrap_or_else(|error| panic!("failed to parse {kind}: {error}"));
+            assert!(is_expected(&message), "unexpected {kind}: {message:?}");
+
+            let serialized = serde_json::to_string(&message).unwrap();
+            let value = serde_json::from_str::<serde_json::Value>(&serialized).unwrap();
+            assert_eq!(value["kind"], *kind, "{serialized}");
+            assert_eq!(
+                serialized.matches(r#""kind":"#).count(),
+                json.matches(r#""kind":"#).count(),
+                "{serialized}"
+            );
+
+            let reparsed = serde_json::from_str::<SupermavenMessage>(&serialized).unwrap();
+            assert!(
+                is_expected(&reparsed),
+                "unexpected reparsed {kind}: {reparsed:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_message_kind_matches_wire_format() {
+        let inbound = [
+            r#"{"kind":"response","stateId":"1","items":[]}"#,
+            r#"{"kind":"metadata","dust_strings":null}"#,
+            r#"{"kind":"apology","message":null}"#,
+            r#"{"kind":"activation_success"}"#,
+            r#"{"kind":"passthrough","passthrough":{"kind":"activation_success"}}"#,
+            r#"{"kind":"service_tier","service_tier":"FreeNoLicense"}"#,
+            r#"{"kind":"set","key":"a","value":1}"#,
+            r#"{"kind":"config_update"}"#,
+        ];
+        for line in inbound {
+            let message = serde_json::from_str::<SupermavenMessage>(line).unwrap();
+            let wire = serde_json::from_str::<serde_json::Value>(line).unwrap();
+            assert_eq!(message.kind(), wire["kind"], "{line}");
+        }
+
+        let outbound = [
+            OutboundMessage::SetApiKey(SetApiKey {
+                api_key: "key".into(),
+            }),
+            OutboundMessage::StateUpdate(StateUpdateMessage {
+                new_id: SupermavenCompletionStateId(3),
+                updates: Vec::new(),
+            }),
+            OutboundMessage::UseFreeVersion,
+        ];
+        for message in outbound {
+            let wire = serde_json::to_value(&message).unwrap();
+            assert_eq!(message.kind(), wire["kind"]);
+        }
+    }
+
+    const RESPONSE_WITH_EXTRA_FIELD: &str =
+        r#"{"kind":"response","stateId":"1","items":[],"latencyMs":12}"#;
+
+    /// A valid message of every inbound kind, with every optional field set.
+    const INBOUND_MESSAGES: &[&str] = &[
+        r#"{"kind":"response","stateId":"1","items":[{"kind":"text","text":"fn"},{"kind":"del","text":""},{"kind":"dedent","text":"  "},{"kind":"barrier"},{"kind":"end"}]}"#,
+        r#"{"kind":"metadata","dust_strings":["}"]}"#,
+        r#"{"kind":"apology","message":"sorry"}"#,
+        r#"{"kind":"activation_request","activateUrl":"https://supermaven.com/activate"}"#,
+        r#"{"kind":"activation_success"}"#,
+        r#"{"kind":"passthrough","passthrough":{"kind":"apology","message":null}}"#,
+        r#"{"kind":"popup","message":"Upgrade?","actions":[{"kind":"open_url","label":"Yes","url":"https://supermaven.com"},{"kind":"no_op","label":"No"}]}"#,
+        r#"{"kind":"task_status","task":"indexing","status":"in_progress","percent_complete":12.5}"#,
+        r#"{"kind":"active_repo","repo_simple_name":"zed"}"#,
+        r#"{"kind":"service_tier","service_tier":"FreeNoLicense"}"#,
+        r#"{"kind":"set","key":"theme","value":{"dark":true}}"#,
+        r#"{"kind":"config_update","enableInlineCompletions":false,"disabledLanguages":["Markdown"],"maxCompletionLength":512,"limits":{"maxTokens":128}}"#,
+    ];
+
+    #[test]
+    fn test_every_inbound_kind_parses() {
+        for line in INBOUND_MESSAGES {
+            let message = serde_json::from_str::<SupermavenMessage>(line)
+                .unwrap_or_else(|error| panic!("failed to parse {line}: {error}"));
+            assert!(
+                !matches!(message, SupermavenMessage::Unknown),
+                "{line} parsed as unknown"
+            );
+            if let SupermavenMessage::Response(response) = message {
+                assert_eq!(response.items.len(), 5, "{line}");
+            }
+        }
+    }
+
+    #[cfg(feature = "strict-protocol")]
+    #[test]
+    fn test_strict_protocol_rejects_unknown_fields() {
+        for line in [
+            RESPONSE_WITH_EXTRA_FIELD,
+            r#"{"kind":"apology","message":null,"retry":true}"#,
+            r#"{"kind":"passthrough","passthrough":{"kind":"activation_success"},"depth":1}"#,
+            r#"{"kind":"service_tier","service_tier":"FreeNoLicense","trial":false}"#,
+            r#"{"kind":"popup","message":"Upgrade?","actions":[{"kind":"no_op","label":"No","style":"primary"}]}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<SupermavenMessage>(line).is_err(),
+                "{line}"
+            );
+        }
+
+        // An item with an unknown field is skipped rather than failing its response.
+        let message = serde_json::from_str::<SupermavenMessage>(
+            r#"{"kind":"response","stateId":"1","items":[{"kind":"text","text":"fn","score":1},{"kind":"end"}]}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            message,
+            SupermavenMessage::Response(response)
+                if matches!(response.items[..], [ResponseItem::End])
+        ));
+    }
+
+    #[cfg(not(feature = "strict-protocol"))]
+    #[test]
+    fn test_unknown_fields_are_ignored() {
+        let message = serde_json::from_str::<SupermavenMessage>(RESPONSE_WITH_EXTRA_FIELD).unwrap();
+        assert!(matches!(message, SupermavenMessage::Response(_)));
+    }
+
+    #[test]
+    fn test_flatten_passthrough() {
+        fn nest(depth: usize) -> SupermavenMessage {
+            let mut message = SupermavenMessage::ActivationSuccess;
+            for _ in 0..depth {
+                message = SupermavenMessage::Passthrough {
+                    passthrough: Box::new(message),
+                };
+            }
+            message
+        }
+
+        assert!(matches!(
+            nest(0).flatten_passthrough(),
+            Ok(SupermavenMessage::ActivationSuccess)
+        ));
+        assert!(matches!(
+            nest(MAX_PASSTHROUGH_DEPTH).flatten_passthrough(),
+            Ok(SupermavenMessage::ActivationSuccess)
+        ));
+        assert!(nest(40).flatten_passthrough().is_err());
+    }
+
+    #[test]
+    fn test_response_skips_invalid_items() {
+        let message = serde_json::from_str::<SupermavenMessage>(
+            r#"{"kind":"response","stateId":"1","items":[
+                {"kind":"text","text":"let "},
+                {"kind":"sparkle","intensity":11},
+                {"kind":"text","text":42},
+                "garbage",
+                {"kind":"text","text":"x"},
+                {"kind":"end"},
+                {"kind":"text","text":"ignored"}
+            ]}"#,
+        )
+        .unwrap();
+        let SupermavenMessage::Response(response) = message else {
+            panic!("expected a response, got {:?}", message);
+        };
+        assert_eq!(response.items.len(), 4);
+
+        let completion = crate::CompletionBuilder::from_items(&response.items);
+        assert_eq!(completion.text(), "let x");
+        assert!(completion.is_finished());
+    }
+
+    #[cfg(feature = "file-deltas")]
+    #[test]
+    fn test_apply_rejects_invalid_edits() {
+        let delta = |ranges: &[Range<usize>]| FileDeltaUpdateMessage {
+            path: "a.rs".into(),
+            base_state_id: SupermavenCompletionStateId(0),
+            edits: ranges
+                .iter()
+                .map(|range| FileEdit {
+                    range: range.clone(),
+                    replacement: "x".into(),
+                })
+                .collect(),
+        };
+
+        assert_eq!(delta(&[0..1, 3..4]).apply("héllo").unwrap(), "xéxlo");
+        assert_eq!(delta(&[6..6]).apply("héllo").unwrap(), "héllox");
+        for ranges in [
+            &[3..4, 0..1][..],
+            &[0..4, 3..5],
+            &[2..1],
+            &[5..9],
+            &[7..7],
+            &[2..3],
+        ] {
+            assert!(delta(ranges).apply("héllo").is_err(), "{ranges:?}");
+        }
+    }
+
+    #[test]
+    fn test_skipped_items_are_logged_without_content() {
+        let (message, records) = crate::test_logger::capture(|| {
+            serde_json::from_str::<SupermavenMessage>(
+                r#"{"kind":"response","stateId":"1","items":[
+                    {"kind":"text","text":["let secret = 42;"]}
+                ]}"#,
+            )
+        });
+        let SupermavenMessage::Response(response) = message.unwrap() else {
+            panic!("expected a response");
+        };
+        assert!(response.items.is_empty());
+
+        let warnings = records
+            .iter()
+            .filter(|(level, _)| *level == log::Level::Warn)
+            .collect::<Vec<_>>();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].1.contains("\"text\""), "{}", warnings[0].1);
+        assert!(records
+            .iter()
+            .all(|(_, message)| !message.contains("secret")));
+    }
+}
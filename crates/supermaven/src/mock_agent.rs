@@ -0,0 +1,187 @@
+use crate::{
+    messages::{ResponseItem, StateUpdateMessage, SupermavenMessage, SupermavenResponse},
+    MESSAGE_PREFIX,
+};
+use anyhow::{Context as _, Result};
+use futures::{
+    channel::mpsc,
+    io::IntoAsyncRead,
+    task::{Context, Poll},
+    AsyncBufReadExt, AsyncWrite, StreamExt, TryStreamExt,
+};
+use std::{collections::VecDeque, io, pin::Pin};
+
+type Chunks = mpsc::UnboundedReceiver<io::Result<Vec<u8>>>;
+
+/// An in-process stand-in for the agent, speaking its protocol over in-memory pipes.
+///
+/// Each state update it receives consumes the scripted messages queued before the next
+/// scripted response, then that response, addressed to the update's state.
+pub struct MockAgent {
+    stdin: IntoAsyncRead<Chunks>,
+    stdout: mpsc::UnboundedSender<io::Result<Vec<u8>>>,
+    script: VecDeque<Scripted>,
+}
+
+/// The editor's end of the pipes to a [`MockAgent`].
+pub struct MockAgentConnection {
+    pub stdin: PipeWriter,
+    pub stdout: IntoAsyncRead<Chunks>,
+}
+
+enum Scripted {
+    Message(SupermavenMessage),
+    Response(Vec<ResponseItem>),
+}
+
+impl MockAgent {
+    pub fn new() -> (Self, MockAgentConnection) {
+        let (stdin_tx, stdin_rx) = mpsc::unbounded();
+        let (stdout_tx, stdout_rx) = mpsc::unbounded();
+        let agent = Self {
+            stdin: stdin_rx.into_async_read(),
+            stdout: stdout_tx,
+            script: VecDeque::new(),
+        };
+        let connection = MockAgentConnection {
+            stdin: PipeWriter(stdin_tx),
+            stdout: stdout_rx.into_async_read(),
+        };
+        (agent, connection)
+    }
+
+    /// Queues the items of the response to the next state update.
+    pub fn respond_with(&mut self, items: Vec<ResponseItem>) -> &mut Self {
+        self.script.push_back(Scripted::Response(items));
+        self
+    }
+
+    /// Queues a message, such as an `Apology` or `Popup`, to send ahead of the next response.
+    pub fn then_send(&mut self, message: SupermavenMessage) -> &mut Self {
+        self.script.push_back(Scripted::Message(message));
+        self
+    }
+
+    /// Serves state updates until the editor closes stdin, returning the updates received.
+    pub async fn run(mut self) -> Result<Vec<StateUpdateMessage>> {
+        let mut received = Vec::new();
+        let mut lines = (&mut self.stdin).lines();
+        while let Some(line) = lines.next().await {
+            let line = line?;
+            let message = serde_json::from_str::<serde_json::Value>(&line)
+                .with_context(|| format!("agent received invalid JSON: {line:?}"))?;
+            if message["kind"] != "state_update" {
+                continue;
+            }
+            let update = serde_json::from_value::<StateUpdateMessage>(message)?;
+
+            while let Some(Scripted::Message(_)) = self.script.front() {
+                if let Some(Scripted::Message(message)) = self.script.pop_front() {
+                    send(&self.stdout, &message)?;
+                }
+            }
+            if let Some(Scripted::Response(items)) = self.script.pop_front() {
+                let response = SupermavenMessage::Response(SupermavenResponse {
+                    state_id: update.new_id.to_string(),
+                    items,
+                });
+                send(&self.stdout, &response)?;
+            }
+            received.push(update);
+        }
+        Ok(received)
+    }
+}
+
+fn send(
+    stdout: &mpsc::UnboundedSender<io::Result<Vec<u8>>>,
+    message: &SupermavenMessage,
+) -> Result<()> {
+    let line = format!("{MESSAGE_PREFIX}{}\n", serde_json::to_string(message)?);
+    stdout
+        .unbounded_send(Ok(line.into_bytes()))
+        .context("editor closed the agent's stdout")
+}
+
+/// The writing end of an in-memory pipe.
+pub struct PipeWriter(mpsc::UnboundedSender<io::Result<Vec<u8>>>);
+
+impl AsyncWrite for PipeWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.0.unbounded_send(Ok(buf.to_vec())) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        messages::*, parse_messages, write_message, SupermavenCompletionStateId, SupermavenStats,
+    };
+
+    #[test]
+    fn test_mock_agent_completion() {
+        let (mut agent, connection) = MockAgent::new();
+        agent
+            .then_send(SupermavenMessage::Apology {
+                message: Some("warming up".into()),
+            })
+            .respond_with(vec![
+                ResponseItem::Text {
+                    text: "fn main() {}".into(),
+                },
+                ResponseItem::End,
+            ]);
+
+        let MockAgentConnection { mut stdin, stdout } = connection;
+        let client = async move {
+            let update = OutboundMessage::StateUpdate(StateUpdateMessage {
+                new_id: SupermavenCompletionStateId(5),
+                updates: vec![StateUpdate::FileUpdate(FileUpdateMessage {
+                    path: "/src/main.rs".into(),
+                    content: "fn".into(),
+                    language: None,
+                })],
+            });
+            write_message(&mut stdin, &update, &SupermavenStats::default())
+                .await
+                .unwrap();
+            let messages = parse_messages(stdout).take(2).collect::<Vec<_>>().await;
+            drop(stdin);
+            messages
+        };
+
+        let (received, messages) = smol::block_on(futures::future::join(agent.run(), client));
+        let received = received.unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].new_id, SupermavenCompletionStateId(5));
+
+        assert!(matches!(
+            &messages[0],
+            Ok(SupermavenMessage::Apology { message: Some(message) }) if message == "warming up"
+        ));
+        let Ok(SupermavenMessage::Response(response)) = &messages[1] else {
+            panic!("expected a response, got {:?}", messages[1]);
+        };
+        assert_eq!(response.state_id, "5");
+        let completion = crate::CompletionBuilder::from_items(&response.items);
+        assert_eq!(completion.text(), "fn main() {}");
+        assert!(completion.is_finished());
+    }
+}
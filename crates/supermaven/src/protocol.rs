@@ -0,0 +1,614 @@
+use crate::{
+    messages::{OutboundMessage, SupermavenMessage, SupermavenResponse},
+    MessageDirection, SupermavenCompletionStateId, SupermavenStats,
+};
+use anyhow::{anyhow, Context as _, Result};
+use futures::{
+    stream, AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, Stream, StreamExt,
+};
+use serde::Deserialize;
+use std::{collections::VecDeque, fmt, io, sync::Arc};
+
+/// Prefix of the stdout lines that carry messages. Anything else the agent prints is logging.
+pub const MESSAGE_PREFIX: &str = "SM-MESSAGE ";
+
+/// The longest line [`parse_messages`] buffers before discarding it.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 8 * 1024 * 1024;
+
+/// Decodes the agent's newline-delimited stdout into messages. Blank lines and lines without
+/// [`MESSAGE_PREFIX`] are skipped, and a line that fails to parse yields an error without
+/// ending the stream. Such errors are also logged as warnings, and their messages never
+/// include the line itself.
+///
+/// Some agent builds put several whitespace-separated messages after a single prefix, which
+/// are yielded one by one. A message that fails to parse also drops the rest of its line.
+pub fn parse_messages<R: AsyncBufRead + Unpin>(
+    reader: R,
+) -> impl Stream<Item = Result<SupermavenMessage>> {
+    parse_messages_with_limit(reader, DEFAULT_MAX_LINE_LENGTH)
+}
+
+/// Like [`parse_messages`], but lines longer than `max_line_length` bytes yield an error and
+/// are skipped up to the next newline rather than buffered.
+pub fn parse_messages_with_limit<R: AsyncBufRead + Unpin>(
+    reader: R,
+    max_line_length: usize,
+) -> impl Stream<Item = Result<SupermavenMessage>> {
+    parse_messages_with_stats(reader, max_line_length, Arc::default())
+}
+
+/// Like [`parse_messages_with_limit`], recording each decoded message and its size in `stats`.
+pub fn parse_messages_with_stats<R: AsyncBufRead + Unpin>(
+    reader: R,
+    max_line_length: usize,
+    stats: Arc<SupermavenStats>,
+) -> impl Stream<Item = Result<SupermavenMessage>> {
+    let line_stats = stats.clone();
+    stream::unfold(Some(reader), move |reader| async move {
+        let mut reader = reader?;
+        match read_line(&mut reader, max_line_length).await {
+            Ok(Some(line)) => Some((line, Some(reader))),
+            Ok(None) => None,
+            // The reader is unusable after an I/O error, so end the stream.
+            Err(error) => Some((Err(error).context("failed to read line from stdout"), None)),
+        }
+    })
+    .flat_map(move |line| stream::iter(parse_line(line, &line_stats)))
+    .inspect(move |message| {
+        stats.record_received(message);
+        match message {
+            Ok(message) => log::trace!(
+                "received supermaven {} message (state {:?})",
+                message.kind(),
+                message.state_id()
+            ),
+            Err(error) => log::warn!("{error}"),
+        }
+    })
+}
+
+/// The final item of [`detect_agent_exit`] when the agent's output ends while a completion is
+/// still streaming, meaning the agent died rather than shut down.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AgentExited {
+    /// The state whose completion was cut off.
+    pub state_id: String,
+}
+
+impl fmt::Display for AgentExited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "supermaven agent exited while streaming state {}",
+            self.state_id
+        )
+    }
+}
+
+impl std::error::Error for AgentExited {}
+
+/// Passes `messages` through, appending an [`AgentExited`] error if they end before the most
+/// recent state's response has seen its `End`. Older states are superseded by newer ones and
+/// may legitimately never finish, so only the latest one counts.
+pub fn detect_agent_exit<S: Stream<Item = Result<SupermavenMessage>>>(
+    messages: S,
+) -> impl Stream<Item = Result<SupermavenMessage>> {
+    stream::unfold(
+        Some((Box::pin(messages), None::<String>)),
+        |state| async move {
+            let (mut messages, mut streaming_state) = state?;
+            match messages.next().await {
+                Some(message) => {
+                    if let Some(response) = message.as_ref().ok().and_then(response) {
+                        if !response.is_final() {
+                            streaming_state = Some(response.state_id.clone());
+                        } else if streaming_state.as_ref() == Some(&response.state_id) {
+                            streaming_state = None;
+                        }
+                    }
+                    Some((message, Some((messages, streaming_state))))
+                }
+                None => {
+                    streaming_state.map(|state_id| (Err(AgentExited { state_id }.into()), None))
+                }
+            }
+        },
+    )
+}
+
+/// The response carried by `message`, looking through any passthrough layers.
+fn response(mut message: &SupermavenMessage) -> Option<&SupermavenResponse> {
+    while let SupermavenMessage::Passthrough { passthrough } = message {
+        message = passthrough;
+    }
+    match message {
+        SupermavenMessage::Response(response) => Some(response),
+        _ => None,
+    }
+}
+
+/// Reads up to the next newline, returning `None` at the end of the input. The inner result
+/// is an error when the line is too long or isn't UTF-8.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_line_length: usize,
+) -> io::Result<Option<Result<String>>> {
+    let mut line = Vec::new();
+    let mut line_length = 0;
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            if line_length == 0 {
+                return Ok(None);
+            }
+            break;
+        }
+
+        let (chunk, found_newline) = match available.iter().position(|byte| *byte == b'\n') {
+            Some(ix) => (&available[..ix], true),
+            None => (available, false),
+        };
+        line_length += chunk.len();
+        if line_length <= max_line_length {
+            line.extend_from_slice(chunk);
+        } else {
+            line = Vec::new();
+        }
+
+        let consumed = chunk.len() + found_newline as usize;
+        reader.consume_unpin(consumed);
+        if found_newline {
+            break;
+        }
+    }
+
+    if line_length > max_line_length {
+        return Ok(Some(Err(anyhow!(
+            "discarded a {line_length} byte line from stdout, longer than the limit of {max_line_length} bytes"
+        ))));
+    }
+    Ok(Some(
+        String::from_utf8(line).context("line from stdout is not valid UTF-8"),
+    ))
+}
+
+fn parse_line(line: Result<String>, stats: &SupermavenStats) -> Vec<Result<SupermavenMessage>> {
+    let line = match line {
+        Ok(line) => line,
+        Err(error) => return vec![Err(error)],
+    };
+    let Some(line) = line.trim_end().strip_prefix(MESSAGE_PREFIX) else {
+        return Vec::new();
+    };
+
+    let mut messages = Vec::new();
+    let mut values = serde_json::Deserializer::from_str(line).into_iter::<SupermavenMessage>();
+    loop {
+        let start = values.byte_offset();
+        let rest = &line[start..];
+        match values.next() {
+            None => break,
+            Some(Ok(message)) => {
+                let message_json = line[start..values.byte_offset()].trim_start();
+                stats.record_message_bytes(MessageDirection::Inbound, message_json.len());
+                if let SupermavenMessage::Unknown = message {
+                    log::debug!(
+                        "ignoring supermaven message with unknown kind: {:?}",
+                        message_kind(rest)
+                    );
+                }
+                messages.push(Ok(message));
+            }
+            Some(Err(error)) => {
+                // The line may contain source code, so describe the failure without quoting
+                // it. There's no telling where the next message would start, so stop here.
+                messages.push(Err(anyhow!(
+                    "failed to deserialize {:?} message from stdout: {:?} error at line {} column {}",
+                    message_kind(rest),
+                    error.classify(),
+                    error.line(),
+                    error.column()
+                )));
+                break;
+            }
+        }
+    }
+    messages
+}
+
+/// Writes a message to the agent's stdin as a single line of JSON and flushes it.
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &OutboundMessage,
+    stats: &SupermavenStats,
+) -> Result<()> {
+    let mut bytes = serde_json::to_vec(message).context("failed to serialize message")?;
+    debug_assert!(
+        !bytes.contains(&b'\n'),
+        "serialized message contains a newline"
+    );
+    stats.record_message_bytes(MessageDirection::Outbound, bytes.len());
+    bytes.push(b'\n');
+    writer.write_all(&bytes).await?;
+    writer.flush().await?;
+    stats.record_sent();
+    log::trace!(
+        "sent supermaven {} message (state {:?})",
+        message.kind(),
+        message.state_id()
+    );
+    Ok(())
+}
+
+/// How many of the most recently sent state ids [`SentStateIds`] remembers.
+pub const SENT_STATE_ID_HISTORY: usize = 256;
+
+/// Remembers the ids of the states recently sent to the agent, to catch a `new_id` being
+/// reused. The agent's responses to a reused id would be ambiguous.
+pub struct SentStateIds {
+    recent: VecDeque<SupermavenCompletionStateId>,
+    capacity: usize,
+}
+
+impl Default for SentStateIds {
+    fn default() -> Self {
+        Self::new(SENT_STATE_ID_HISTORY)
+    }
+}
+
+impl SentStateIds {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            recent: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records the state created by `message`, failing if the same id was sent recently.
+    /// Messages that don't create a state are always accepted.
+    pub fn record(&mut self, message: &OutboundMessage) -> Result<()> {
+        let Some(state_id) = message.state_id() else {
+            return Ok(());
+        };
+        if self.recent.contains(&state_id) {
+            return Err(anyhow!("state id {state_id} was already sent to the agent"));
+        }
+        if self.recent.len() == self.capacity {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(state_id);
+        Ok(())
+    }
+}
+
+/// Extracts the `kind` of the first message in `line`, for diagnostics about messages we
+/// don't model.
+fn message_kind(line: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct MessageKind {
+        kind: String,
+    }
+
+    serde_json::Deserializer::from_str(line)
+        .into_iter::<MessageKind>()
+        .next()?
+        .ok()
+        .map(|message| message.kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_logger;
+
+    #[test]
+    fn test_parse_messages() {
+        let input = [
+            r#"SM-MESSAGE {"kind":"activation_success"}"#,
+            "",
+            "some agent log output",
+            r#"SM-MESSAGE {"kind":"apology","message":"sorry"}"#,
+            "SM-MESSAGE {not json",
+            "   ",
+            r#"SM-MESSAGE {"kind":"future_thing"}"#,
+            r#"SM-MESSAGE {"kind":"response","stateId":"1","items":[]}"#,
+        ]
+        .join("\n");
+
+        let messages = smol::block_on(parse_messages(input.as_bytes()).collect::<Vec<_>>());
+        assert_eq!(messages.len(), 5);
+        assert!(matches!(
+            messages[0],
+            Ok(SupermavenMessage::ActivationSuccess)
+        ));
+        assert!(matches!(messages[1], Ok(SupermavenMessage::Apology { .. })));
+        assert!(messages[2].is_err());
+        assert!(matches!(messages[3], Ok(SupermavenMessage::Unknown)));
+        assert!(matches!(messages[4], Ok(SupermavenMessage::Response(_))));
+    }
+
+    #[test]
+    fn test_parse_multiple_messages_per_line() {
+        let input = concat!(
+            r#"SM-MESSAGE {"kind":"activation_success"} {"kind":"apology","message":"sorry"}"#,
+            "\n",
+            r#"SM-MESSAGE {"kind":"metadata","dust_strings":null}{"kind":"future_thing"}  "#,
+            "\n",
+            r#"SM-MESSAGE {"kind":"activation_success"} {oops} {"kind":"activation_success"}"#,
+            "\n",
+        );
+        let messages = smol::block_on(parse_messages(input.as_bytes()).collect::<Vec<_>>());
+        assert_eq!(messages.len(), 6);
+        assert!(matches!(
+            messages[0],
+            Ok(SupermavenMessage::ActivationSuccess)
+        ));
+        assert!(matches!(messages[1], Ok(SupermavenMessage::Apology { .. })));
+        assert!(matches!(messages[2], Ok(SupermavenMessage::Metadata(_))));
+        assert!(matches!(messages[3], Ok(SupermavenMessage::Unknown)));
+        assert!(matches!(
+            messages[4],
+            Ok(SupermavenMessage::ActivationSuccess)
+        ));
+        assert!(messages[5].is_err());
+
+        // A message split across two reads is buffered until the rest of it arrives.
+        let chunks = [
+            r#"SM-MESSAGE {"kind":"activation_success"} {"kind":"apol"#,
+            r#"ogy","message":"sorry"}"#,
+            "\n",
+        ];
+        let reader =
+            futures::TryStreamExt::into_async_read(stream::iter(chunks.map(io::Result::Ok)));
+        let messages = smol::block_on(parse_messages(reader).collect::<Vec<_>>());
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(
+            messages[0],
+            Ok(SupermavenMessage::ActivationSuccess)
+        ));
+        assert!(
+            matches!(&messages[1], Ok(SupermavenMessage::Apology { message }) if message.as_deref() == Some("sorry"))
+        );
+    }
+
+    #[test]
+    fn test_parse_messages_with_line_limit() {
+        let input = format!(
+            "SM-MESSAGE {{\"kind\":\"apology\",\"message\":\"{}\"}}\n{}\n",
+            "x".repeat(100),
+            r#"SM-MESSAGE {"kind":"activation_success"}"#,
+        );
+
+        let messages =
+            smol::block_on(parse_messages_with_limit(input.as_bytes(), 64).collect::<Vec<_>>());
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].is_err());
+        assert!(matches!(
+            messages[1],
+            Ok(SupermavenMessage::ActivationSuccess)
+        ));
+
+        let messages = smol::block_on(parse_messages(input.as_bytes()).collect::<Vec<_>>());
+        assert!(matches!(messages[0], Ok(SupermavenMessage::Apology { .. })));
+    }
+
+    #[test]
+    fn test_detect_agent_exit() {
+        let input = [
+            r#"SM-MESSAGE {"kind":"response","stateId":"1","items":[{"kind":"text","text":"a"}]}"#,
+            r#"SM-MESSAGE {"kind":"response","stateId":"2","items":[{"kind":"text","text":"b"}]}"#,
+            r#"SM-MESSAGE {"kind":"response","stateId":"2","items":[{"kind":"end"}]}"#,
+        ]
+        .join("\n");
+        let messages =
+            smol::block_on(detect_agent_exit(parse_messages(input.as_bytes())).collect::<Vec<_>>());
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|message| message.is_ok()));
+
+        // The stream is cut off before state 2 ends.
+        let input = [
+            r#"SM-MESSAGE {"kind":"response","stateId":"2","items":[{"kind":"text","text":"b"}]}"#,
+            r#"SM-MESSAGE {"kind":"passthrough","passthrough":{"kind":"response","stateId":"2","items":[{"kind":"text","text":"c"}]}}"#,
+            r#"SM-MESSAGE {"kind":"response","stateId":"1","items":[{"kind":"end"}]}"#,
+        ]
+        .join("\n");
+        let mut messages =
+            smol::block_on(detect_agent_exit(parse_messages(input.as_bytes())).collect::<Vec<_>>());
+        assert_eq!(messages.len(), 4);
+        let error = messages.pop().unwrap().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<AgentExited>(),
+            Some(&AgentExited {
+                state_id: "2".into()
+            })
+        );
+        assert!(messages.iter().all(|message| message.is_ok()));
+    }
+
+    #[test]
+    fn test_parse_errors_are_logged_without_content() {
+        let input = [
+            r#"SM-MESSAGE {"kind":"apology","message":"sorry"}"#,
+            r#"SM-MESSAGE {"kind":"response","stateId":1,"items":[{"kind":"text","text":"let secret = 42;"}]}"#,
+        ]
+        .join("\n");
+
+        let (messages, records) = test_logger::capture(|| {
+            smol::block_on(parse_messages(input.as_bytes()).collect::<Vec<_>>())
+        });
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1].is_err());
+        assert!(!messages[1]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("secret"));
+
+        let warnings = records
+            .iter()
+            .filter(|(level, _)| *level == log::Level::Warn)
+            .collect::<Vec<_>>();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].1.contains("\"response\""));
+        assert!(records
+            .iter()
+            .any(|(level, message)| *level == log::Level::Trace && message.contains("apology")));
+        assert!(records
+            .iter()
+            .all(|(_, message)| !message.contains("secret")));
+    }
+
+    #[test]
+    fn test_sent_state_ids() {
+        use crate::messages::*;
+
+        let state_update = |id| {
+            OutboundMessage::StateUpdate(StateUpdateMessage {
+                new_id: SupermavenCompletionStateId(id),
+                updates: Vec::new(),
+            })
+        };
+
+        let mut sent = SentStateIds::new(2);
+        sent.record(&state_update(1)).unwrap();
+        sent.record(&OutboundMessage::UseFreeVersion).unwrap();
+        sent.record(&OutboundMessage::UseFreeVersion).unwrap();
+        assert_eq!(
+            sent.record(&state_update(1)).unwrap_err().to_string(),
+            "state id 1 was already sent to the agent"
+        );
+
+        sent.record(&state_update(2)).unwrap();
+        sent.record(&state_update(3)).unwrap();
+        assert!(sent.record(&state_update(3)).is_err());
+        // Only the most recent ids are remembered.
+        sent.record(&state_update(1)).unwrap();
+    }
+
+    #[test]
+    fn test_write_message() {
+        use crate::{messages::*, SupermavenCompletionStateId};
+
+        let message = OutboundMessage::StateUpdate(StateUpdateMessage {
+            new_id: SupermavenCompletionStateId(7),
+            updates: vec![StateUpdate::FileUpdate(FileUpdateMessage {
+                path: "/src/main.rs".into(),
+                content: "fn main() {\n    println!(\"hi\");\n}\n".into(),
+                language: None,
+            })],
+        });
+
+        let mut output = Vec::new();
+        let stats = SupermavenStats::default();
+        smol::block_on(async {
+            write_message(&mut output, &message, &stats).await.unwrap();
+            write_message(&mut output, &OutboundMessage::UseFreeVersion, &stats)
+                .await
+                .unwrap();
+        });
+        assert_eq!(stats.messages_sent(), 2);
+
+        let lines = smol::block_on(output.as_slice().lines().collect::<Vec<_>>());
+        assert_eq!(lines.len(), 2);
+        assert!(output.ends_with(b"\n"));
+
+        let state_update =
+            serde_json::from_str::<StateUpdateMessage>(lines[0].as_ref().unwrap()).unwrap();
+        assert_eq!(state_update.new_id, SupermavenCompletionStateId(7));
+        let [StateUpdate::FileUpdate(file_update)] = state_update.updates.as_slice() else {
+            panic!("expected a single file update");
+        };
+        assert_eq!(
+            file_update.content,
+            "fn main() {\n    println!(\"hi\");\n}\n"
+        );
+        assert_eq!(lines[1].as_ref().unwrap(), r#"{"kind":"use_free_version"}"#);
+    }
+
+    #[test]
+    fn test_stats() {
+        let input = [
+            r#"SM-MESSAGE {"kind":"activation_success"}"#,
+            r#"SM-MESSAGE {"kind":"response","stateId":"1","items":[{"kind":"text","text":"a"}]}"#,
+            r#"SM-MESSAGE {"kind":"response","stateId":"1","items":[{"kind":"end"}]}"#,
+            "SM-MESSAGE {not json",
+            "agent log output",
+        ]
+        .join("\n");
+
+        let stats = Arc::new(SupermavenStats::default());
+        let messages = smol::block_on(
+            parse_messages_with_stats(input.as_bytes(), DEFAULT_MAX_LINE_LENGTH, stats.clone())
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(messages.len(), 4);
+        assert_eq!(stats.messages_received(), 3);
+        assert_eq!(stats.parse_errors(), 1);
+        assert_eq!(stats.responses_completed(), 1);
+        assert_eq!(stats.messages_sent(), 0);
+    }
+
+    #[test]
+    fn test_message_size_histogram() {
+        use crate::{messages::*, MESSAGE_SIZE_BUCKETS};
+        use std::sync::Mutex;
+
+        let apology = |len: usize| {
+            format!(
+                r#"{{"kind":"apology","message":"{}"}}"#,
+                "x".repeat(len - r#"{"kind":"apology","message":""}"#.len())
+            )
+        };
+        let input = [
+            format!("SM-MESSAGE {}", apology(100)),
+            format!("SM-MESSAGE {} {}", apology(200), apology(256)),
+            format!("SM-MESSAGE {}", apology(2000)),
+            format!("SM-MESSAGE {}", apology(300 * 1024)),
+            "SM-MESSAGE {not json".into(),
+            "agent log output".into(),
+        ]
+        .join("\n");
+
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let stats = Arc::new(SupermavenStats::default().on_message_bytes({
+            let sizes = sizes.clone();
+            move |direction, len| sizes.lock().unwrap().push((direction, len))
+        }));
+        let messages = smol::block_on(
+            parse_messages_with_stats(input.as_bytes(), DEFAULT_MAX_LINE_LENGTH, stats.clone())
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(messages.len(), 6);
+
+        let inbound = stats.message_sizes(MessageDirection::Inbound);
+        assert_eq!(inbound.counts(), [3, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(inbound.total(), 5);
+
+        let message = OutboundMessage::StateUpdate(StateUpdateMessage {
+            new_id: SupermavenCompletionStateId(1),
+            updates: vec![StateUpdate::FileUpdate(FileUpdateMessage {
+                path: "/src/main.rs".into(),
+                content: "x".repeat(10 * 1024),
+                language: None,
+            })],
+        });
+        let mut output = Vec::new();
+        smol::block_on(write_message(&mut output, &message, &stats)).unwrap();
+        let outbound = stats.message_sizes(MessageDirection::Outbound);
+        assert_eq!(outbound.counts(), [0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(outbound.counts().len(), MESSAGE_SIZE_BUCKETS.len() + 1);
+
+        let sizes = sizes.lock().unwrap();
+        assert_eq!(
+            sizes.as_slice(),
+            &[
+                (MessageDirection::Inbound, 100),
+                (MessageDirection::Inbound, 200),
+                (MessageDirection::Inbound, 256),
+                (MessageDirection::Inbound, 2000),
+                (MessageDirection::Inbound, 300 * 1024),
+                (MessageDirection::Outbound, output.len() - 1),
+            ]
+        );
+    }
+}
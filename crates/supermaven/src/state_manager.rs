@@ -0,0 +1,583 @@
+use crate::{messages::ResponseItem, CompletionBuilder, SupermavenCompletionStateId};
+use collections::BTreeMap;
+use futures::channel::mpsc;
+use std::time::{Duration, Instant};
+
+/// How many cancelled states [`StateManager::cancel_older_than`] keeps around.
+pub const MAX_CANCELLED_STATES: usize = 32;
+
+/// Tracks the completion streamed by the agent for each state we've sent it, along with
+/// caller-specific `data` for that state.
+pub struct StateManager<T> {
+    states: BTreeMap<SupermavenCompletionStateId, StateEntry<T>>,
+    completed_tx: Option<mpsc::UnboundedSender<SupermavenCompletionStateId>>,
+    timed_out_tx: Option<mpsc::UnboundedSender<SupermavenCompletionStateId>>,
+    timeout: Option<(Duration, TimeoutPolicy)>,
+    segment_policy: Option<Box<SegmentPolicy>>,
+}
+
+/// The part of a completion between two `Barrier`s, assembled like a whole completion.
+pub type Segment = CompletionBuilder;
+
+/// Decides how many of a state's leading segments to show inline. See
+/// [`StateManager::with_segment_policy`].
+pub type SegmentPolicy = dyn Fn(&[Segment]) -> usize + Send + Sync;
+
+/// A state's segments, split into those shown inline and those left for "accept more".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SegmentSelection<'a> {
+    pub inline: &'a [Segment],
+    pub remaining: &'a [Segment],
+}
+
+impl SegmentSelection<'_> {
+    /// The text of the inline segments, joined.
+    pub fn inline_text(&self) -> String {
+        self.inline.iter().map(Segment::text).collect()
+    }
+}
+
+/// What happens to the partial completion of a state that times out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutPolicy {
+    /// Keep the text received so far as the state's final completion.
+    KeepPartial,
+    /// Drop the state as if it had been cancelled.
+    Discard,
+}
+
+pub struct StateEntry<T> {
+    pub data: T,
+    pub completion: CompletionBuilder,
+    segments: Vec<Segment>,
+    inserted_at: Instant,
+    complete: bool,
+    timed_out: bool,
+    cancelled: bool,
+}
+
+impl<T> StateEntry<T> {
+    /// Whether the agent has sent an `End` for this state.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Whether the agent failed to finish this state within the timeout. Its completion won't
+    /// grow any further.
+    pub fn is_timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// The completion split at every `Barrier`. Unlike [`Self::completion`], which stops at the
+    /// first barrier, this keeps streaming until the `End`.
+    pub fn segments(&self) -> &[Segment] {
+        match self.segments.split_last() {
+            // A barrier right before the end doesn't start a segment worth offering.
+            Some((last, rest)) if !rest.is_empty() && last.text().is_empty() => rest,
+            _ => &self.segments,
+        }
+    }
+
+    fn push_segments(&mut self, items: &[ResponseItem]) {
+        for item in items {
+            let Some(segment) = self.segments.last_mut() else {
+                return;
+            };
+            // Barriers start a new segment right away, so a finished last segment saw an `End`.
+            if segment.is_finished() {
+                return;
+            }
+            segment.push(item);
+            if matches!(item, ResponseItem::Barrier) {
+                self.segments.push(Segment::new());
+            }
+        }
+    }
+}
+
+impl<T> Default for StateManager<T> {
+    fn default() -> Self {
+        Self {
+            states: BTreeMap::default(),
+            completed_tx: None,
+            timed_out_tx: None,
+            timeout: None,
+            segment_policy: None,
+        }
+    }
+}
+
+impl<T> StateManager<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gives every state `timeout` to receive its `End`, after which
+    /// [`Self::expire_timed_out`] finalizes it according to `policy`. States never time out
+    /// by default.
+    pub fn with_timeout(mut self, timeout: Duration, policy: TimeoutPolicy) -> Self {
+        self.timeout = Some((timeout, policy));
+        self
+    }
+
+    /// Sets how many leading segments [`Self::select_segments`] shows inline, e.g. to prefer a
+    /// longer suggestion when the first segment is short. The result is clamped to at least
+    /// one segment. By default only the segment before the first `Barrier` is shown.
+    pub fn with_segment_policy(
+        mut self,
+        policy: impl Fn(&[Segment]) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.segment_policy = Some(Box::new(policy));
+        self
+    }
+
+    pub fn insert(&mut self, id: SupermavenCompletionStateId, data: T) {
+        self.states.insert(
+            id,
+            StateEntry {
+                data,
+                completion: CompletionBuilder::new(),
+                segments: vec![Segment::new()],
+                inserted_at: Instant::now(),
+                complete: false,
+                timed_out: false,
+                cancelled: false,
+            },
+        );
+    }
+
+    /// Returns the state with the given id, unless it has been cancelled.
+    pub fn get(&self, id: SupermavenCompletionStateId) -> Option<&StateEntry<T>> {
+        self.states.get(&id).filter(|state| !state.cancelled)
+    }
+
+    /// Like [`Self::get`], but returns the state mutably.
+    pub fn get_mut(&mut self, id: SupermavenCompletionStateId) -> Option<&mut StateEntry<T>> {
+        self.states.get_mut(&id).filter(|state| !state.cancelled)
+    }
+
+    /// Appends the items of a response to the state they belong to, returning that state if
+    /// it is still tracked. Responses for cancelled or timed out states are dropped.
+    pub fn push_response(
+        &mut self,
+        id: SupermavenCompletionStateId,
+        items: &[ResponseItem],
+    ) -> Option<&mut StateEntry<T>> {
+        let state = self
+            .states
+            .get_mut(&id)
+            .filter(|state| !state.cancelled && !state.timed_out)?;
+        state.completion.extend(items);
+        state.push_segments(items);
+        if !state.complete && items.iter().any(|item| matches!(item, ResponseItem::End)) {
+            state.complete = true;
+            if let Some(completed_tx) = &self.completed_tx {
+                completed_tx.unbounded_send(id).ok();
+            }
+        }
+        Some(state)
+    }
+
+    /// Returns a stream that yields each state's id once, when its `End` arrives. Replaces any
+    /// previously returned stream.
+    pub fn on_complete(&mut self) -> mpsc::UnboundedReceiver<SupermavenCompletionStateId> {
+        let (completed_tx, completed_rx) = mpsc::unbounded();
+        self.completed_tx = Some(completed_tx);
+        completed_rx
+    }
+
+    /// Returns a stream that yields the id of each state that times out. Replaces any
+    /// previously returned stream.
+    pub fn on_timeout(&mut self) -> mpsc::UnboundedReceiver<SupermavenCompletionStateId> {
+        let (timed_out_tx, timed_out_rx) = mpsc::unbounded();
+        self.timed_out_tx = Some(timed_out_tx);
+        timed_out_rx
+    }
+
+    /// Finalizes every unfinished state that has been waiting for its `End` longer than the
+    /// timeout as of `now`, returning their ids.
+    pub fn expire_timed_out(&mut self, now: Instant) -> Vec<SupermavenCompletionStateId> {
+        let Some((timeout, policy)) = self.timeout else {
+            return Vec::new();
+        };
+
+        let mut expired = Vec::new();
+        for (id, state) in &mut self.states {
+            if state.complete
+                || state.timed_out
+                || state.cancelled
+                || now.saturating_duration_since(state.inserted_at) < timeout
+            {
+                continue;
+            }
+            state.timed_out = true;
+            if policy == TimeoutPolicy::Discard {
+                state.cancelled = true;
+            }
+            if let Some(timed_out_tx) = &self.timed_out_tx {
+                timed_out_tx.unbounded_send(*id).ok();
+            }
+            expired.push(*id);
+        }
+        expired
+    }
+
+    /// The best completion assembled so far for the given state.
+    pub fn completion(&self, id: SupermavenCompletionStateId) -> Option<&str> {
+        Some(self.get(id)?.completion.text())
+    }
+
+    /// Forgets the given state and returns the completion assembled for it so far, e.g. to
+    /// still offer it as a partial completion after the agent's output stream fails before
+    /// the `End`. Returns `None` for unknown or cancelled states.
+    pub fn take_partial(&mut self, id: SupermavenCompletionStateId) -> Option<String> {
+        let state = self.states.remove(&id)?;
+        if state.cancelled {
+            return None;
+        }
+        Some(state.completion.text().to_string())
+    }
+
+    /// Splits the given state's segments into those shown inline and the rest, according to
+    /// the segment policy.
+    pub fn select_segments(&self, id: SupermavenCompletionStateId) -> Option<SegmentSelection> {
+        let segments = self.get(id)?.segments();
+        let inline_count = self
+            .segment_policy
+            .as_ref()
+            .map_or(1, |policy| policy(segments))
+            .clamp(1, segments.len());
+        let (inline, remaining) = segments.split_at(inline_count);
+        Some(SegmentSelection { inline, remaining })
+    }
+
+    /// Marks every state older than `id` as cancelled, since a newer state supersedes them.
+    /// Unlike [`Self::prune_older_than`], this keeps the latest [`MAX_CANCELLED_STATES`] of
+    /// them around so that late responses for them are recognized and dropped, and forgets
+    /// the rest.
+    pub fn cancel_older_than(&mut self, id: SupermavenCompletionStateId) {
+        let mut cancelled = 0;
+        for (_, state) in self.states.range_mut(..id).rev() {
+            state.cancelled = true;
+            cancelled += 1;
+            if cancelled == MAX_CANCELLED_STATES {
+                break;
+            }
+        }
+        if let Some(oldest_kept) = self.states.range(..id).rev().nth(MAX_CANCELLED_STATES - 1) {
+            let oldest_kept = *oldest_kept.0;
+            self.prune_older_than(oldest_kept);
+        }
+    }
+
+    /// Forgets every state older than `id`, e.g. once a newer state has been sent to the
+    /// agent.
+    pub fn prune_older_than(&mut self, id: SupermavenCompletionStateId) {
+        self.states = self.states.split_off(&id);
+    }
+
+    /// Forgets every state, e.g. when the agent's state is reset. Late responses for them are
+    /// dropped, because their ids are no longer tracked.
+    pub fn clear(&mut self) {
+        self.states.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> ResponseItem {
+        ResponseItem::Text { text: text.into() }
+    }
+
+    #[test]
+    fn test_interleaved_responses() {
+        let first = SupermavenCompletionStateId(1);
+        let second = SupermavenCompletionStateId(2);
+        let mut states = StateManager::new();
+        states.insert(first, ());
+        states.insert(second, ());
+
+        states.push_response(first, &[text("fn ")]);
+        states.push_response(second, &[text("let ")]);
+        states.push_response(first, &[text("main"), ResponseItem::End]);
+        states.push_response(second, &[text("x")]);
+
+        assert_eq!(states.completion(first), Some("fn main"));
+        assert!(states.get(first).unwrap().is_complete());
+        assert_eq!(states.completion(second), Some("let x"));
+        assert!(!states.get(second).unwrap().is_complete());
+
+        assert!(states
+            .push_response(SupermavenCompletionStateId(3), &[text("?")])
+            .is_none());
+    }
+
+    #[test]
+    fn test_prune_older_than() {
+        let mut states = StateManager::new();
+        for id in 1..=3 {
+            states.insert(SupermavenCompletionStateId(id), ());
+        }
+
+        states.prune_older_than(SupermavenCompletionStateId(2));
+
+        assert!(states.get(SupermavenCompletionStateId(1)).is_none());
+        assert!(states.get(SupermavenCompletionStateId(2)).is_some());
+        assert!(states.get(SupermavenCompletionStateId(3)).is_some());
+        assert_eq!(states.len(), 2);
+    }
+
+    #[test]
+    fn test_on_complete_fires_once() {
+        let first = SupermavenCompletionStateId(1);
+        let second = SupermavenCompletionStateId(2);
+        let mut states = StateManager::new();
+        let mut completed = states.on_complete();
+        states.insert(first, ());
+        states.insert(second, ());
+
+        states.push_response(first, &[text("a"), ResponseItem::End, text("b")]);
+        states.push_response(first, &[text("c"), ResponseItem::End]);
+        states.push_response(second, &[text("x")]);
+        assert_eq!(states.completion(first), Some("a"));
+
+        assert_eq!(completed.try_next().unwrap(), Some(first));
+        assert!(completed.try_next().is_err());
+
+        states.push_response(second, &[ResponseItem::End]);
+        assert_eq!(completed.try_next().unwrap(), Some(second));
+        assert!(completed.try_next().is_err());
+    }
+
+    #[test]
+    fn test_cancel_older_than() {
+        let older = SupermavenCompletionStateId(1);
+        let newer = SupermavenCompletionStateId(2);
+        let mut states = StateManager::new();
+        states.insert(older, ());
+        states.insert(newer, ());
+
+        states.push_response(older, &[text("fo")]);
+        states.push_response(newer, &[text("foo")]);
+        states.cancel_older_than(newer);
+
+        assert!(states.push_response(older, &[text("o")]).is_none());
+        assert!(states.get(older).is_none());
+        assert_eq!(states.completion(older), None);
+        assert_eq!(states.completion(newer), Some("foo"));
+        assert_eq!(states.len(), 2);
+    }
+
+    #[test]
+    fn test_cancel_older_than_bounds_cancelled_states() {
+        let mut states = StateManager::new();
+        for id in 0..MAX_CANCELLED_STATES * 3 {
+            let id = SupermavenCompletionStateId(id);
+            states.insert(id, ());
+            states.cancel_older_than(id);
+            assert!(states.len() <= MAX_CANCELLED_STATES + 1);
+        }
+
+        let latest = SupermavenCompletionStateId(MAX_CANCELLED_STATES * 3 - 1);
+        assert_eq!(states.len(), MAX_CANCELLED_STATES + 1);
+        assert!(states.get(latest).is_some());
+        for id in 0..MAX_CANCELLED_STATES * 3 - 1 {
+            assert!(states
+                .push_response(SupermavenCompletionStateId(id), &[text("late")])
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_expire_timed_out() {
+        let timeout = Duration::from_secs(5);
+        let finished = SupermavenCompletionStateId(1);
+        let stalled = SupermavenCompletionStateId(2);
+        let mut states = StateManager::new().with_timeout(timeout, TimeoutPolicy::KeepPartial);
+        let mut completed = states.on_complete();
+        let mut timed_out = states.on_timeout();
+        states.insert(finished, ());
+        states.insert(stalled, ());
+        let start = Instant::now();
+
+        states.push_response(finished, &[text("done"), ResponseItem::End]);
+        states.push_response(stalled, &[text("par")]);
+        assert!(states.expire_timed_out(start).is_empty());
+
+        let later = start + timeout + Duration::from_secs(1);
+        assert_eq!(states.expire_timed_out(later), [stalled]);
+        assert!(states.expire_timed_out(later).is_empty());
+
+        assert_eq!(completed.try_next().unwrap(), Some(finished));
+        assert!(completed.try_next().is_err());
+        assert_eq!(timed_out.try_next().unwrap(), Some(stalled));
+        assert!(timed_out.try_next().is_err());
+
+        let state = states.get(stalled).unwrap();
+        assert!(state.is_timed_out());
+        assert!(!state.is_complete());
+        assert_eq!(states.completion(stalled), Some("par"));
+        assert!(states.push_response(stalled, &[text("tial")]).is_none());
+        assert_eq!(states.completion(stalled), Some("par"));
+    }
+
+    #[test]
+    fn test_expire_timed_out_discard() {
+        let timeout = Duration::from_secs(5);
+        let id = SupermavenCompletionStateId(1);
+        let mut states = StateManager::new().with_timeout(timeout, TimeoutPolicy::Discard);
+        states.insert(id, ());
+        states.push_response(id, &[text("par")]);
+
+        let later = Instant::now() + timeout;
+        assert_eq!(states.expire_timed_out(later), [id]);
+        assert!(states.get(id).is_none());
+        assert_eq!(states.completion(id), None);
+
+        let mut states = StateManager::<()>::new();
+        states.insert(id, ());
+        assert!(states.expire_timed_out(later).is_empty());
+    }
+
+    fn segment_texts(segments: &[Segment]) -> Vec<&str> {
+        segments.iter().map(Segment::text).collect()
+    }
+
+    #[test]
+    fn test_select_segments() {
+        let id = SupermavenCompletionStateId(1);
+
+        let mut states = StateManager::new();
+        states.insert(id, ());
+        states.push_response(id, &[text("foo()"), ResponseItem::End]);
+        let selection = states.select_segments(id).unwrap();
+        assert_eq!(selection.inline_text(), "foo()");
+        assert!(selection.remaining.is_empty());
+
+        let mut states = StateManager::new();
+        states.insert(id, ());
+        states.push_response(id, &[text("foo("), ResponseItem::Barrier, text("bar)")]);
+        states.push_response(id, &[ResponseItem::Barrier, ResponseItem::End]);
+        let selection = states.select_segments(id).unwrap();
+        assert_eq!(selection.inline_text(), "foo(");
+        assert_eq!(segment_texts(selection.remaining), ["bar)"]);
+
+        let mut states = StateManager::new();
+        states.insert(id, ());
+        states.push_response(
+            id,
+            &[
+                text("a"),
+                ResponseItem::Barrier,
+                text("b"),
+                ResponseItem::Barrier,
+                text("c"),
+                ResponseItem::End,
+                ResponseItem::Barrier,
+                text("ignored"),
+            ],
+        );
+        assert_eq!(states.completion(id), Some("a"));
+        let selection = states.select_segments(id).unwrap();
+        assert_eq!(selection.inline_text(), "a");
+        assert_eq!(segment_texts(selection.remaining), ["b", "c"]);
+    }
+
+    #[test]
+    fn test_segment_policy() {
+        let id = SupermavenCompletionStateId(1);
+        let items = [
+            text("x"),
+            ResponseItem::Barrier,
+            text("yy"),
+            ResponseItem::Barrier,
+            text("zzz"),
+        ];
+
+        // Prefer at least three characters inline.
+        let mut states = StateManager::new().with_segment_policy(|segments| {
+            let mut len = 0;
+            segments
+                .iter()
+                .take_while(|segment| {
+                    let take = len < 3;
+                    len += segment.text().len();
+                    take
+                })
+                .count()
+        });
+        states.insert(id, ());
+        states.push_response(id, &items);
+        let selection = states.select_segments(id).unwrap();
+        assert_eq!(selection.inline_text(), "xyy");
+        assert_eq!(segment_texts(selection.remaining), ["zzz"]);
+
+        let mut states = StateManager::new().with_segment_policy(|_| 0);
+        states.insert(id, ());
+        states.push_response(id, &items);
+        assert_eq!(states.select_segments(id).unwrap().inline_text(), "x");
+
+        let mut states = StateManager::new().with_segment_policy(|_| usize::MAX);
+        states.insert(id, ());
+        states.push_response(id, &items);
+        let selection = states.select_segments(id).unwrap();
+        assert_eq!(selection.inline_text(), "xyyzzz");
+        assert!(selection.remaining.is_empty());
+        assert!(states
+            .select_segments(SupermavenCompletionStateId(2))
+            .is_none());
+    }
+
+    #[test]
+    fn test_take_partial() {
+        let id = SupermavenCompletionStateId(1);
+        let mut states = StateManager::new();
+        states.insert(id, ());
+        states.push_response(id, &[text("fn "), text("ma")]);
+        states.push_response(id, &[text("in(")]);
+
+        // The agent's output stream fails here, before the `End` arrives.
+        assert_eq!(states.take_partial(id).as_deref(), Some("fn main("));
+        assert!(states.is_empty());
+        assert_eq!(states.take_partial(id), None);
+        assert!(states
+            .push_response(id, &[text(")"), ResponseItem::End])
+            .is_none());
+
+        let cancelled = SupermavenCompletionStateId(2);
+        states.insert(cancelled, ());
+        states.push_response(cancelled, &[text("x")]);
+        states.cancel_older_than(SupermavenCompletionStateId(3));
+        assert_eq!(states.take_partial(cancelled), None);
+    }
+
+    #[test]
+    fn test_clear() {
+        let old = SupermavenCompletionStateId(1);
+        let new = SupermavenCompletionStateId(2);
+        let mut states = StateManager::new();
+        states.insert(old, ());
+        states.push_response(old, &[text("a")]);
+
+        states.clear();
+        assert!(states.is_empty());
+        assert!(states
+            .push_response(old, &[text("b"), ResponseItem::End])
+            .is_none());
+
+        states.insert(new, ());
+        states.push_response(new, &[text("c")]);
+        assert_eq!(states.completion(new), Some("c"));
+        assert_eq!(states.completion(old), None);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{
+    messages::{StateUpdate, StateUpdateMessage},
+    SupermavenCompletionStateId,
+};
+
+/// Collects the updates that make up a single state, e.g. a file update, a cursor move and
+/// the selections, and sends them as one [`StateUpdateMessage`].
+///
+/// File changes are flushed ahead of cursor and selection updates regardless of the order they
+/// were pushed in, since cursor offsets refer to the content sent along with them. Otherwise
+/// updates keep the order they were pushed in.
+#[derive(Debug, Default)]
+pub struct StateUpdateBatcher {
+    updates: Vec<StateUpdate>,
+}
+
+impl StateUpdateBatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, update: StateUpdate) {
+        self.updates.push(update);
+    }
+
+    pub fn extend(&mut self, updates: impl IntoIterator<Item = StateUpdate>) {
+        self.updates.extend(updates);
+    }
+
+    pub fn len(&self) -> usize {
+        self.updates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    /// Takes the collected updates as the message creating state `new_id`, or `None` if
+    /// nothing was pushed since the last flush.
+    pub fn flush(&mut self, new_id: SupermavenCompletionStateId) -> Option<StateUpdateMessage> {
+        if self.updates.is_empty() {
+            return None;
+        }
+
+        let mut updates = std::mem::take(&mut self.updates);
+        updates.sort_by_key(|update| {
+            matches!(
+                update,
+                StateUpdate::CursorUpdate(_) | StateUpdate::SelectionsUpdate(_)
+            )
+        });
+        Some(StateUpdateMessage { new_id, updates })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::*;
+
+    #[test]
+    fn test_flush_combines_updates() {
+        let mut batcher = StateUpdateBatcher::new();
+        assert!(batcher.flush(SupermavenCompletionStateId(1)).is_none());
+
+        batcher.push(StateUpdate::CursorUpdate(CursorPositionUpdateMessage {
+            path: "a.rs".into(),
+            offset: 2,
+        }));
+        batcher.push(StateUpdate::FileUpdate(FileUpdateMessage {
+            path: "a.rs".into(),
+            content: "fn".into(),
+            language: None,
+        }));
+        batcher.extend([
+            StateUpdate::SelectionsUpdate(SelectionsUpdateMessage {
+                path: "a.rs".into(),
+                selections: vec![CursorRange { start: 0, end: 2 }],
+            }),
+            StateUpdate::FileUpdate(FileUpdateMessage {
+                path: "b.rs".into(),
+                content: "".into(),
+                language: None,
+            }),
+        ]);
+        assert_eq!(batcher.len(), 4);
+
+        let message = batcher.flush(SupermavenCompletionStateId(1)).unwrap();
+        assert_eq!(message.new_id, SupermavenCompletionStateId(1));
+        let [StateUpdate::FileUpdate(first), StateUpdate::FileUpdate(second), StateUpdate::CursorUpdate(cursor), StateUpdate::SelectionsUpdate(_)] =
+            message.updates.as_slice()
+        else {
+            panic!("unexpected updates: {:?}", message.updates);
+        };
+        assert_eq!(first.path, "a.rs");
+        assert_eq!(second.path, "b.rs");
+        assert_eq!(cursor.offset, 2);
+
+        let wire = serde_json::to_value(OutboundMessage::StateUpdate(message)).unwrap();
+        assert_eq!(wire["updates"].as_array().unwrap().len(), 4);
+
+        assert!(batcher.is_empty());
+        assert!(batcher.flush(SupermavenCompletionStateId(2)).is_none());
+    }
+}
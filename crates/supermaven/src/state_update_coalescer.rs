@@ -0,0 +1,414 @@
+use crate::messages::{StateUpdate, StateUpdateMessage};
+#[cfg(feature = "file-deltas")]
+use crate::SupermavenCompletionStateId;
+#[cfg(feature = "file-deltas")]
+use collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long after a state update is sent later ones are held back to be coalesced.
+pub const DEFAULT_COALESCE_WINDOW: Duration = Duration::from_millis(50);
+
+/// Collapses the state updates sent within a short window into a single message, dropping
+/// file and cursor updates that were superseded by later ones for the same path. An update
+/// arriving when nothing was sent for a whole window goes out right away, so only bursts of
+/// updates are delayed.
+///
+/// Coalescing replaces a state's id with that of the state merged into it, so the agent
+/// never sees the earlier id. With the `file-deltas` feature, deltas are therefore only merged
+/// by folding them into a pending full update for their path, and a pending delta for the
+/// same path is sent first instead. Deltas that go out on their own are rebased onto the id
+/// their path's content was last sent with, in case the state they were computed against was
+/// merged away.
+pub struct StateUpdateCoalescer {
+    window: Duration,
+    pending: Option<StateUpdateMessage>,
+    last_sent_at: Option<Instant>,
+    #[cfg(feature = "file-deltas")]
+    sent_with: HashMap<String, SupermavenCompletionStateId>,
+}
+
+impl StateUpdateCoalescer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: None,
+            last_sent_at: None,
+            #[cfg(feature = "file-deltas")]
+            sent_with: HashMap::default(),
+        }
+    }
+
+    /// When the pending updates should be flushed, if there are any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref()?;
+        Some(self.last_sent_at? + self.window)
+    }
+
+    /// Queues `message`, returning a message to send right away: `message` itself when
+    /// nothing was sent within the window, or the pending updates when `message` can't be
+    /// merged into them.
+    pub fn push(
+        &mut self,
+        message: StateUpdateMessage,
+        now: Instant,
+    ) -> Option<StateUpdateMessage> {
+        let Some(pending) = self.pending.as_mut() else {
+            let within_window = self
+                .last_sent_at
+                .map_or(false, |last_sent_at| now < last_sent_at + self.window);
+            if within_window {
+                self.pending = Some(message);
+                return None;
+            }
+            self.last_sent_at = Some(now);
+            return Some(self.finish(message));
+        };
+
+        #[cfg(feature = "file-deltas")]
+        if !can_merge(&pending.updates, &message.updates) {
+            let pending = self.pending.replace(message)?;
+            self.last_sent_at = Some(now);
+            return Some(self.finish(pending));
+        }
+
+        pending.new_id = message.new_id;
+        for update in message.updates {
+            coalesce(&mut pending.updates, update);
+        }
+        None
+    }
+
+    /// Takes the pending message if its window has elapsed.
+    pub fn flush_if_due(&mut self, now: Instant) -> Option<StateUpdateMessage> {
+        if self.deadline()? <= now {
+            self.last_sent_at = Some(now);
+            self.flush()
+        } else {
+            None
+        }
+    }
+
+    /// Takes the pending message regardless of the window.
+    pub fn flush(&mut self) -> Option<StateUpdateMessage> {
+        let pending = self.pending.take()?;
+        Some(self.finish(pending))
+    }
+
+    #[cfg(not(feature = "file-deltas"))]
+    fn finish(&mut self, message: StateUpdateMessage) -> StateUpdateMessage {
+        message
+    }
+
+    /// Rebases the message's deltas onto the states the agent actually received, and records
+    /// which state each of its paths is sent with.
+    #[cfg(feature = "file-deltas")]
+    fn finish(&mut self, mut message: StateUpdateMessage) -> StateUpdateMessage {
+        for update in &mut message.updates {
+            match update {
+                StateUpdate::FileUpdate(file) => {
+                    self.sent_with.insert(file.path.clone(), message.new_id);
+                }
+                StateUpdate::FileDeltaUpdate(delta) => {
+                    if let Some(sent_with) = self.sent_with.get(&delta.path) {
+                        delta.base_state_id = *sent_with;
+                    }
+                    self.sent_with.insert(delta.path.clone(), message.new_id);
+                }
+                StateUpdate::RenameFile { from, to } => {
+                    if let Some(sent_with) = self.sent_with.remove(from.as_str()) {
+                        self.sent_with.insert(to.clone(), sent_with);
+                    }
+                }
+                StateUpdate::DeleteFile { path } => {
+                    self.sent_with.remove(path.as_str());
+                }
+                StateUpdate::CursorUpdate(_) | StateUpdate::SelectionsUpdate(_) => {}
+            }
+        }
+        message
+    }
+}
+
+impl Default for StateUpdateCoalescer {
+    fn default() -> Self {
+        Self::new(DEFAULT_COALESCE_WINDOW)
+    }
+}
+
+/// Whether `updates` can be merged into `pending` without a delta losing its base: every delta
+/// must either fold into a pending full update for its path, or have nothing pending for it.
+#[cfg(feature = "file-deltas")]
+fn can_merge(pending: &[StateUpdate], updates: &[StateUpdate]) -> bool {
+    updates.iter().all(|update| {
+        let StateUpdate::FileDeltaUpdate(delta) = update else {
+            return true;
+        };
+        let last_for_path = pending.iter().rev().find(|existing| match existing {
+            StateUpdate::FileUpdate(existing) => existing.path == delta.path,
+            StateUpdate::FileDeltaUpdate(existing) => existing.path == delta.path,
+            _ => false,
+        });
+        match last_for_path {
+            Some(StateUpdate::FileUpdate(file)) => delta.apply(&file.content).is_ok(),
+            Some(_) => false,
+            None => true,
+        }
+    })
+}
+
+fn coalesce(updates: &mut Vec<StateUpdate>, update: StateUpdate) {
+    match &update {
+        StateUpdate::FileUpdate(file) => {
+            let path = file.path.clone();
+            updates.retain(|existing| match existing {
+                StateUpdate::FileUpdate(existing) => existing.path != path,
+                #[cfg(feature = "file-deltas")]
+                StateUpdate::FileDeltaUpdate(existing) => existing.path != path,
+                _ => true,
+            });
+        }
+        #[cfg(feature = "file-deltas")]
+        StateUpdate::FileDeltaUpdate(delta) => {
+            let last_for_path = updates.iter_mut().rev().find(|existing| match existing {
+                StateUpdate::FileUpdate(existing) => existing.path == delta.path,
+                StateUpdate::FileDeltaUpdate(existing) => existing.path == delta.path,
+                _ => false,
+            });
+            if let Some(StateUpdate::FileUpdate(file)) = last_for_path {
+                if let Ok(content) = delta.apply(&file.content) {
+                    file.content = content;
+                    return;
+                }
+            }
+        }
+        StateUpdate::CursorUpdate(cursor) => {
+            let path = cursor.path.clone();
+            updates.retain(|existing| {
+                !matches!(existing, StateUpdate::CursorUpdate(existing) if existing.path == path)
+            });
+        }
+        StateUpdate::SelectionsUpdate(selections) => {
+            let path = selections.path.clone();
+            updates.retain(|existing| {
+                !matches!(existing, StateUpdate::SelectionsUpdate(existing) if existing.path == path)
+            });
+        }
+        StateUpdate::RenameFile { .. } | StateUpdate::DeleteFile { .. } => {}
+    }
+    updates.push(update);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{messages::*, SupermavenCompletionStateId};
+
+    fn state_update(id: usize, content: &str, offset: usize) -> StateUpdateMessage {
+        StateUpdateMessage {
+            new_id: SupermavenCompletionStateId(id),
+            updates: vec![
+                StateUpdate::FileUpdate(FileUpdateMessage {
+                    path: "a.rs".into(),
+                    content: content.into(),
+                    language: None,
+                }),
+                StateUpdate::CursorUpdate(CursorPositionUpdateMessage {
+                    path: "a.rs".into(),
+                    offset,
+                }),
+            ],
+        }
+    }
+
+    #[cfg(feature = "file-deltas")]
+    fn delta_update(id: usize, base_id: usize, at: usize, text: &str) -> StateUpdateMessage {
+        StateUpdateMessage {
+            new_id: SupermavenCompletionStateId(id),
+            updates: vec![StateUpdate::FileDeltaUpdate(FileDeltaUpdateMessage {
+                path: "a.rs".into(),
+                base_state_id: SupermavenCompletionStateId(base_id),
+                edits: vec![FileEdit {
+                    range: at..at,
+                    replacement: text.into(),
+                }],
+            })],
+        }
+    }
+
+    #[test]
+    fn test_coalesce_rapid_updates() {
+        let start = Instant::now();
+        let mut coalescer = StateUpdateCoalescer::new(Duration::from_millis(50));
+        assert_eq!(coalescer.deadline(), None);
+
+        let message = coalescer.push(state_update(1, "f", 1), start).unwrap();
+        assert_eq!(message.new_id, SupermavenCompletionStateId(1));
+        assert_eq!(coalescer.deadline(), None);
+
+        assert!(coalescer
+            .push(state_update(2, "fn", 2), start + Duration::from_millis(10))
+            .is_none());
+        assert!(coalescer
+            .push(
+                StateUpdateMessage {
+                    new_id: SupermavenCompletionStateId(3),
+                    updates: vec![StateUpdate::FileUpdate(FileUpdateMessage {
+                        path: "a.rs".into(),
+                        content: "fn m".into(),
+                        language: None,
+                    })],
+                },
+                start + Duration::from_millis(20)
+            )
+            .is_none());
+        assert_eq!(
+            coalescer.deadline(),
+            Some(start + Duration::from_millis(50))
+        );
+        assert!(coalescer
+            .flush_if_due(start + Duration::from_millis(49))
+            .is_none());
+
+        let message = coalescer
+            .flush_if_due(start + Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(message.new_id, SupermavenCompletionStateId(3));
+        let [StateUpdate::CursorUpdate(cursor), StateUpdate::FileUpdate(file)] =
+            message.updates.as_slice()
+        else {
+            panic!("unexpected updates: {:?}", message.updates);
+        };
+        assert_eq!(cursor.offset, 2);
+        assert_eq!(file.content, "fn m");
+        assert_eq!(coalescer.deadline(), None);
+        assert!(coalescer.flush().is_none());
+    }
+
+    #[cfg(feature = "file-deltas")]
+    #[test]
+    fn test_coalesce_folds_deltas() {
+        let start = Instant::now();
+        let mut coalescer = StateUpdateCoalescer::new(Duration::from_millis(50));
+        coalescer.push(state_update(1, "f", 1), start);
+        coalescer.push(state_update(2, "fn", 2), start);
+        assert!(coalescer.push(delta_update(3, 2, 2, " m"), start).is_none());
+
+        let message = coalescer.flush().unwrap();
+        assert_eq!(message.new_id, SupermavenCompletionStateId(3));
+        let [StateUpdate::FileUpdate(file), StateUpdate::CursorUpdate(_)] =
+            message.updates.as_slice()
+        else {
+            panic!("unexpected updates: {:?}", message.updates);
+        };
+        assert_eq!(file.content, "fn m");
+    }
+
+    #[test]
+    fn test_update_after_quiet_period_is_sent_immediately() {
+        let start = Instant::now();
+        let mut coalescer = StateUpdateCoalescer::new(Duration::from_millis(50));
+        assert!(coalescer.push(state_update(1, "a", 1), start).is_some());
+        assert!(coalescer
+            .push(state_update(2, "ab", 2), start + Duration::from_millis(50))
+            .is_some());
+        assert_eq!(coalescer.deadline(), None);
+    }
+
+    #[test]
+    fn test_coalesce_keeps_other_paths() {
+        let start = Instant::now();
+        let mut coalescer = StateUpdateCoalescer::default();
+        coalescer.push(state_update(1, "a", 1), start);
+        coalescer.push(state_update(2, "ab", 2), start);
+        coalescer.push(
+            StateUpdateMessage {
+                new_id: SupermavenCompletionStateId(3),
+                updates: vec![StateUpdate::CursorUpdate(CursorPositionUpdateMessage {
+                    path: "b.rs".into(),
+                    offset: 0,
+                })],
+            },
+            start,
+        );
+
+        let message = coalescer.flush().unwrap();
+        assert_eq!(message.updates.len(), 3);
+    }
+
+    #[cfg(feature = "file-deltas")]
+    #[test]
+    fn test_deltas_are_not_coalesced_across_their_base() {
+        let start = Instant::now();
+        let mut coalescer = StateUpdateCoalescer::new(Duration::from_millis(50));
+        coalescer.push(state_update(1, "a", 1), start);
+        assert!(coalescer.push(delta_update(2, 1, 1, "b"), start).is_none());
+
+        // A delta against the pending delta can't be folded, so the pending state is sent
+        // first with its own id.
+        let message = coalescer.push(delta_update(3, 2, 2, "c"), start).unwrap();
+        assert_eq!(message.new_id, SupermavenCompletionStateId(2));
+        let message = coalescer.flush().unwrap();
+        assert_eq!(message.new_id, SupermavenCompletionStateId(3));
+        let [StateUpdate::FileDeltaUpdate(delta)] = message.updates.as_slice() else {
+            panic!("unexpected updates: {:?}", message.updates);
+        };
+        assert_eq!(delta.base_state_id, SupermavenCompletionStateId(2));
+    }
+
+    #[cfg(feature = "file-deltas")]
+    #[test]
+    fn test_invalid_delta_is_not_folded() {
+        let start = Instant::now();
+        let mut coalescer = StateUpdateCoalescer::new(Duration::from_millis(50));
+        coalescer.push(state_update(1, "a", 1), start);
+        assert!(coalescer.push(state_update(2, "ab", 2), start).is_none());
+
+        // The edit is past the end of the pending content, so the pending state is sent as is.
+        let message = coalescer.push(delta_update(3, 2, 9, "c"), start).unwrap();
+        assert_eq!(message.new_id, SupermavenCompletionStateId(2));
+        let StateUpdate::FileUpdate(file) = &message.updates[0] else {
+            panic!("unexpected updates: {:?}", message.updates);
+        };
+        assert_eq!(file.content, "ab");
+        let message = coalescer.flush().unwrap();
+        assert!(matches!(
+            message.updates.as_slice(),
+            [StateUpdate::FileDeltaUpdate(_)]
+        ));
+    }
+
+    #[cfg(feature = "file-deltas")]
+    #[test]
+    fn test_deltas_are_rebased_onto_sent_states() {
+        let start = Instant::now();
+        let mut coalescer = StateUpdateCoalescer::new(Duration::from_millis(50));
+        coalescer.push(state_update(1, "a", 1), start);
+        coalescer.push(state_update(2, "ab", 2), start);
+        coalescer.push(
+            StateUpdateMessage {
+                new_id: SupermavenCompletionStateId(3),
+                updates: vec![StateUpdate::CursorUpdate(CursorPositionUpdateMessage {
+                    path: "a.rs".into(),
+                    offset: 0,
+                })],
+            },
+            start,
+        );
+        let message = coalescer
+            .flush_if_due(start + Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(message.new_id, SupermavenCompletionStateId(3));
+
+        // State 2 was merged into state 3, so a delta computed against it is sent against 3.
+        let message = coalescer
+            .push(
+                delta_update(4, 2, 2, "c"),
+                start + Duration::from_millis(100),
+            )
+            .unwrap();
+        let [StateUpdate::FileDeltaUpdate(delta)] = message.updates.as_slice() else {
+            panic!("unexpected updates: {:?}", message.updates);
+        };
+        assert_eq!(delta.base_state_id, SupermavenCompletionStateId(3));
+    }
+}
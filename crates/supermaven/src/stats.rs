@@ -0,0 +1,142 @@
+use crate::messages::{ResponseItem, SupermavenMessage};
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Upper bounds, in bytes, of the buckets [`MessageSizeHistogram`] sorts messages into.
+/// Messages larger than the last bound are counted in one extra bucket.
+pub const MESSAGE_SIZE_BUCKETS: [usize; 6] =
+    [256, 1024, 4 * 1024, 16 * 1024, 64 * 1024, 256 * 1024];
+
+/// Whether a message was read from the agent's stdout or written to its stdin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageDirection {
+    Inbound,
+    Outbound,
+}
+
+/// Called with the size in bytes of every message exchanged with the agent. See
+/// [`SupermavenStats::on_message_bytes`].
+pub type MessageBytesCallback = dyn Fn(MessageDirection, usize) + Send + Sync;
+
+/// Counts the messages exchanged with the agent, for diagnosing flaky completions.
+#[derive(Default)]
+pub struct SupermavenStats {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    parse_errors: AtomicU64,
+    responses_completed: AtomicU64,
+    inbound_sizes: MessageSizeHistogram,
+    outbound_sizes: MessageSizeHistogram,
+    on_message_bytes: Option<Box<MessageBytesCallback>>,
+}
+
+impl fmt::Debug for SupermavenStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SupermavenStats")
+            .field("messages_sent", &self.messages_sent)
+            .field("messages_received", &self.messages_received)
+            .field("parse_errors", &self.parse_errors)
+            .field("responses_completed", &self.responses_completed)
+            .field("inbound_sizes", &self.inbound_sizes)
+            .field("outbound_sizes", &self.outbound_sizes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SupermavenStats {
+    /// Calls `callback` with the size of every message sent or received, e.g. to feed a
+    /// telemetry pipeline. Without a callback, recording a size only updates the histograms
+    /// returned by [`Self::message_sizes`].
+    pub fn on_message_bytes(
+        mut self,
+        callback: impl Fn(MessageDirection, usize) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_message_bytes = Some(Box::new(callback));
+        self
+    }
+
+    pub fn messages_sent(&self) -> u64 {
+        self.messages_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn messages_received(&self) -> u64 {
+        self.messages_received.load(Ordering::Relaxed)
+    }
+
+    pub fn parse_errors(&self) -> u64 {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    /// How many responses ended with an `End` item.
+    pub fn responses_completed(&self) -> u64 {
+        self.responses_completed.load(Ordering::Relaxed)
+    }
+
+    /// The sizes of the messages exchanged in `direction`, in bytes of JSON.
+    pub fn message_sizes(&self, direction: MessageDirection) -> &MessageSizeHistogram {
+        match direction {
+            MessageDirection::Inbound => &self.inbound_sizes,
+            MessageDirection::Outbound => &self.outbound_sizes,
+        }
+    }
+
+    pub fn record_message_bytes(&self, direction: MessageDirection, len: usize) {
+        self.message_sizes(direction).record(len);
+        if let Some(on_message_bytes) = &self.on_message_bytes {
+            on_message_bytes(direction, len);
+        }
+    }
+
+    pub fn record_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, message: &anyhow::Result<SupermavenMessage>) {
+        match message {
+            Ok(message) => {
+                self.messages_received.fetch_add(1, Ordering::Relaxed);
+                if let SupermavenMessage::Response(response) = message {
+                    if response
+                        .items
+                        .iter()
+                        .any(|item| matches!(item, ResponseItem::End))
+                    {
+                        self.responses_completed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            Err(_) => {
+                self.parse_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Counts messages by size, in the buckets bounded by [`MESSAGE_SIZE_BUCKETS`].
+#[derive(Debug, Default)]
+pub struct MessageSizeHistogram {
+    counts: [AtomicU64; MESSAGE_SIZE_BUCKETS.len() + 1],
+}
+
+impl MessageSizeHistogram {
+    pub fn record(&self, len: usize) {
+        let bucket = MESSAGE_SIZE_BUCKETS
+            .iter()
+            .position(|bound| len <= *bound)
+            .unwrap_or(MESSAGE_SIZE_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of messages in each bucket. Bucket `i` counts messages of at most
+    /// `MESSAGE_SIZE_BUCKETS[i]` bytes that don't fit an earlier bucket, and the last bucket
+    /// counts everything larger.
+    pub fn counts(&self) -> [u64; MESSAGE_SIZE_BUCKETS.len() + 1] {
+        std::array::from_fn(|bucket| self.counts[bucket].load(Ordering::Relaxed))
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts().iter().sum()
+    }
+}
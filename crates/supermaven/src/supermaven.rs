@@ -1,14 +1,41 @@
-mod messages;
+mod active_repo_state;
+mod apology_state;
+mod completion_builder;
+mod completion_diff;
+mod dust_filter;
+mod file_update_encoder;
+pub mod messages;
+#[cfg(test)]
+mod mock_agent;
+mod protocol;
+mod state_manager;
+mod state_update_batcher;
+mod state_update_coalescer;
+mod stats;
 mod supermaven_completion_provider;
-
+mod supermaven_event;
+#[cfg(test)]
+mod test_logger;
+
+pub use active_repo_state::*;
+pub use apology_state::*;
+pub use completion_builder::*;
+pub use completion_diff::*;
+pub use dust_filter::*;
+pub use file_update_encoder::*;
+pub use protocol::*;
+pub use state_manager::*;
+pub use state_update_batcher::*;
+pub use state_update_coalescer::*;
+pub use stats::*;
 pub use supermaven_completion_provider::*;
+pub use supermaven_event::*;
 
 use anyhow::{Context as _, Result};
 #[allow(unused_imports)]
 use client::{proto, Client};
-use collections::BTreeMap;
 
-use futures::{channel::mpsc, io::BufReader, AsyncBufReadExt, StreamExt};
+use futures::{channel::mpsc, io::BufReader, AsyncRead, AsyncWrite, StreamExt};
 use gpui::{AppContext, AsyncAppContext, EntityId, Global, Model, ModelContext, Task, WeakModel};
 use language::{language_settings::all_language_settings, Anchor, Buffer, ToOffset};
 use messages::*;
@@ -16,32 +43,63 @@ use postage::watch;
 use serde::{Deserialize, Serialize};
 use settings::SettingsStore;
 use smol::{
-    io::AsyncWriteExt,
-    process::{Child, ChildStdin, ChildStdout, Command},
+    future,
+    process::{Child, Command},
+    Timer,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    num::ParseIntError,
+    ops::Range,
+    path::PathBuf,
+    pin::pin,
+    process::Stdio,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use std::{ops::Range, path::PathBuf, process::Stdio, sync::Arc};
 use ui::prelude::*;
 use util::ResultExt;
 
 pub fn init(client: Arc<Client>, cx: &mut AppContext) {
+    init_with_stats(client, SupermavenStats::default, cx);
+}
+
+/// Like [`init`], but each launch of the agent counts its messages in a fresh `new_stats()`,
+/// e.g. one that forwards message sizes to telemetry with [`SupermavenStats::on_message_bytes`].
+pub fn init_with_stats(
+    client: Arc<Client>,
+    new_stats: impl Fn() -> SupermavenStats + 'static,
+    cx: &mut AppContext,
+) {
     let supermaven = cx.new_model(|_| Supermaven::Starting);
     Supermaven::set_global(supermaven.clone(), cx);
 
     let mut provider = all_language_settings(None, cx).inline_completions.provider;
     if provider == language::language_settings::InlineCompletionProvider::Supermaven {
-        supermaven.update(cx, |supermaven, cx| supermaven.start(client.clone(), cx));
+        supermaven.update(cx, |supermaven, cx| {
+            supermaven.start(client.clone(), new_stats(), cx)
+        });
     }
+    let mut enabled = all_language_settings(None, cx).inline_completions_enabled(None, None);
 
     cx.observe_global::<SettingsStore>(move |cx| {
-        let new_provider = all_language_settings(None, cx).inline_completions.provider;
+        let settings = all_language_settings(None, cx);
+        let new_provider = settings.inline_completions.provider;
+        let new_enabled = settings.inline_completions_enabled(None, None);
         if new_provider != provider {
             provider = new_provider;
             if provider == language::language_settings::InlineCompletionProvider::Supermaven {
-                supermaven.update(cx, |supermaven, cx| supermaven.start(client.clone(), cx));
+                supermaven.update(cx, |supermaven, cx| {
+                    supermaven.start(client.clone(), new_stats(), cx)
+                });
             } else {
                 supermaven.update(cx, |supermaven, _cx| supermaven.stop());
             }
+        } else if new_enabled != enabled {
+            supermaven.update(cx, |supermaven, _cx| supermaven.reset());
         }
+        enabled = new_enabled;
     })
     .detach();
 }
@@ -60,6 +118,28 @@ pub enum AccountStatus {
     Ready,
 }
 
+impl AccountStatus {
+    /// The URL the user needs to visit to activate Supermaven, while activation is pending.
+    pub fn activate_url(&self) -> Option<&str> {
+        match self {
+            Self::NeedsActivation { activate_url } => Some(activate_url),
+            Self::Unknown | Self::Ready => None,
+        }
+    }
+
+    fn handle_activation_request(&mut self, request: ActivationRequest) {
+        *self = match request.activate_url {
+            Some(activate_url) => Self::NeedsActivation { activate_url },
+            None => Self::Ready,
+        };
+    }
+
+    /// Marks the account as activated. Late or repeated successes leave it ready.
+    fn handle_activation_success(&mut self) {
+        *self = Self::Ready;
+    }
+}
+
 #[derive(Clone)]
 struct SupermavenGlobal(Model<Supermaven>);
 
@@ -75,16 +155,30 @@ impl Supermaven {
         cx.set_global(SupermavenGlobal(supermaven));
     }
 
-    pub fn start(&mut self, client: Arc<Client>, cx: &mut ModelContext<Self>) {
+    /// Downloads and launches the agent, counting the messages exchanged with it in `stats`.
+    pub fn start(
+        &mut self,
+        client: Arc<Client>,
+        stats: SupermavenStats,
+        cx: &mut ModelContext<Self>,
+    ) {
         if let Self::Starting = self {
             cx.spawn(|this, mut cx| async move {
                 let binary_path =
                     supermaven_api::get_supermaven_agent_path(client.http_client()).await?;
+                let agent_version = supermaven_api::installed_agent_version(&binary_path).await;
+                if let Some(agent_version) = agent_version {
+                    if let Err(error) = supermaven_api::check_supported_version(agent_version) {
+                        log::warn!("{error}");
+                    }
+                }
 
                 this.update(&mut cx, |this, cx| {
                     if let Self::Starting = this {
-                        *this =
-                            Self::Spawned(SupermavenAgent::new(binary_path, client.clone(), cx)?);
+                        let mut agent =
+                            SupermavenAgent::new(binary_path, client.clone(), stats, cx)?;
+                        agent.agent_version = agent_version;
+                        *this = Self::Spawned(agent);
                     }
                     anyhow::Ok(())
                 })
@@ -97,6 +191,16 @@ impl Supermaven {
         *self = Self::Starting;
     }
 
+    /// Discards the completion states and file contents sent to the agent, e.g. when a
+    /// different project is opened or completions are turned off and on. The protocol has no reset message, so the next state
+    /// simply resends each file in full, and responses for earlier states are ignored.
+    pub fn reset(&mut self) {
+        if let Self::Spawned(agent) = self {
+            agent.states.clear();
+            agent.file_updates.clear();
+        }
+    }
+
     pub fn is_enabled(&self) -> bool {
         matches!(self, Self::Spawned { .. })
     }
@@ -117,9 +221,11 @@ impl Supermaven {
                 .to_string_lossy()
                 .to_string();
             let content = buffer.text();
-            let offset = cursor_position.to_offset(buffer);
-            let state_id = agent.next_state_id;
-            agent.next_state_id.0 += 1;
+            let offset = CursorPositionUpdateMessage::clamp_to_boundary(
+                &content,
+                cursor_position.to_offset(buffer),
+            );
+            let state_id = agent.state_ids.next();
 
             let (updates_tx, mut updates_rx) = watch::channel();
             postage::stream::Stream::try_recv(&mut updates_rx).unwrap();
@@ -129,23 +235,31 @@ impl Supermaven {
                 SupermavenCompletionState {
                     buffer_id,
                     range: cursor_position.bias_left(buffer)..cursor_position.bias_right(buffer),
-                    completion: Vec::new(),
-                    text: String::new(),
                     updates_tx,
                 },
             );
-            let _ = agent
-                .outgoing_tx
-                .unbounded_send(OutboundMessage::StateUpdate(StateUpdateMessage {
-                    new_id: state_id.0.to_string(),
-                    updates: vec![
-                        StateUpdate::FileUpdate(FileUpdateMessage {
-                            path: path.clone(),
-                            content,
-                        }),
-                        StateUpdate::CursorUpdate(CursorPositionUpdateMessage { path, offset }),
-                    ],
-                }));
+            let mut batcher = StateUpdateBatcher::new();
+            batcher.push(
+                agent.file_updates.encode_current(
+                    state_id,
+                    path.clone(),
+                    content,
+                    buffer
+                        .language()
+                        .map(|language| language.name().to_string()),
+                ),
+            );
+            batcher.push(StateUpdate::CursorUpdate(CursorPositionUpdateMessage {
+                path,
+                offset,
+            }));
+            if let Some(message) = batcher.flush(state_id) {
+                let _ = agent
+                    .outgoing_tx
+                    .unbounded_send(OutboundMessage::StateUpdate(message));
+                // The agent only completes the latest state it was sent.
+                agent.states.cancel_older_than(state_id);
+            }
 
             Some(SupermavenCompletion {
                 id: state_id,
@@ -156,12 +270,75 @@ impl Supermaven {
         }
     }
 
+    /// The reason the agent recently gave for being unable to provide completions, if it
+    /// apologized. `Some("")` means it apologized without saying why.
+    pub fn apology(&self) -> Option<&str> {
+        if let Self::Spawned(agent) = self {
+            let now = Instant::now();
+            if agent.apology.is_apologizing(now) {
+                return Some(agent.apology.message(now).unwrap_or_default());
+            }
+        }
+        None
+    }
+
+    /// Notifies with the name of the repository the agent is working in whenever it changes.
+    pub fn active_repo(&self) -> Option<watch::Receiver<Option<String>>> {
+        if let Self::Spawned(agent) = self {
+            Some(agent.active_repo.subscribe())
+        } else {
+            None
+        }
+    }
+
+    /// Counters for the messages exchanged with the agent, if it is running.
+    pub fn stats(&self) -> Option<&SupermavenStats> {
+        if let Self::Spawned(agent) = self {
+            Some(&agent.stats)
+        } else {
+            None
+        }
+    }
+
+    /// The version of the running agent, as recorded when it was downloaded.
+    pub fn agent_version(&self) -> Option<u64> {
+        if let Self::Spawned(agent) = self {
+            agent.agent_version
+        } else {
+            None
+        }
+    }
+
+    /// Whether the agent's latest metadata marks `completion` as dust that shouldn't be shown.
+    pub fn is_dust(&self, completion: &str) -> bool {
+        if let Self::Spawned(agent) = self {
+            agent.dust_filter.is_dust(completion)
+        } else {
+            false
+        }
+    }
+
     pub fn completion(
         &self,
         id: SupermavenCompletionStateId,
-    ) -> Option<&SupermavenCompletionState> {
+    ) -> Option<&StateEntry<SupermavenCompletionState>> {
         if let Self::Spawned(agent) = self {
-            agent.states.get(&id)
+            agent.states.get(id)
+        } else {
+            None
+        }
+    }
+
+    /// The unfinished completion for `id`, if the agent exited while streaming it.
+    pub fn partial_completion(
+        &self,
+        id: SupermavenCompletionStateId,
+    ) -> Option<&PartialCompletion> {
+        if let Self::Spawned(agent) = self {
+            agent
+                .partial_completion
+                .as_ref()
+                .filter(|partial| partial.id == id)
         } else {
             None
         }
@@ -169,22 +346,29 @@ impl Supermaven {
 }
 
 pub struct SupermavenAgent {
-    _process: Child,
-    next_state_id: SupermavenCompletionStateId,
-    states: BTreeMap<SupermavenCompletionStateId, SupermavenCompletionState>,
+    _process: Option<Child>,
+    state_ids: StateIdGenerator,
+    states: StateManager<SupermavenCompletionState>,
+    file_updates: FileUpdateEncoder,
+    dust_filter: DustFilter,
+    apology: ApologyState,
+    active_repo: ActiveRepoState,
+    stats: Arc<SupermavenStats>,
     outgoing_tx: mpsc::UnboundedSender<OutboundMessage>,
     _handle_outgoing_messages: Task<Result<()>>,
     _handle_incoming_messages: Task<Result<()>>,
+    _expire_timed_out_states: Task<()>,
     pub account_status: AccountStatus,
     service_tier: Option<ServiceTier>,
-    #[allow(dead_code)]
-    client: Arc<Client>,
+    agent_version: Option<u64>,
+    partial_completion: Option<PartialCompletion>,
 }
 
 impl SupermavenAgent {
     fn new(
         binary_path: PathBuf,
         client: Arc<Client>,
+        stats: SupermavenStats,
         cx: &mut ModelContext<Supermaven>,
     ) -> Result<Self> {
         let mut process = Command::new(&binary_path)
@@ -205,11 +389,10 @@ impl SupermavenAgent {
             .take()
             .context("failed to get stdout for process")?;
 
-        let (outgoing_tx, outgoing_rx) = mpsc::unbounded();
+        let agent = Self::with_io(Some(process), stdin, stdout, stats, cx);
 
         cx.spawn({
-            let client = client.clone();
-            let outgoing_tx = outgoing_tx.clone();
+            let outgoing_tx = agent.outgoing_tx.clone();
             move |this, mut cx| async move {
                 let mut status = client.status();
                 while let Some(status) = status.next().await {
@@ -232,55 +415,145 @@ impl SupermavenAgent {
         })
         .detach();
 
-        Ok(Self {
+        Ok(agent)
+    }
+
+    /// Talks to an agent over the given pipes. `process` is kept alive as long as the agent.
+    fn with_io(
+        process: Option<Child>,
+        stdin: impl AsyncWrite + Unpin + 'static,
+        stdout: impl AsyncRead + Unpin + 'static,
+        stats: SupermavenStats,
+        cx: &mut ModelContext<Supermaven>,
+    ) -> Self {
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded();
+        let stats = Arc::new(stats);
+        Self {
             _process: process,
-            next_state_id: SupermavenCompletionStateId::default(),
-            states: BTreeMap::default(),
+            state_ids: StateIdGenerator::new(),
+            states: StateManager::new()
+                .with_timeout(COMPLETION_TIMEOUT, TimeoutPolicy::KeepPartial),
+            file_updates: FileUpdateEncoder::new(),
+            dust_filter: DustFilter::default(),
+            apology: ApologyState::default(),
+            active_repo: ActiveRepoState::new(),
             outgoing_tx,
-            _handle_outgoing_messages: cx
-                .spawn(|_, _cx| Self::handle_outgoing_messages(outgoing_rx, stdin)),
-            _handle_incoming_messages: cx
-                .spawn(|this, cx| Self::handle_incoming_messages(this, stdout, cx)),
+            _handle_outgoing_messages: cx.spawn({
+                let stats = stats.clone();
+                |_, _cx| Self::handle_outgoing_messages(outgoing_rx, stdin, stats)
+            }),
+            _handle_incoming_messages: cx.spawn({
+                let stats = stats.clone();
+                |this, cx| Self::handle_incoming_messages(this, stdout, stats, cx)
+            }),
+            _expire_timed_out_states: cx.spawn(|this, mut cx| async move {
+                loop {
+                    cx.background_executor().timer(TIMEOUT_CHECK_INTERVAL).await;
+                    let result = this.update(&mut cx, |this, _cx| {
+                        if let Supermaven::Spawned(this) = this {
+                            this.expire_timed_out_states(Instant::now());
+                        }
+                    });
+                    if result.is_err() {
+                        break;
+                    }
+                }
+            }),
+            stats,
             account_status: AccountStatus::Unknown,
             service_tier: None,
-            client,
-        })
+            agent_version: None,
+            partial_completion: None,
+        }
     }
 
     async fn handle_outgoing_messages(
         mut outgoing: mpsc::UnboundedReceiver<OutboundMessage>,
-        mut stdin: ChildStdin,
+        mut stdin: impl AsyncWrite + Unpin,
+        stats: Arc<SupermavenStats>,
     ) -> Result<()> {
-        while let Some(message) = outgoing.next().await {
-            let bytes = serde_json::to_vec(&message)?;
-            stdin.write_all(&bytes).await?;
-            stdin.write_all(&[b'\n']).await?;
+        let mut coalescer = StateUpdateCoalescer::default();
+        let mut sent_state_ids = SentStateIds::default();
+        loop {
+            let message = match coalescer.deadline() {
+                Some(deadline) => {
+                    future::or(async { Some(outgoing.next().await) }, async {
+                        Timer::at(deadline).await;
+                        None
+                    })
+                    .await
+                }
+                None => Some(outgoing.next().await),
+            };
+
+            match message {
+                Some(Some(OutboundMessage::StateUpdate(message))) => {
+                    if let Some(message) = coalescer.push(message, Instant::now()) {
+                        let message = OutboundMessage::StateUpdate(message);
+                        check_state_id(&mut sent_state_ids, &message);
+                        write_message(&mut stdin, &message, &stats).await?;
+                    }
+                }
+                Some(Some(message)) => {
+                    if let Some(pending) = coalescer.flush() {
+                        let pending = OutboundMessage::StateUpdate(pending);
+                        check_state_id(&mut sent_state_ids, &pending);
+                        write_message(&mut stdin, &pending, &stats).await?;
+                    }
+                    write_message(&mut stdin, &message, &stats).await?;
+                }
+                Some(None) => break,
+                None => {
+                    if let Some(pending) = coalescer.flush_if_due(Instant::now()) {
+                        let pending = OutboundMessage::StateUpdate(pending);
+                        check_state_id(&mut sent_state_ids, &pending);
+                        write_message(&mut stdin, &pending, &stats).await?;
+                    }
+                }
+            }
+        }
+
+        if let Some(pending) = coalescer.flush() {
+            let pending = OutboundMessage::StateUpdate(pending);
+            check_state_id(&mut sent_state_ids, &pending);
+            write_message(&mut stdin, &pending, &stats).await?;
         }
         Ok(())
     }
 
     async fn handle_incoming_messages(
         this: WeakModel<Supermaven>,
-        stdout: ChildStdout,
+        stdout: impl AsyncRead + Unpin,
+        stats: Arc<SupermavenStats>,
         mut cx: AsyncAppContext,
     ) -> Result<()> {
-        const MESSAGE_PREFIX: &str = "SM-MESSAGE ";
-
-        let stdout = BufReader::new(stdout);
-        let mut lines = stdout.lines();
-        while let Some(line) = lines.next().await {
-            let Some(line) = line.context("failed to read line from stdout").log_err() else {
-                continue;
+        let mut messages = pin!(detect_agent_exit(parse_messages_with_stats(
+            BufReader::new(stdout),
+            DEFAULT_MAX_LINE_LENGTH,
+            stats
+        )));
+        while let Some(message) = messages.next().await {
+            let message = match message {
+                Err(error) if error.is::<AgentExited>() => {
+                    log::error!("{error}");
+                    let exited = error.downcast::<AgentExited>()?;
+                    this.update(&mut cx, |this, _cx| {
+                        if let Supermaven::Spawned(this) = this {
+                            this.keep_partial_completion(&exited);
+                        }
+                    })?;
+                    return Err(exited.into());
+                }
+                // The decoder has already logged the error.
+                Err(_) => continue,
+                Ok(message) => message,
             };
-            let Some(line) = line.strip_prefix(MESSAGE_PREFIX) else {
+            let Some(message) = message.flatten_passthrough().log_err() else {
                 continue;
             };
-            let Some(message) = serde_json::from_str::<SupermavenMessage>(&line)
-                .with_context(|| format!("failed to deserialize line from stdout: {:?}", line))
-                .log_err()
-            else {
+            if let SupermavenMessage::Unknown = message {
                 continue;
-            };
+            }
 
             this.update(&mut cx, |this, _cx| {
                 if let Supermaven::Spawned(this) = this {
@@ -294,48 +567,157 @@ impl SupermavenAgent {
         Ok(())
     }
 
+    /// Finalizes the states the agent didn't finish within [`COMPLETION_TIMEOUT`], notifying
+    /// their completions so that they stop waiting and show what arrived so far.
+    fn expire_timed_out_states(&mut self, now: Instant) {
+        for id in self.states.expire_timed_out(now) {
+            if let Some(state) = self.states.get_mut(id) {
+                *state.data.updates_tx.borrow_mut() = ();
+            }
+        }
+    }
+
+    /// Keeps what the agent streamed for the state it was cut off in, so that it can still be
+    /// offered as a [`PartialCompletion`], and notifies that state's completion.
+    fn keep_partial_completion(&mut self, exited: &AgentExited) {
+        let Some(state_id) = exited
+            .state_id
+            .parse::<SupermavenCompletionStateId>()
+            .log_err()
+        else {
+            return;
+        };
+        let Some(state) = self.states.get_mut(state_id) else {
+            return;
+        };
+        let range = state.data.range.clone();
+        *state.data.updates_tx.borrow_mut() = ();
+        if let Some(text) = self.states.take_partial(state_id) {
+            self.partial_completion = Some(PartialCompletion {
+                id: state_id,
+                range,
+                text,
+            });
+        }
+    }
+
+    /// Handles a message from the agent. Passthrough layers have already been unwrapped by
+    /// [`Self::handle_incoming_messages`].
     fn handle_message(&mut self, message: SupermavenMessage) {
         match message {
             SupermavenMessage::ActivationRequest(request) => {
-                self.account_status = match request.activate_url {
-                    Some(activate_url) => AccountStatus::NeedsActivation {
-                        activate_url: activate_url.clone(),
-                    },
-                    None => AccountStatus::Ready,
-                };
+                self.account_status.handle_activation_request(request);
+            }
+            SupermavenMessage::ActivationSuccess => {
+                self.account_status.handle_activation_success();
             }
             SupermavenMessage::ServiceTier { service_tier } => {
                 self.service_tier = Some(service_tier);
             }
             SupermavenMessage::Response(response) => {
-                let state_id = SupermavenCompletionStateId(response.state_id.parse().unwrap());
-                if let Some(state) = self.states.get_mut(&state_id) {
-                    for item in &response.items {
-                        if let ResponseItem::Text { text } = item {
-                            state.text.push_str(text);
-                        }
-                    }
-                    state.completion.extend(response.items);
-                    *state.updates_tx.borrow_mut() = ();
+                let Some(state_id) = response
+                    .state_id
+                    .parse::<SupermavenCompletionStateId>()
+                    .log_err()
+                else {
+                    return;
+                };
+                self.apology.clear();
+                if let Some(state) = self.states.push_response(state_id, &response.items) {
+                    *state.data.updates_tx.borrow_mut() = ();
                 }
             }
-            SupermavenMessage::Passthrough { passthrough } => self.handle_message(*passthrough),
+            SupermavenMessage::ActiveRepo(repo) => {
+                self.active_repo.update(repo.repo_simple_name);
+            }
+            SupermavenMessage::Apology { message } => {
+                self.apology.record(message, Instant::now());
+            }
+            SupermavenMessage::Metadata(metadata) => {
+                self.dust_filter = DustFilter::from_metadata(&metadata);
+            }
+            SupermavenMessage::ConfigUpdate(config) => {
+                log::debug!(
+                    "received supermaven config update: {:?}",
+                    config.extra.keys().collect::<Vec<_>>()
+                );
+            }
             _ => {
-                log::warn!("unhandled message: {:?}", message);
+                log::warn!("unhandled supermaven {} message", message.kind());
             }
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
-pub struct SupermavenCompletionStateId(usize);
+/// How long the agent has to finish a completion, after which the text it sent so far is kept
+/// as the final completion.
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often states are checked against [`COMPLETION_TIMEOUT`].
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reusing a state id is a bug in how ids are allocated, so it fails loudly in debug builds.
+/// Release builds log it and send the message anyway.
+fn check_state_id(sent_state_ids: &mut SentStateIds, message: &OutboundMessage) {
+    if let Err(error) = sent_state_ids.record(message) {
+        util::debug_panic!("{error}");
+    }
+}
+
+/// Identifies a state sent to the agent. Ids are allocated in increasing order, so a larger id
+/// always refers to a newer state. On the wire the id is sent as a string.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct SupermavenCompletionStateId(pub usize);
+
+impl std::fmt::Display for SupermavenCompletionStateId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for SupermavenCompletionStateId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+impl Serialize for SupermavenCompletionStateId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SupermavenCompletionStateId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        id.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Hands out the ids of new states. Clones share the same counter, so ids from any of them are
+/// unique and strictly increasing, matching the ordering [`SupermavenCompletionStateId`]
+/// relies on.
+#[derive(Clone, Debug, Default)]
+pub struct StateIdGenerator {
+    next_id: Arc<AtomicUsize>,
+}
+
+impl StateIdGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn next(&self) -> SupermavenCompletionStateId {
+        SupermavenCompletionStateId(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+}
 
 #[allow(dead_code)]
 pub struct SupermavenCompletionState {
     buffer_id: EntityId,
     range: Range<Anchor>,
-    completion: Vec<ResponseItem>,
-    text: String,
     updates_tx: watch::Sender<()>,
 }
 
@@ -343,3 +725,243 @@ pub struct SupermavenCompletion {
     pub id: SupermavenCompletionStateId,
     pub updates: watch::Receiver<()>,
 }
+
+/// The text the agent streamed for a state before it exited without finishing it.
+pub struct PartialCompletion {
+    pub id: SupermavenCompletionStateId,
+    pub range: Range<Anchor>,
+    pub text: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_agent::MockAgent;
+    use gpui::TestAppContext;
+
+    #[test]
+    fn test_state_id_round_trip() {
+        let id = SupermavenCompletionStateId(42);
+        assert_eq!(id.to_string(), "42");
+        assert_eq!("42".parse::<SupermavenCompletionStateId>().unwrap(), id);
+        assert!("abc".parse::<SupermavenCompletionStateId>().is_err());
+
+        assert_eq!(serde_json::to_string(&id).unwrap(), r#""42""#);
+        assert_eq!(
+            serde_json::from_str::<SupermavenCompletionStateId>(r#""42""#).unwrap(),
+            id
+        );
+        assert!(SupermavenCompletionStateId(2) > SupermavenCompletionStateId(1));
+    }
+
+    #[test]
+    fn test_state_id_generator() {
+        let state_ids = StateIdGenerator::new();
+        assert_eq!(state_ids.next(), SupermavenCompletionStateId(0));
+        assert_eq!(state_ids.clone().next(), SupermavenCompletionStateId(1));
+
+        let threads = (0..8)
+            .map(|_| {
+                let state_ids = state_ids.clone();
+                std::thread::spawn(move || (0..1000).map(|_| state_ids.next()).collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+        let mut all_ids = Vec::new();
+        for thread in threads {
+            let ids = thread.join().unwrap();
+            assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+            all_ids.extend(ids);
+        }
+
+        all_ids.sort();
+        all_ids.dedup();
+        assert_eq!(all_ids.len(), 8000);
+        assert_eq!(state_ids.next(), SupermavenCompletionStateId(8002));
+    }
+
+    #[test]
+    fn test_activation_flow() {
+        let mut status = AccountStatus::Unknown;
+        assert_eq!(status.activate_url(), None);
+
+        status.handle_activation_request(ActivationRequest {
+            activate_url: Some("https://supermaven.com/activate".into()),
+        });
+        assert_eq!(
+            status.activate_url(),
+            Some("https://supermaven.com/activate")
+        );
+
+        status.handle_activation_success();
+        assert!(matches!(status, AccountStatus::Ready));
+        assert_eq!(status.activate_url(), None);
+
+        status.handle_activation_success();
+        assert!(matches!(status, AccountStatus::Ready));
+
+        let mut status = AccountStatus::Unknown;
+        status.handle_activation_request(ActivationRequest { activate_url: None });
+        assert!(matches!(status, AccountStatus::Ready));
+    }
+
+    #[gpui::test]
+    fn test_timed_out_states_are_notified(cx: &mut TestAppContext) {
+        let (_agent, connection) = MockAgent::new();
+        let supermaven = cx.new_model(|cx| {
+            Supermaven::Spawned(SupermavenAgent::with_io(
+                None,
+                connection.stdin,
+                connection.stdout,
+                SupermavenStats::default(),
+                cx,
+            ))
+        });
+        let buffer = cx.new_model(|cx| Buffer::local("fn main() {}", cx));
+        let mut completion = supermaven
+            .update(cx, |supermaven, cx| {
+                supermaven.complete(&buffer, Anchor::MIN, cx)
+            })
+            .unwrap();
+        assert!(postage::stream::Stream::try_recv(&mut completion.updates).is_err());
+
+        supermaven.update(cx, |supermaven, _cx| {
+            if let Supermaven::Spawned(agent) = supermaven {
+                agent.expire_timed_out_states(Instant::now() + COMPLETION_TIMEOUT);
+            }
+        });
+        assert!(postage::stream::Stream::try_recv(&mut completion.updates).is_ok());
+        supermaven.read_with(cx, |supermaven, _cx| {
+            assert!(supermaven.completion(completion.id).unwrap().is_timed_out());
+        });
+    }
+
+    #[gpui::test]
+    fn test_reset_discards_pre_reset_responses(cx: &mut TestAppContext) {
+        let (_agent, connection) = MockAgent::new();
+        let supermaven = cx.new_model(|cx| {
+            Supermaven::Spawned(SupermavenAgent::with_io(
+                None,
+                connection.stdin,
+                connection.stdout,
+                SupermavenStats::default(),
+                cx,
+            ))
+        });
+        let buffer = cx.new_model(|cx| Buffer::local("fn main() {}", cx));
+        let complete = |cx: &mut TestAppContext| {
+            supermaven
+                .update(cx, |supermaven, cx| {
+                    supermaven.complete(&buffer, Anchor::MIN, cx)
+                })
+                .unwrap()
+        };
+        let respond = |id: SupermavenCompletionStateId, cx: &mut TestAppContext| {
+            supermaven.update(cx, |supermaven, _cx| {
+                if let Supermaven::Spawned(agent) = supermaven {
+                    agent.handle_message(SupermavenMessage::Response(SupermavenResponse {
+                        state_id: id.to_string(),
+                        items: vec![
+                            ResponseItem::Text {
+                                text: "println!()".into(),
+                            },
+                            ResponseItem::End,
+                        ],
+                    }));
+                }
+            })
+        };
+
+        let mut old_completion = complete(cx);
+        supermaven.update(cx, |supermaven, _cx| supermaven.reset());
+        respond(old_completion.id, cx);
+        assert!(postage::stream::Stream::try_recv(&mut old_completion.updates).is_err());
+        supermaven.read_with(cx, |supermaven, _cx| {
+            assert!(supermaven.completion(old_completion.id).is_none());
+        });
+
+        let mut new_completion = complete(cx);
+        assert!(new_completion.id > old_completion.id);
+        respond(new_completion.id, cx);
+        assert!(postage::stream::Stream::try_recv(&mut new_completion.updates).is_ok());
+        supermaven.read_with(cx, |supermaven, _cx| {
+            assert!(supermaven
+                .completion(new_completion.id)
+                .unwrap()
+                .is_complete());
+        });
+    }
+
+    #[gpui::test]
+    fn test_stats_callback_sees_agent_messages(cx: &mut TestAppContext) {
+        let (_agent, connection) = MockAgent::new();
+        let sizes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let stats = SupermavenStats::default().on_message_bytes({
+            let sizes = sizes.clone();
+            move |direction, len| sizes.lock().unwrap().push((direction, len))
+        });
+        let supermaven = cx.new_model(|cx| {
+            Supermaven::Spawned(SupermavenAgent::with_io(
+                None,
+                connection.stdin,
+                connection.stdout,
+                stats,
+                cx,
+            ))
+        });
+
+        supermaven.update(cx, |supermaven, _cx| {
+            if let Supermaven::Spawned(agent) = supermaven {
+                agent
+                    .outgoing_tx
+                    .unbounded_send(OutboundMessage::UseFreeVersion)
+                    .unwrap();
+            }
+        });
+        cx.run_until_parked();
+
+        let len = serde_json::to_vec(&OutboundMessage::UseFreeVersion)
+            .unwrap()
+            .len();
+        assert_eq!(*sizes.lock().unwrap(), [(MessageDirection::Outbound, len)]);
+    }
+
+    #[gpui::test]
+    fn test_partial_completion_survives_agent_exit(cx: &mut TestAppContext) {
+        let response = SupermavenMessage::Response(SupermavenResponse {
+            state_id: "0".into(),
+            items: vec![ResponseItem::Text {
+                text: "println!(".into(),
+            }],
+        });
+        let stdout = format!(
+            "{MESSAGE_PREFIX}{}\n",
+            serde_json::to_string(&response).unwrap()
+        );
+        let supermaven = cx.new_model(|cx| {
+            Supermaven::Spawned(SupermavenAgent::with_io(
+                None,
+                futures::io::sink(),
+                futures::io::Cursor::new(stdout.into_bytes()),
+                SupermavenStats::default(),
+                cx,
+            ))
+        });
+        let buffer = cx.new_model(|cx| Buffer::local("fn main() {}", cx));
+        let completion = supermaven
+            .update(cx, |supermaven, cx| {
+                supermaven.complete(&buffer, Anchor::MIN, cx)
+            })
+            .unwrap();
+        assert_eq!(completion.id, SupermavenCompletionStateId(0));
+
+        cx.run_until_parked();
+        supermaven.read_with(cx, |supermaven, _cx| {
+            assert!(supermaven.completion(completion.id).is_none());
+            let partial = supermaven.partial_completion(completion.id).unwrap();
+            assert_eq!(partial.text, "println!(");
+            assert!(supermaven
+                .partial_completion(SupermavenCompletionStateId(1))
+                .is_none());
+        });
+    }
+}
@@ -95,25 +95,35 @@ impl InlineCompletionProvider for SupermavenCompletionProvider {
         let completion_id = self.completion_id?;
         let buffer = buffer.read(cx);
         let cursor_offset = cursor_position.to_offset(buffer);
-        let completion = self.supermaven.read(cx).completion(completion_id)?;
+        let supermaven = self.supermaven.read(cx);
+        let (text, range) = if let Some(completion) = supermaven.completion(completion_id) {
+            if completion.completion.is_malformed() {
+                return None;
+            }
+            (completion.completion.text(), &completion.data.range)
+        } else {
+            let partial = supermaven.partial_completion(completion_id)?;
+            (partial.text.as_str(), &partial.range)
+        };
 
-        let mut completion_range = completion.range.to_offset(buffer);
+        let mut completion_range = range.to_offset(buffer);
 
         let prefix_len = common_prefix(
             buffer.chars_for_range(completion_range.clone()),
-            completion.text.chars(),
+            text.chars(),
         );
         completion_range.start += prefix_len;
         let suffix_len = common_prefix(
             buffer.reversed_chars_for_range(completion_range.clone()),
-            completion.text[prefix_len..].chars().rev(),
+            text[prefix_len..].chars().rev(),
         );
         completion_range.end = completion_range.end.saturating_sub(suffix_len);
 
-        let completion_text = &completion.text[prefix_len..completion.text.len() - suffix_len];
+        let completion_text = &text[prefix_len..text.len() - suffix_len];
         if completion_range.is_empty()
             && completion_range.start == cursor_offset
             && !completion_text.trim().is_empty()
+            && !supermaven.is_dust(completion_text)
         {
             Some(completion_text)
         } else {
@@ -0,0 +1,125 @@
+use crate::{messages::SupermavenPopupAction, SupermavenCompletionStateId};
+use futures::{future, Stream, StreamExt};
+
+/// A protocol-independent view of what the agent told us, for UI code to react to.
+#[derive(Debug, PartialEq)]
+pub enum SupermavenEvent {
+    CompletionReady {
+        state_id: SupermavenCompletionStateId,
+        text: String,
+        /// Whether the agent has finished this completion.
+        is_complete: bool,
+    },
+    ProgressChanged {
+        percent: f32,
+    },
+    NeedsActivation {
+        url: String,
+    },
+    ShowPopup {
+        message: String,
+        actions: Vec<SupermavenPopupAction>,
+    },
+    RepoChanged {
+        name: Option<String>,
+    },
+}
+
+/// Drops `CompletionReady` events that repeat the previous completion, e.g. for responses whose
+/// items all land past a barrier, so that the UI only re-renders when the completion's text
+/// changes or it finishes. Other events pass through unchanged.
+pub fn dedup_completions(
+    events: impl Stream<Item = SupermavenEvent>,
+) -> impl Stream<Item = SupermavenEvent> {
+    let mut last_completion: Option<(SupermavenCompletionStateId, String, bool)> = None;
+    events.filter(move |event| {
+        let changed = match event {
+            SupermavenEvent::CompletionReady {
+                state_id,
+                text,
+                is_complete,
+            } => {
+                let changed = last_completion.as_ref().map_or(
+                    true,
+                    |(last_state_id, last_text, last_is_complete)| {
+                        last_state_id != state_id
+                            || last_text != text
+                            || last_is_complete != is_complete
+                    },
+                );
+                if changed {
+                    last_completion = Some((*state_id, text.clone(), *is_complete));
+                }
+                changed
+            }
+            _ => true,
+        };
+        future::ready(changed)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{messages::ResponseItem, StateManager};
+
+    #[test]
+    fn test_dedup_completions() {
+        let first = SupermavenCompletionStateId(1);
+        let second = SupermavenCompletionStateId(2);
+        let mut states = StateManager::new();
+        states.insert(first, ());
+        states.insert(second, ());
+
+        let text = |text: &str| ResponseItem::Text { text: text.into() };
+        let deltas = [
+            (first, vec![text("foo")]),
+            (first, vec![text(" bar")]),
+            (first, vec![ResponseItem::Barrier, text("baz")]),
+            (first, vec![text("qux")]),
+            (first, vec![ResponseItem::End]),
+            (first, vec![text("late")]),
+            (second, vec![text("foo bar")]),
+        ];
+        let mut events = Vec::new();
+        for (state_id, items) in deltas {
+            states.push_response(state_id, &items);
+            let state = states.get(state_id).unwrap();
+            events.push(SupermavenEvent::CompletionReady {
+                state_id,
+                text: state.completion.text().to_string(),
+                is_complete: state.is_complete(),
+            });
+        }
+        events.insert(2, SupermavenEvent::RepoChanged { name: None });
+
+        let deduped =
+            smol::block_on(dedup_completions(futures::stream::iter(events)).collect::<Vec<_>>());
+        assert_eq!(
+            deduped,
+            [
+                SupermavenEvent::CompletionReady {
+                    state_id: first,
+                    text: "foo".into(),
+                    is_complete: false,
+                },
+                SupermavenEvent::CompletionReady {
+                    state_id: first,
+                    text: "foo bar".into(),
+                    is_complete: false,
+                },
+                SupermavenEvent::RepoChanged { name: None },
+                SupermavenEvent::CompletionReady {
+                    state_id: first,
+                    text: "foo bar".into(),
+                    is_complete: true,
+                },
+                SupermavenEvent::CompletionReady {
+                    state_id: second,
+                    text: "foo bar".into(),
+                    is_complete: false,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,39 @@
+//! A logger capturing the records emitted on the current thread, so that tests running
+//! in parallel don't see each other's logs.
+
+use std::{cell::RefCell, sync::Once};
+
+thread_local! {
+    static RECORDS: RefCell<Option<Vec<(log::Level, String)>>> = RefCell::new(None);
+}
+
+struct CapturingLogger;
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        RECORDS.with(|records| {
+            if let Some(records) = records.borrow_mut().as_mut() {
+                records.push((record.level(), record.args().to_string()));
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, Vec<(log::Level, String)>) {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&CapturingLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+
+    RECORDS.with(|records| *records.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let records = RECORDS.with(|records| records.borrow_mut().take().unwrap_or_default());
+    (result, records)
+}
@@ -1,101 +1,474 @@
 use anyhow::{anyhow, Context, Result};
-use futures::io::BufReader;
-use futures::{AsyncReadExt, Future};
+use async_compression::futures::bufread::GzipDecoder;
+use async_trait::async_trait;
+use futures::{channel::mpsc, io::BufReader, AsyncRead, AsyncReadExt, AsyncWriteExt, Future};
+use rand::Rng as _;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use smol::fs::{self, File};
 use smol::stream::StreamExt;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
-use util::http::{AsyncBody, HttpClient, Request as HttpRequest};
+use std::time::Duration;
+use util::http::{
+    AsyncBody, HttpClient, Method, Request as HttpRequest, Response, StatusCode, Url,
+};
 use util::paths::SUPERMAVEN_DIR;
 
-#[derive(Serialize)]
+const SUPERMAVEN_API_URL: &str = "https://supermaven.com/api/";
+const ADMIN_API_KEY_ENV_VAR: &str = "SUPERMAVEN_ADMIN_API_KEY";
+const API_URL_ENV_VAR: &str = "SUPERMAVEN_API_URL";
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const MIN_ADMIN_API_KEY_LEN: usize = 32;
+const MAX_ADMIN_API_KEY_LEN: usize = 128;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct GetExternalUserRequest {
     pub id: String,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct CreateExternalUserRequest {
     pub id: String,
     pub email: String,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct DeleteExternalUserRequest {
     pub id: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateExternalUserResponse {
     pub api_key: String,
 }
 
-#[derive(Deserialize)]
+/// An unsuccessful response from the Supermaven API. Deserializes from the body of the
+/// response, e.g. `{ "message": "User not found" }`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
 pub struct SupermavenApiError {
     pub message: String,
+    /// The status of the response the error came from, which isn't part of the body.
+    #[serde(skip)]
+    pub status: Option<u16>,
+    /// The category of the failure, see [`classify_error`].
+    #[serde(skip)]
+    pub kind: Option<AdminApiErrorKind>,
+}
+
+impl SupermavenApiError {
+    fn new(kind: AdminApiErrorKind, status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            status: Some(status.as_u16()),
+            kind: Some(kind),
+        }
+    }
+}
+
+impl std::fmt::Display for SupermavenApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some(status) = self.status else {
+            return write!(f, "Supermaven API error: {}", self.message);
+        };
+        let reason = StatusCode::from_u16(status)
+            .ok()
+            .and_then(|status| status.canonical_reason());
+        match reason {
+            Some(reason) => write!(
+                f,
+                "Supermaven API error ({} {}): {}",
+                status, reason, self.message
+            ),
+            None => write!(f, "Supermaven API error ({}): {}", status, self.message),
+        }
+    }
 }
 
+impl std::error::Error for SupermavenApiError {}
+
 pub struct SupermavenBinary {}
 
 pub struct SupermavenAdminApi {
-    admin_api_key: String,
+    admin_api_key: SecretString,
     api_url: String,
     http_client: Arc<dyn HttpClient>,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    timeout: Duration,
+    extra_headers: Vec<(String, String)>,
 }
 
-#[derive(Debug, Deserialize)]
+/// The response returned by the `download-path` endpoint, e.g.
+///
+/// ```json
+/// {
+///   "downloadUrl": "https://supermaven-public.s3.amazonaws.com/sm-agent/26/linux/amd64/sm-agent",
+///   "version": 26,
+///   "sha256Hash": "8b70a2a1a1ed6dbf0c7ef4a3c1a1a8c5f1e2a19a0f5c3b1c83dc675a0b0c2c7a"
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SupermavenDownloadResponse {
     pub download_url: String,
     pub version: u64,
+    /// The hash of the agent binary itself, after any decompression.
     pub sha256_hash: String,
 }
 
-#[derive(Deserialize)]
+/// A user returned by the `external-user` endpoint, e.g.
+///
+/// ```json
+/// { "id": "1", "email": "user@example.com", "apiKey": "..." }
+/// ```
+///
+/// A missing user is reported as `{ "message": "User not found" }` with a client error status
+/// instead, which [`SupermavenAdminApi::try_get_user`] maps to `None`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SupermavenUser {
-    id: String,
-    email: String,
-    api_key: String,
+    pub id: String,
+    pub email: String,
+    pub api_key: String,
+}
+
+impl SupermavenUser {
+    /// The user's API key. A missing user is `None` from [`SupermavenAdminApi::try_get_user`],
+    /// so `user.as_ref().map(SupermavenUser::api_key)` yields the key only if one was found.
+    pub fn api_key(&self) -> &str {
+        &self.api_key
+    }
+}
+
+/// A string that is redacted when formatted, so that it can't leak into logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        Self(secret.to_string())
+    }
+}
+
+impl std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl std::fmt::Display for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+/// Why [`SupermavenAdminApi::new`] rejected an admin key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidAdminApiKey {
+    Empty,
+    ContainsWhitespace,
+    /// Admin keys are UUID-like, so a key much shorter or longer than that is most likely
+    /// truncated or pasted together with something else.
+    WrongLength {
+        len: usize,
+    },
+}
+
+impl InvalidAdminApiKey {
+    fn check(admin_api_key: &str) -> Result<(), Self> {
+        if admin_api_key.is_empty() {
+            Err(Self::Empty)
+        } else if admin_api_key.chars().any(char::is_whitespace) {
+            Err(Self::ContainsWhitespace)
+        } else if !(MIN_ADMIN_API_KEY_LEN..=MAX_ADMIN_API_KEY_LEN).contains(&admin_api_key.len()) {
+            Err(Self::WrongLength {
+                len: admin_api_key.len(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidAdminApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "Supermaven admin API key is empty"),
+            Self::ContainsWhitespace => {
+                write!(f, "Supermaven admin API key contains whitespace")
+            }
+            Self::WrongLength { len } => write!(
+                f,
+                "Supermaven admin API key has {len} characters, \
+                 expected {MIN_ADMIN_API_KEY_LEN} to {MAX_ADMIN_API_KEY_LEN}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidAdminApiKey {}
+
+impl std::fmt::Debug for SupermavenAdminApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SupermavenAdminApi")
+            .field("admin_api_key", &self.admin_api_key)
+            .field("api_url", &self.api_url)
+            .field("max_retries", &self.max_retries)
+            .field("retry_base_delay", &self.retry_base_delay)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Configures a [`SupermavenAdminApi`] one setting at a time. The admin key and HTTP client
+/// are required; everything else defaults to the same values as [`SupermavenAdminApi::new`].
+#[derive(Default)]
+pub struct SupermavenAdminApiBuilder {
+    admin_api_key: Option<SecretString>,
+    base_url: Option<String>,
+    http_client: Option<Arc<dyn HttpClient>>,
+    timeout: Option<Duration>,
+    max_retries: Option<u32>,
+    retry_base_delay: Option<Duration>,
+    extra_headers: Vec<(String, String)>,
+}
+
+impl SupermavenAdminApiBuilder {
+    pub fn admin_api_key(mut self, admin_api_key: impl Into<SecretString>) -> Self {
+        self.admin_api_key = Some(admin_api_key.into());
+        self
+    }
+
+    /// See [`SupermavenAdminApi::with_base_url`].
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    pub fn http_client(mut self, http_client: Arc<dyn HttpClient>) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
+    /// See [`SupermavenAdminApi::with_timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// See [`SupermavenAdminApi::with_retry_policy`].
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// See [`SupermavenAdminApi::with_retry_policy`].
+    pub fn retry_base_delay(mut self, retry_base_delay: Duration) -> Self {
+        self.retry_base_delay = Some(retry_base_delay);
+        self
+    }
+
+    /// Adds headers to every request. See [`SupermavenAdminApi::with_header`].
+    pub fn extra_headers<K: Into<String>, V: Into<String>>(
+        mut self,
+        headers: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.extra_headers.extend(
+            headers
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into())),
+        );
+        self
+    }
+
+    pub fn build(self) -> Result<SupermavenAdminApi> {
+        let admin_api_key = self
+            .admin_api_key
+            .ok_or_else(|| anyhow!("Supermaven admin API key is required"))?;
+        let http_client = self
+            .http_client
+            .ok_or_else(|| anyhow!("Supermaven admin API HTTP client is required"))?;
+        let base_url = self
+            .base_url
+            .unwrap_or_else(|| SUPERMAVEN_API_URL.to_string());
+
+        let mut api = SupermavenAdminApi::with_base_url(admin_api_key, base_url, http_client)?
+            .with_timeout(self.timeout.unwrap_or(DEFAULT_TIMEOUT))
+            .with_retry_policy(
+                self.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+                self.retry_base_delay.unwrap_or(DEFAULT_RETRY_BASE_DELAY),
+            );
+        for (name, value) in self.extra_headers {
+            api = api.with_header(name, value);
+        }
+        Ok(api)
+    }
+}
+
+/// A page of users returned by [`SupermavenAdminApi::list_users`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserPage {
+    pub users: Vec<SupermavenUser>,
+    /// Where the next page starts, or `None` if this is the last page.
+    #[serde(default)]
+    pub next_cursor: Option<String>,
 }
 
 impl SupermavenAdminApi {
-    pub fn new(admin_api_key: String, http_client: Arc<dyn HttpClient>) -> Self {
+    pub fn builder() -> SupermavenAdminApiBuilder {
+        SupermavenAdminApiBuilder::default()
+    }
+
+    /// Creates an admin API client for the production Supermaven API.
+    ///
+    /// Requests are sent as-is through `http_client`, so proxying is configured there, e.g.
+    /// [`util::http::client`] routes through the proxy from the environment. Proxies that
+    /// expect credentials on every request can be given them with [`Self::with_header`].
+    ///
+    /// The key is checked up front, so that an empty or mangled key is reported here rather
+    /// than as an authentication failure on the first request.
+    pub fn new(
+        admin_api_key: impl Into<SecretString>,
+        http_client: Arc<dyn HttpClient>,
+    ) -> Result<Self, InvalidAdminApiKey> {
+        Self::with_base_url(admin_api_key, SUPERMAVEN_API_URL.to_string(), http_client)
+    }
+
+    /// Like [`Self::new`], but accepts any key without checking it.
+    pub fn new_unchecked(
+        admin_api_key: impl Into<SecretString>,
+        http_client: Arc<dyn HttpClient>,
+    ) -> Self {
+        Self::with_base_url_unchecked(
+            admin_api_key.into(),
+            SUPERMAVEN_API_URL.to_string(),
+            http_client,
+        )
+    }
+
+    /// Creates an admin API client that talks to `api_url` instead of the production
+    /// Supermaven API, e.g. a staging server or a local mock.
+    pub fn with_base_url(
+        admin_api_key: impl Into<SecretString>,
+        api_url: String,
+        http_client: Arc<dyn HttpClient>,
+    ) -> Result<Self, InvalidAdminApiKey> {
+        let admin_api_key = admin_api_key.into();
+        InvalidAdminApiKey::check(admin_api_key.expose())?;
+        Ok(Self::with_base_url_unchecked(
+            admin_api_key,
+            api_url,
+            http_client,
+        ))
+    }
+
+    fn with_base_url_unchecked(
+        admin_api_key: SecretString,
+        mut api_url: String,
+        http_client: Arc<dyn HttpClient>,
+    ) -> Self {
+        if !api_url.ends_with('/') {
+            api_url.push('/');
+        }
+
         Self {
             admin_api_key,
-            api_url: "https://supermaven.com/api/".to_string(),
+            api_url,
             http_client,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_base_delay: DEFAULT_RETRY_BASE_DELAY,
+            timeout: DEFAULT_TIMEOUT,
+            extra_headers: Vec::new(),
         }
     }
 
+    /// Adds a header to every request, e.g. `Proxy-Authorization`. The `Authorization`
+    /// header always carries the admin key, so attempts to set it here are ignored.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let name = name.into();
+        if !name.eq_ignore_ascii_case("Authorization") {
+            self.extra_headers.push((name, value.into()));
+        }
+        self
+    }
+
+    /// Creates an admin API client configured from the environment: the admin key comes from
+    /// `SUPERMAVEN_ADMIN_API_KEY`, and `SUPERMAVEN_API_URL` optionally overrides the API URL.
+    /// Surrounding whitespace is trimmed from both.
+    pub fn from_env(http_client: Arc<dyn HttpClient>) -> Result<Self> {
+        Self::from_env_with(http_client, |key| std::env::var(key).ok())
+    }
+
+    /// Like [`Self::from_env`], but reads the variables through `lookup`.
+    pub fn from_env_with(
+        http_client: Arc<dyn HttpClient>,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self> {
+        let admin_api_key = lookup(ADMIN_API_KEY_ENV_VAR)
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .ok_or_else(|| anyhow!("{ADMIN_API_KEY_ENV_VAR} is not set"))?;
+        let api_url = lookup(API_URL_ENV_VAR)
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .unwrap_or_else(|| SUPERMAVEN_API_URL.to_string());
+        Self::with_base_url(admin_api_key, api_url, http_client)
+            .with_context(|| format!("invalid {ADMIN_API_KEY_ENV_VAR}"))
+    }
+
+    /// Sets how long each request attempt may take, including reading the response body.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many times a request is retried after a server error or transport failure,
+    /// and the delay before the first retry. The delay doubles for every following attempt.
+    pub fn with_retry_policy(mut self, max_retries: u32, retry_base_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_base_delay = retry_base_delay;
+        self
+    }
+
     pub async fn try_get_user(
         &self,
         request: GetExternalUserRequest,
     ) -> Result<Option<SupermavenUser>> {
         let uri = format!("{}external-user/{}", &self.api_url, &request.id);
 
-        let request = HttpRequest::get(&uri).header("Authorization", self.admin_api_key.clone());
-
-        let mut response = self
-            .http_client
-            .send(request.body(AsyncBody::default())?)
+        let (status, body) = self
+            .send_with_truncation_retry(|| self.build_request(Method::GET, &uri, None))
             .await
             .with_context(|| "Unable to get Supermaven API Key".to_string())?;
 
-        let mut body = Vec::new();
-        response.body_mut().read_to_end(&mut body).await?;
-
-        if response.status().is_client_error() {
-            let error: SupermavenApiError = serde_json::from_slice(&body)?;
-            if error.message == "User not found" {
+        if !status.is_success() {
+            // Only a "User not found" message means the user is missing; any other
+            // client error (e.g. a bad admin key) must not be masked as a missing user.
+            if status.is_client_error()
+                && serde_json::from_slice::<SupermavenApiError>(&body)
+                    .map_or(false, |error| error.message == "User not found")
+            {
                 return Ok(None);
-            } else {
-                return Err(anyhow!("Supermaven API error: {}", error.message));
             }
-        } else if response.status().is_server_error() {
-            let error: SupermavenApiError = serde_json::from_slice(&body)?;
-            return Err(anyhow!("Supermaven API server error").context(error.message));
+            return Err(api_error(status, &body));
         }
 
         let body_str = std::str::from_utf8(&body)?;
@@ -110,28 +483,24 @@ impl SupermavenAdminApi {
         &self,
         request: CreateExternalUserRequest,
     ) -> Result<CreateExternalUserResponse> {
-        let uri = format!("{}external-user", &self.api_url);
+        validate_email(&request.email)?;
 
-        let request = HttpRequest::post(&uri)
-            .header("Authorization", self.admin_api_key.clone())
-            .body(AsyncBody::from(serde_json::to_vec(&request)?))?;
+        let uri = format!("{}external-user", &self.api_url);
+        let request_body = serde_json::to_vec(&request)?;
 
-        let mut response = self
-            .http_client
-            .send(request)
+        let (status, body) = self
+            .send_with_truncation_retry(|| {
+                self.build_request(Method::POST, &uri, Some(&request_body))
+            })
             .await
             .with_context(|| "Unable to create Supermaven API Key".to_string())?;
 
-        let mut body = Vec::new();
-        response.body_mut().read_to_end(&mut body).await?;
+        if !status.is_success() {
+            return Err(api_error(status, &body));
+        }
 
         let body_str = std::str::from_utf8(&body)?;
 
-        if !response.status().is_success() {
-            let error: SupermavenApiError = serde_json::from_slice(&body)?;
-            return Err(anyhow!("Supermaven API server error").context(error.message));
-        }
-
         serde_json::from_str::<CreateExternalUserResponse>(body_str)
             .with_context(|| "Unable to parse Supermaven API Key response".to_string())
     }
@@ -139,33 +508,204 @@ impl SupermavenAdminApi {
     pub async fn try_delete_user(&self, request: DeleteExternalUserRequest) -> Result<()> {
         let uri = format!("{}external-user/{}", &self.api_url, &request.id);
 
-        let request = HttpRequest::delete(&uri).header("Authorization", self.admin_api_key.clone());
-
-        let mut response = self
-            .http_client
-            .send(request.body(AsyncBody::default())?)
+        let (status, body) = self
+            .send_with_retry(|| self.build_request(Method::DELETE, &uri, None))
             .await
             .with_context(|| "Unable to delete Supermaven User".to_string())?;
 
-        let mut body = Vec::new();
-        response.body_mut().read_to_end(&mut body).await?;
+        // Deleting a user that doesn't exist leaves us in the desired state.
+        if status.is_success() || status == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
 
-        if response.status().is_client_error() {
-            let error: SupermavenApiError = serde_json::from_slice(&body)?;
-            if error.message == "User not found" {
-                return Ok(());
-            } else {
-                return Err(anyhow!("Supermaven API error: {}", error.message));
+        Err(api_error(status, &body))
+    }
+
+    /// Lists the external users provisioned with this admin key, one page at a time. Pass
+    /// the previous page's `next_cursor` to fetch the page after it.
+    pub async fn list_users(&self, cursor: Option<String>) -> Result<UserPage> {
+        let mut uri = Url::parse(&format!("{}external-user", &self.api_url))?;
+        if let Some(cursor) = &cursor {
+            uri.query_pairs_mut().append_pair("cursor", cursor);
+        }
+
+        let (status, body) = self
+            .send_with_retry(|| self.build_request(Method::GET, uri.as_str(), None))
+            .await
+            .with_context(|| "Unable to list Supermaven users".to_string())?;
+
+        if !status.is_success() {
+            return Err(api_error(status, &body));
+        }
+
+        serde_json::from_slice::<UserPage>(&body)
+            .with_context(|| "Unable to parse Supermaven user list response".to_string())
+    }
+
+    /// Walks every page of [`Self::list_users`], collecting all users.
+    pub async fn list_all_users(&self) -> Result<Vec<SupermavenUser>> {
+        let mut users = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self.list_users(cursor.clone()).await?;
+            users.extend(page.users);
+            match page.next_cursor {
+                Some(next_cursor) if Some(&next_cursor) == cursor.as_ref() => {
+                    return Err(anyhow!(
+                        "Supermaven user list repeated cursor {:?}",
+                        next_cursor
+                    ));
+                }
+                Some(next_cursor) => cursor = Some(next_cursor),
+                None => return Ok(users),
             }
-        } else if response.status().is_server_error() {
-            let error: SupermavenApiError = serde_json::from_slice(&body)?;
-            return Err(anyhow!("Supermaven API server error").context(error.message));
         }
+    }
 
-        Ok(())
+    /// Builds a request carrying the admin key and any headers added with [`Self::with_header`].
+    /// A `json_body`, if given, is sent with a matching `Content-Type`.
+    fn build_request(
+        &self,
+        method: Method,
+        uri: &str,
+        json_body: Option<&[u8]>,
+    ) -> Result<HttpRequest<AsyncBody>> {
+        let mut request = HttpRequest::builder().method(method).uri(uri);
+        for (name, value) in &self.extra_headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        request = request
+            .header("Authorization", self.admin_api_key.expose())
+            .header("Accept", "application/json");
+        let body = match json_body {
+            Some(json_body) => {
+                request = request.header("Content-Type", "application/json");
+                AsyncBody::from(json_body.to_vec())
+            }
+            None => AsyncBody::default(),
+        };
+        Ok(request.body(body)?)
+    }
+
+    /// Like [`Self::send_with_retry`], but a successful response whose body ends partway
+    /// through its JSON, usually because the connection dropped after the headers, is
+    /// requested once more. This happens regardless of the retry policy.
+    async fn send_with_truncation_retry(
+        &self,
+        build_request: impl Fn() -> Result<HttpRequest<AsyncBody>>,
+    ) -> Result<(StatusCode, Vec<u8>)> {
+        let mut retried = false;
+        loop {
+            let (status, body) = self.send_with_retry(&build_request).await?;
+            let truncated = status.is_success()
+                && serde_json::from_slice::<serde::de::IgnoredAny>(&body)
+                    .map_or_else(|error| error.is_eof(), |_| false);
+            if !truncated {
+                return Ok((status, body));
+            }
+            if retried {
+                return Err(SupermavenApiError::new(
+                    AdminApiErrorKind::Network,
+                    status,
+                    "response body ended unexpectedly, the connection may have been reset",
+                )
+                .into());
+            }
+            retried = true;
+        }
+    }
+
+    /// Sends the request produced by `build_request` and reads its body, retrying server
+    /// errors and transport failures with exponential backoff. Client errors are returned
+    /// immediately, since retrying them won't change the outcome. A successful response that
+    /// declares a non-JSON `Content-Type` is reported as an error rather than parsed.
+    async fn send_with_retry(
+        &self,
+        build_request: impl Fn() -> Result<HttpRequest<AsyncBody>>,
+    ) -> Result<(StatusCode, Vec<u8>)> {
+        let mut attempt = 0;
+        loop {
+            let request = build_request()?;
+            let result = with_timeout(self.timeout, async {
+                let mut response = self.http_client.send(request).await?;
+                let content_type = response
+                    .headers()
+                    .get("Content-Type")
+                    .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+                let mut body = Vec::new();
+                response.body_mut().read_to_end(&mut body).await?;
+                anyhow::Ok((response.status(), content_type, body))
+            })
+            .await;
+
+            let should_retry = match &result {
+                Ok((status, _, _)) => status.is_server_error(),
+                Err(_) => true,
+            };
+            if !should_retry || attempt >= self.max_retries {
+                let (status, content_type, body) = result?;
+                match content_type {
+                    Some(content_type) if status.is_success() && !is_json(&content_type) => {
+                        return Err(SupermavenApiError::new(
+                            AdminApiErrorKind::Parse,
+                            status,
+                            format!("expected a JSON response, got {content_type}"),
+                        )
+                        .into());
+                    }
+                    _ => return Ok((status, body)),
+                }
+            }
+
+            smol::Timer::after(self.retry_delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .retry_base_delay
+            .saturating_mul(2u32.saturating_pow(attempt));
+        let jitter = delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5));
+        delay + jitter
     }
+}
+
+/// Sends `request`, failing if no response arrives within `timeout`. Only waiting for the
+/// response head is subject to the timeout, so callers reading the body wrap that in
+/// [`with_timeout`] too.
+async fn send_with_timeout(
+    client: &dyn HttpClient,
+    request: HttpRequest<AsyncBody>,
+    timeout: Duration,
+) -> Result<Response<AsyncBody>> {
+    with_timeout(timeout, async { Ok(client.send(request).await?) }).await
+}
+
+/// Runs `future`, failing with [`TimedOut`] if it doesn't finish within `timeout`.
+async fn with_timeout<T>(timeout: Duration, future: impl Future<Output = Result<T>>) -> Result<T> {
+    let timer = async {
+        smol::Timer::after(timeout).await;
+        Err(TimedOut(timeout).into())
+    };
+    smol::future::or(future, timer).await
+}
+
+/// The operations offered by the Supermaven admin API. Callers depend on this trait rather
+/// than on [`SupermavenAdminApi`] so that a fake can be substituted in tests.
+#[async_trait]
+pub trait AdminApi: Send + Sync {
+    async fn try_get_user(&self, request: GetExternalUserRequest)
+        -> Result<Option<SupermavenUser>>;
+
+    async fn try_create_user(
+        &self,
+        request: CreateExternalUserRequest,
+    ) -> Result<CreateExternalUserResponse>;
 
-    pub async fn try_get_or_create_user(
+    async fn try_delete_user(&self, request: DeleteExternalUserRequest) -> Result<()>;
+
+    async fn try_get_or_create_user(
         &self,
         request: CreateExternalUserRequest,
     ) -> Result<CreateExternalUserResponse> {
@@ -173,46 +713,240 @@ impl SupermavenAdminApi {
             id: request.id.clone(),
         };
 
-        match self.try_get_user(get_user_request).await? {
-            None => self.try_create_user(request).await,
-            Some(SupermavenUser { api_key, .. }) => Ok(CreateExternalUserResponse { api_key }),
+        if let Some(SupermavenUser { api_key, .. }) = self.try_get_user(get_user_request).await? {
+            return Ok(CreateExternalUserResponse { api_key });
+        }
+
+        let user_id = request.id.clone();
+        match self.try_create_user(request).await {
+            // Someone else may have created the user between our lookup and our create
+            // request, in which case the create conflicts but the user now exists. Any other
+            // failure is returned as is.
+            Err(error) if classify_error(&error) == Some(AdminApiErrorKind::Conflict) => match self
+                .try_get_user(GetExternalUserRequest { id: user_id })
+                .await
+            {
+                Ok(Some(SupermavenUser { api_key, .. })) => {
+                    Ok(CreateExternalUserResponse { api_key })
+                }
+                _ => Err(error),
+            },
+            result => result,
+        }
+    }
+}
+
+#[async_trait]
+impl AdminApi for SupermavenAdminApi {
+    async fn try_get_user(
+        &self,
+        request: GetExternalUserRequest,
+    ) -> Result<Option<SupermavenUser>> {
+        SupermavenAdminApi::try_get_user(self, request).await
+    }
+
+    async fn try_create_user(
+        &self,
+        request: CreateExternalUserRequest,
+    ) -> Result<CreateExternalUserResponse> {
+        SupermavenAdminApi::try_create_user(self, request).await
+    }
+
+    async fn try_delete_user(&self, request: DeleteExternalUserRequest) -> Result<()> {
+        SupermavenAdminApi::try_delete_user(self, request).await
+    }
+}
+
+/// Rejects obviously malformed email addresses before they reach the server. This is
+/// deliberately permissive and only catches common mistakes.
+fn validate_email(email: &str) -> Result<()> {
+    let is_valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+                && !email.chars().any(char::is_whitespace)
+        }
+        None => false,
+    };
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(anyhow!("invalid email: {email}"))
+    }
+}
+
+/// Broad categories of admin API failures, for deciding whether to retry a request or
+/// surface the failure to the user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdminApiErrorKind {
+    /// The server couldn't be reached, or didn't respond in time.
+    Network,
+    /// The admin API key was rejected (401 or 403).
+    Auth,
+    /// The request conflicts with the server's state (409), e.g. the user already exists.
+    Conflict,
+    /// Any other 4xx response.
+    Client,
+    /// A 5xx response.
+    Server,
+    /// The response couldn't be parsed.
+    Parse,
+}
+
+impl AdminApiErrorKind {
+    pub fn is_retryable(self) -> bool {
+        matches!(self, Self::Network | Self::Server)
+    }
+
+    fn for_status(status: StatusCode) -> Self {
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            Self::Auth
+        } else if status == StatusCode::CONFLICT {
+            Self::Conflict
+        } else if status.is_server_error() {
+            Self::Server
+        } else {
+            Self::Client
         }
     }
 }
 
+/// Raised when a request, or reading its response, doesn't finish within its timeout.
+#[derive(Debug)]
+struct TimedOut(Duration);
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Supermaven request timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Classifies an error returned by the admin API by inspecting its causes. Returns `None` for
+/// errors that don't come from talking to the server, such as an invalid email.
+pub fn classify_error(error: &anyhow::Error) -> Option<AdminApiErrorKind> {
+    error.chain().find_map(|cause| {
+        if let Some(kind) = cause
+            .downcast_ref::<SupermavenApiError>()
+            .and_then(|error| error.kind)
+        {
+            Some(kind)
+        } else if cause.is::<TimedOut>()
+            || cause.is::<util::http::Error>()
+            || cause.is::<std::io::Error>()
+        {
+            Some(AdminApiErrorKind::Network)
+        } else if cause.is::<serde_json::Error>() || cause.is::<std::str::Utf8Error>() {
+            Some(AdminApiErrorKind::Parse)
+        } else {
+            None
+        }
+    })
+}
+
+/// Whether a `Content-Type` header value denotes JSON, including `+json` types.
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+fn api_error(status: StatusCode, body: &[u8]) -> anyhow::Error {
+    let message = serde_json::from_slice::<SupermavenApiError>(body)
+        .map(|error| error.message)
+        .unwrap_or_else(|_| String::from_utf8_lossy(body).into_owned());
+    SupermavenApiError::new(AdminApiErrorKind::for_status(status), status, message).into()
+}
+
+/// Resolves the latest agent release for the given platform without downloading it. This
+/// only makes the `download-path` request, so it is cheap enough for update checks.
+///
+/// Fails with "no Supermaven agent available for {platform}/{arch}" when no agent is
+/// published for the platform, which the server reports as a 404 or an empty response.
 pub async fn latest_release(
     client: Arc<dyn HttpClient>,
-    platform: &str,
-    arch: &str,
+    platform: Platform,
+    arch: Arch,
 ) -> Result<SupermavenDownloadResponse> {
     let uri = format!(
-        "https://supermaven.com/api/download-path?platform={}&arch={}",
-        platform, arch
+        "{}download-path?platform={}&arch={}",
+        SUPERMAVEN_API_URL, platform, arch
     );
 
     // Download is not authenticated
-    let request = HttpRequest::get(&uri);
-
-    let mut response = client
-        .send(request.body(AsyncBody::default())?)
-        .await
-        .with_context(|| "Unable to acquire Supermaven Agent".to_string())?;
+    let request = HttpRequest::get(&uri).body(AsyncBody::default())?;
+    let (status, body) = with_timeout(DEFAULT_TIMEOUT, async {
+        let mut response = client.send(request).await?;
+        let mut body = Vec::new();
+        response.body_mut().read_to_end(&mut body).await?;
+        anyhow::Ok((response.status(), body))
+    })
+    .await
+    .with_context(|| "Unable to acquire Supermaven Agent".to_string())?;
 
-    let mut body = Vec::new();
-    response.body_mut().read_to_end(&mut body).await?;
+    let unavailable = || anyhow!("no Supermaven agent available for {platform}/{arch}");
+    if status == StatusCode::NOT_FOUND {
+        return Err(unavailable());
+    }
 
-    if response.status().is_client_error() || response.status().is_server_error() {
+    if status.is_client_error() || status.is_server_error() {
         let body_str = std::str::from_utf8(&body)?;
-        let error: SupermavenApiError = serde_json::from_str(body_str)?;
-        return Err(anyhow!("Supermaven API error: {}", error.message));
+        let mut error: SupermavenApiError = serde_json::from_str(body_str)?;
+        error.status = Some(status.as_u16());
+        error.kind = Some(AdminApiErrorKind::for_status(status));
+        return Err(error.into());
+    }
+
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Err(unavailable());
+    }
+    let response = serde_json::from_slice::<serde_json::Value>(&body)
+        .with_context(|| "Unable to parse Supermaven Agent response".to_string())?;
+    let has_download_url = response
+        .get("downloadUrl")
+        .and_then(serde_json::Value::as_str)
+        .map_or(false, |url| !url.is_empty());
+    if !has_download_url {
+        return Err(unavailable());
     }
 
-    serde_json::from_slice::<SupermavenDownloadResponse>(&body)
+    serde_json::from_value::<SupermavenDownloadResponse>(response)
         .with_context(|| "Unable to parse Supermaven Agent response".to_string())
 }
 
+/// The oldest agent release this client speaks the protocol of. Older releases are refused
+/// rather than downloaded or run.
+///
+/// 26 is the release the `download-path` endpoint was serving when the `supermaven` crate's
+/// message types were written (see the example on [`SupermavenDownloadResponse`]), which makes
+/// it the oldest release they are known to match. Raise it when the client starts relying on
+/// messages that only newer agents send.
+pub const MIN_SUPPORTED_AGENT_VERSION: u64 = 26;
+
+/// Fails for agent versions older than [`MIN_SUPPORTED_AGENT_VERSION`].
+pub fn check_supported_version(version: u64) -> Result<()> {
+    if version < MIN_SUPPORTED_AGENT_VERSION {
+        return Err(anyhow!(
+            "Supermaven Agent version {version} is older than the minimum supported version \
+             {MIN_SUPPORTED_AGENT_VERSION}, please update Supermaven"
+        ));
+    }
+    Ok(())
+}
+
 pub fn version_path(version: u64) -> PathBuf {
-    SUPERMAVEN_DIR.join(format!("sm-agent-{}", version))
+    binary_path(&SUPERMAVEN_DIR, version)
+}
+
+fn binary_path(dir: &Path, version: u64) -> PathBuf {
+    dir.join(format!("sm-agent-{}", version))
 }
 
 pub async fn has_version(version_path: &Path) -> bool {
@@ -221,67 +955,750 @@ pub async fn has_version(version_path: &Path) -> bool {
         .map_or(false, |m| m.is_file())
 }
 
-pub fn get_supermaven_agent_path(
-    client: Arc<dyn HttpClient>,
-) -> impl Future<Output = Result<PathBuf>> {
-    async move {
-        fs::create_dir_all(&*SUPERMAVEN_DIR)
-            .await
-            .with_context(|| {
-                format!(
-                    "Could not create Supermaven Agent Directory at {:?}",
-                    &*SUPERMAVEN_DIR
-                )
-            })?;
-
-        let platform = match std::env::consts::OS {
-            "macos" => "darwin",
-            "windows" => "windows",
-            "linux" => "linux",
-            _ => return Err(anyhow!("unsupported platform")),
-        };
-
-        let arch = match std::env::consts::ARCH {
-            "x86_64" => "amd64",
-            "aarch64" => "arm64",
-            _ => return Err(anyhow!("unsupported architecture")),
-        };
-
-        let download_info = latest_release(client.clone(), platform, arch).await?;
+/// An operating system the agent is published for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Platform {
+    Darwin,
+    Linux,
+    Windows,
+}
 
-        let binary_path = version_path(download_info.version);
+/// A CPU architecture the agent is published for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Arch {
+    Arm64,
+    X86_64,
+}
 
-        if has_version(&binary_path).await {
-            return Ok(binary_path);
+impl Platform {
+    /// The token the Supermaven API uses for this platform.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Darwin => "darwin",
+            Self::Linux => "linux",
+            Self::Windows => "windows",
         }
+    }
+}
 
-        let request = HttpRequest::get(&download_info.download_url);
+impl Arch {
+    /// The token the Supermaven API uses for this architecture.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Arm64 => "arm64",
+            Self::X86_64 => "amd64",
+        }
+    }
+}
 
-        let mut response = client
-            .send(request.body(AsyncBody::default())?)
-            .await
-            .with_context(|| "Unable to download Supermaven Agent".to_string())?;
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
-        let mut file = File::create(&binary_path)
-            .await
-            .with_context(|| format!("Unable to create file at {:?}", binary_path))?;
+impl std::fmt::Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
-        futures::io::copy(BufReader::new(response.body_mut()), &mut file)
-            .await
-            .with_context(|| format!("Unable to write binary to file at {:?}", binary_path))?;
+impl FromStr for Platform {
+    type Err = anyhow::Error;
 
-        #[cfg(not(windows))]
-        {
-            file.set_permissions(<fs::Permissions as fs::unix::PermissionsExt>::from_mode(
-                0o755,
-            ))
-            .await?;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "darwin" => Ok(Self::Darwin),
+            "linux" => Ok(Self::Linux),
+            "windows" => Ok(Self::Windows),
+            _ => Err(anyhow!("unsupported platform: {}", s)),
         }
+    }
+}
 
-        let mut old_binary_paths = fs::read_dir(&*SUPERMAVEN_DIR).await?;
-        while let Some(old_binary_path) = old_binary_paths.next().await {
+impl FromStr for Arch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "arm64" => Ok(Self::Arm64),
+            "amd64" => Ok(Self::X86_64),
+            _ => Err(anyhow!("unsupported architecture: {}", s)),
+        }
+    }
+}
+
+/// The platform and architecture pairs the agent is published for. Add new pairs here as
+/// Supermaven starts publishing them.
+pub const SUPPORTED_TARGETS: &[(Platform, Arch)] = &[
+    (Platform::Darwin, Arch::Arm64),
+    (Platform::Darwin, Arch::X86_64),
+    (Platform::Linux, Arch::Arm64),
+    (Platform::Linux, Arch::X86_64),
+    (Platform::Windows, Arch::X86_64),
+];
+
+/// Fails if the agent isn't published for `platform` and `arch`, so downloads for them can be
+/// rejected without asking the API.
+pub fn check_supported_target(platform: Platform, arch: Arch) -> Result<()> {
+    if SUPPORTED_TARGETS.contains(&(platform, arch)) {
+        Ok(())
+    } else {
+        Err(anyhow!("unsupported platform/arch: {}/{}", platform, arch))
+    }
+}
+
+/// Parses the API's platform and architecture tokens, e.g. `linux` and `amd64`, failing on
+/// unknown tokens and on pairs missing from [`SUPPORTED_TARGETS`].
+pub fn from_platform_string(platform: &str, arch: &str) -> Result<(Platform, Arch)> {
+    let (platform, arch) = (platform.parse()?, arch.parse()?);
+    check_supported_target(platform, arch)?;
+    Ok((platform, arch))
+}
+
+/// Returns the platform and architecture of the current system.
+pub fn current_platform_arch() -> Result<(Platform, Arch)> {
+    platform_arch(std::env::consts::OS, std::env::consts::ARCH)
+}
+
+/// Maps Rust's names for an OS and architecture (see [`std::env::consts`]) to the agent's.
+fn platform_arch(os: &str, arch: &str) -> Result<(Platform, Arch)> {
+    let platform = match os {
+        "macos" => Platform::Darwin,
+        "windows" => Platform::Windows,
+        "linux" => Platform::Linux,
+        _ => return Err(anyhow!("unsupported platform: {}", os)),
+    };
+
+    let arch = match arch {
+        "x86_64" => Arch::X86_64,
+        "aarch64" => Arch::Arm64,
+        _ => return Err(anyhow!("unsupported architecture: {}", arch)),
+    };
+
+    Ok((platform, arch))
+}
+
+/// Metadata about an installed agent, stored in a sidecar file next to the binary.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InstalledAgent {
+    version: u64,
+    sha256_hash: String,
+}
+
+fn sidecar_path(binary_path: &Path) -> PathBuf {
+    binary_path.with_extension("json")
+}
+
+async fn installed_agent(binary_path: &Path) -> Option<InstalledAgent> {
+    let sidecar = fs::read(sidecar_path(binary_path)).await.ok()?;
+    serde_json::from_slice(&sidecar).ok()
+}
+
+/// The version recorded in the sidecar of the agent installed at `binary_path`, if any.
+pub async fn installed_agent_version(binary_path: &Path) -> Option<u64> {
+    Some(installed_agent(binary_path).await?.version)
+}
+
+/// Returns the path to the latest Supermaven Agent in `dir`, only downloading it if the
+/// installed binary is missing, out of date, or doesn't match its recorded sha256. Fails if
+/// the latest release is older than [`MIN_SUPPORTED_AGENT_VERSION`].
+pub async fn ensure_binary(
+    client: Arc<dyn HttpClient>,
+    platform: Platform,
+    arch: Arch,
+    dir: &Path,
+) -> Result<PathBuf> {
+    check_supported_target(platform, arch)?;
+    let download_info = latest_release(client.clone(), platform, arch).await?;
+    check_supported_version(download_info.version)?;
+    let binary_path = binary_path(dir, download_info.version);
+
+    if let Some(installed) = installed_agent(&binary_path).await {
+        if installed.version == download_info.version
+            && installed
+                .sha256_hash
+                .eq_ignore_ascii_case(&download_info.sha256_hash)
+        {
+            if verify_installed_binary(&binary_path, &installed.sha256_hash)
+                .await
+                .unwrap_or(false)
+            {
+                return Ok(binary_path);
+            }
+        }
+    }
+
+    install_binary(
+        client,
+        &download_info,
+        dir,
+        None,
+        DownloadRetryPolicy::default(),
+    )
+    .await
+}
+
+/// Downloads the latest Supermaven Agent for the given platform and architecture into
+/// `dest_dir`, returning the resolved version along with the path of the installed binary.
+/// Fails without making any request if the pair isn't in [`SUPPORTED_TARGETS`], and without
+/// downloading anything if the release is older than [`MIN_SUPPORTED_AGENT_VERSION`].
+///
+/// The download can be cancelled by dropping the returned future at any point, e.g. when the
+/// user turns Supermaven off. Any partially downloaded file is removed, and nothing is
+/// installed.
+pub async fn download_binary(
+    client: Arc<dyn HttpClient>,
+    platform: Platform,
+    arch: Arch,
+    dest_dir: &Path,
+) -> Result<(u64, PathBuf)> {
+    download_binary_with_retry_policy(
+        client,
+        platform,
+        arch,
+        dest_dir,
+        DownloadRetryPolicy::default(),
+    )
+    .await
+}
+
+/// Like [`download_binary`], but retries failed downloads according to `retry_policy`.
+pub async fn download_binary_with_retry_policy(
+    client: Arc<dyn HttpClient>,
+    platform: Platform,
+    arch: Arch,
+    dest_dir: &Path,
+    retry_policy: DownloadRetryPolicy,
+) -> Result<(u64, PathBuf)> {
+    check_supported_target(platform, arch)?;
+    let download_info = latest_release(client.clone(), platform, arch).await?;
+    check_supported_version(download_info.version)?;
+    let binary_path = install_binary(client, &download_info, dest_dir, None, retry_policy).await?;
+    Ok((download_info.version, binary_path))
+}
+
+/// Downloads the latest Supermaven Agent for the current platform into `dest_dir`.
+pub async fn download_binary_for_current_platform(
+    client: Arc<dyn HttpClient>,
+    dest_dir: &Path,
+) -> Result<(u64, PathBuf)> {
+    let (platform, arch) = current_platform_arch()?;
+    download_binary(client, platform, arch, dest_dir).await
+}
+
+/// Progress of an in-flight agent download.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DownloadProgress {
+    pub bytes_downloaded: u64,
+    /// The size of the download, when the server reports a `Content-Length`. A compressed
+    /// download is smaller than the binary it unpacks to.
+    pub total_bytes: Option<u64>,
+}
+
+/// Like [`download_binary`], but reports progress on `progress` after every chunk read
+/// from the response body. Updates are dropped if the receiver falls behind.
+pub async fn download_binary_with_progress(
+    client: Arc<dyn HttpClient>,
+    platform: Platform,
+    arch: Arch,
+    dest_dir: &Path,
+    mut progress: mpsc::Sender<DownloadProgress>,
+) -> Result<(u64, PathBuf)> {
+    check_supported_target(platform, arch)?;
+    let download_info = latest_release(client.clone(), platform, arch).await?;
+    check_supported_version(download_info.version)?;
+    let binary_path = install_binary(
+        client,
+        &download_info,
+        dest_dir,
+        Some(&mut progress),
+        DownloadRetryPolicy::default(),
+    )
+    .await?;
+    Ok((download_info.version, binary_path))
+}
+
+/// How failed agent downloads are retried. Transport failures and server errors are retried
+/// with exponential backoff, resuming the partial download where the server allows it. Other
+/// failures, such as a hash mismatch, are returned right away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DownloadRetryPolicy {
+    /// How many times to retry after the first attempt fails.
+    pub max_retries: u32,
+    /// The delay before the first retry, which doubles for every following one.
+    pub base_delay: Duration,
+    /// The longest delay between two attempts.
+    pub max_delay: Duration,
+    /// How long an attempt may go without receiving any data before it fails, like any other
+    /// transport failure.
+    pub idle_timeout: Duration,
+}
+
+impl Default for DownloadRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            idle_timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl DownloadRetryPolicy {
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay)
+    }
+}
+
+/// An unsuccessful response to an agent download request.
+#[derive(Debug)]
+struct DownloadStatusError(StatusCode);
+
+impl DownloadStatusError {
+    fn is_retryable(&self) -> bool {
+        self.0.is_server_error() || self.0 == StatusCode::TOO_MANY_REQUESTS
+    }
+}
+
+impl std::fmt::Display for DownloadStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unable to download Supermaven Agent: status {}", self.0)
+    }
+}
+
+impl std::error::Error for DownloadStatusError {}
+
+/// Whether a failed download attempt might succeed if tried again.
+fn is_retryable_download_error(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .find_map(|cause| {
+            if let Some(error) = cause.downcast_ref::<DownloadStatusError>() {
+                Some(error.is_retryable())
+            } else if cause.is::<TimedOut>() || cause.is::<util::http::Error>() {
+                Some(true)
+            } else if let Some(error) = cause.downcast_ref::<std::io::Error>() {
+                // Corrupt compressed data won't decode any better the next time.
+                Some(error.kind() != std::io::ErrorKind::InvalidData)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(false)
+}
+
+async fn install_binary(
+    client: Arc<dyn HttpClient>,
+    download_info: &SupermavenDownloadResponse,
+    dest_dir: &Path,
+    mut progress: Option<&mut mpsc::Sender<DownloadProgress>>,
+    retry_policy: DownloadRetryPolicy,
+) -> Result<PathBuf> {
+    prepare_dest_dir(dest_dir).await?;
+
+    // Attempts resume the partial download left by the previous one. It is removed when the
+    // download succeeds, fails for good or is dropped, and only kept after a transient failure.
+    let partial =
+        TempFile::new(binary_path(dest_dir, download_info.version).with_extension("partial"));
+    let mut attempt = 0;
+    loop {
+        let result = try_install_binary(
+            client.clone(),
+            download_info,
+            dest_dir,
+            &partial,
+            progress.as_deref_mut(),
+            retry_policy.idle_timeout,
+        )
+        .await;
+        match result {
+            Ok(binary_path) => return Ok(binary_path),
+            Err(error)
+                if attempt < retry_policy.max_retries && is_retryable_download_error(&error) =>
+            {
+                smol::Timer::after(retry_policy.delay(attempt)).await;
+                attempt += 1;
+            }
+            Err(error) => {
+                if is_retryable_download_error(&error) {
+                    partial.keep();
+                }
+                if attempt > 0 {
+                    return Err(error.context(format!(
+                        "Unable to download Supermaven Agent after {} attempts",
+                        attempt + 1
+                    )));
+                }
+                return Err(error);
+            }
+        }
+    }
+}
+
+/// A file that is removed when dropped unless it was moved into place with
+/// [`TempFile::persist`] or kept with [`TempFile::keep`], so that it is cleaned up even if the
+/// task writing it fails, panics or is dropped.
+struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            persisted: false,
+        }
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Leaves the file in place, e.g. so that an interrupted download can be resumed later.
+    fn keep(mut self) {
+        self.persisted = true;
+    }
+
+    /// Moves the file to `dest`, after which it is no longer removed on drop.
+    async fn persist(mut self, dest: &Path) -> Result<()> {
+        fs::rename(&self.path, dest)
+            .await
+            .with_context(|| format!("Unable to move binary to {:?}", dest))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            std::fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// Makes sure `dest_dir` exists and can be written to, so that an unusable destination is
+/// reported before anything is downloaded.
+async fn prepare_dest_dir(dest_dir: &Path) -> Result<()> {
+    fs::create_dir_all(dest_dir)
+        .await
+        .with_context(|| format!("Unable to create Supermaven Agent directory {:?}", dest_dir))?;
+
+    let probe_path = dest_dir.join(".sm-agent-write-check");
+    let result = fs::write(&probe_path, b"").await;
+    fs::remove_file(&probe_path).await.ok();
+    result.with_context(|| format!("Supermaven Agent directory {:?} is not writable", dest_dir))
+}
+
+async fn try_install_binary(
+    client: Arc<dyn HttpClient>,
+    download_info: &SupermavenDownloadResponse,
+    dest_dir: &Path,
+    partial: &TempFile,
+    mut progress: Option<&mut mpsc::Sender<DownloadProgress>>,
+    idle_timeout: Duration,
+) -> Result<PathBuf> {
+    let binary_path = binary_path(dest_dir, download_info.version);
+
+    // Download next to the final location so that a corrupt download never
+    // becomes the active agent. If a previous attempt was interrupted, resume
+    // from where it left off.
+    let partial_path = partial.path();
+    let resume_from = fs::metadata(&partial_path)
+        .await
+        .map_or(0, |metadata| metadata.len());
+
+    let mut request = HttpRequest::get(&download_info.download_url);
+    if resume_from > 0 {
+        request = request.header("Range", format!("bytes={}-", resume_from));
+    }
+
+    let mut response = send_with_timeout(
+        client.as_ref(),
+        request.body(AsyncBody::default())?,
+        idle_timeout,
+    )
+    .await
+    .with_context(|| "Unable to download Supermaven Agent".to_string())?;
+
+    if !response.status().is_success() {
+        return Err(DownloadStatusError(response.status()).into());
+    }
+
+    let resumed = resume_from > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+    if !resumed {
+        check_content_encoding(
+            response
+                .headers()
+                .get("Content-Encoding")
+                .and_then(|value| value.to_str().ok()),
+        )?;
+    }
+    let mut bytes_downloaded = if resumed { resume_from } else { 0 };
+    let total_bytes = response
+        .headers()
+        .get("Content-Length")
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .map(|content_length| bytes_downloaded + content_length);
+
+    let mut file = if resumed {
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&partial_path)
+            .await
+            .with_context(|| format!("Unable to open file at {:?}", partial_path))?
+    } else {
+        // Either there's nothing to resume or the server ignored the range.
+        File::create(&partial_path)
+            .await
+            .with_context(|| format!("Unable to create file at {:?}", partial_path))?
+    };
+
+    let mut buffer = vec![0; 64 * 1024];
+    let body = response.body_mut();
+    loop {
+        let bytes_read = with_timeout(idle_timeout, async { Ok(body.read(&mut buffer).await?) })
+            .await
+            .with_context(|| "Unable to download Supermaven Agent".to_string())?;
+        if bytes_read == 0 {
+            break;
+        }
+        file.write_all(&buffer[..bytes_read])
+            .await
+            .with_context(|| format!("Unable to write binary to file at {:?}", partial_path))?;
+
+        bytes_downloaded += bytes_read as u64;
+        if let Some(progress) = progress.as_mut() {
+            progress
+                .try_send(DownloadProgress {
+                    bytes_downloaded,
+                    total_bytes,
+                })
+                .ok();
+        }
+    }
+    file.flush().await?;
+
+    drop(file);
+
+    // The download is complete, so it won't be resumed. Unpack it next to the final location
+    // too, so that only a verified binary is installed.
+    let download = TempFile::new(binary_path.with_extension("download"));
+    fs::rename(partial_path, download.path())
+        .await
+        .with_context(|| format!("Unable to move download to {:?}", download.path()))?;
+    let unpacked = TempFile::new(binary_path.with_extension("unpacked"));
+    let payload = File::open(download.path())
+        .await
+        .with_context(|| format!("Unable to open file at {:?}", download.path()))?;
+    unpack_agent(payload, unpacked.path()).await?;
+    drop(download);
+
+    let mut hasher = Sha256::new();
+    hash_file(unpacked.path(), &mut hasher).await?;
+    let actual_hash = format!("{:x}", hasher.finalize());
+    verify_sha256(&download_info.sha256_hash, &actual_hash)?;
+    unpacked.persist(&binary_path).await?;
+
+    #[cfg(unix)]
+    {
+        fs::set_permissions(
+            &binary_path,
+            <fs::Permissions as fs::unix::PermissionsExt>::from_mode(0o755),
+        )
+        .await
+        .with_context(|| format!("Unable to make {:?} executable", binary_path))?;
+    }
+
+    let installed = InstalledAgent {
+        version: download_info.version,
+        sha256_hash: actual_hash,
+    };
+    fs::write(sidecar_path(&binary_path), serde_json::to_vec(&installed)?)
+        .await
+        .with_context(|| format!("Unable to record installed version for {:?}", binary_path))?;
+
+    Ok(binary_path)
+}
+
+/// The number of leading bytes inspected to tell payload formats apart.
+const MAGIC_PREFIX_LEN: usize = 6;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Formats the agent could plausibly be served in, but that we don't know how to unpack.
+const UNSUPPORTED_MAGIC: &[(&str, &[u8])] = &[
+    ("zstd", &[0x28, 0xb5, 0x2f, 0xfd]),
+    ("xz", &[0xfd, b'7', b'z', b'X', b'Z', 0x00]),
+    ("bzip2", b"BZh"),
+    ("zip", b"PK\x03\x04"),
+];
+
+/// A tar header is marked by `ustar` at this offset.
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// The names the agent binary may have inside a tar archive.
+const AGENT_BINARY_NAMES: &[&str] = &["sm-agent", "sm-agent.exe"];
+
+/// How the body of an agent download is encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PayloadFormat {
+    /// Not compressed.
+    Plain,
+    Gzip,
+}
+
+/// Rejects content encodings that the HTTP client won't have decoded and that
+/// [`unpack_agent`] can't decode either. A gzipped body may or may not have been decoded
+/// already, which [`unpack_agent`] tells apart by its magic bytes.
+fn check_content_encoding(content_encoding: Option<&str>) -> Result<()> {
+    match content_encoding.map(|encoding| encoding.trim().to_ascii_lowercase()) {
+        None => Ok(()),
+        Some(encoding) if matches!(encoding.as_str(), "identity" | "gzip" | "x-gzip") => Ok(()),
+        Some(encoding) => Err(anyhow!(
+            "Unable to unpack Supermaven Agent: unsupported Content-Encoding {encoding:?}"
+        )),
+    }
+}
+
+/// Infers the compression of a download from its first bytes.
+fn detect_payload_format(prefix: &[u8]) -> Result<PayloadFormat> {
+    if prefix.starts_with(GZIP_MAGIC) {
+        return Ok(PayloadFormat::Gzip);
+    }
+    if let Some((name, _)) = UNSUPPORTED_MAGIC
+        .iter()
+        .find(|(_, magic)| prefix.starts_with(magic))
+    {
+        return Err(anyhow!(
+            "Unable to unpack Supermaven Agent: unsupported {name} payload"
+        ));
+    }
+    Ok(PayloadFormat::Plain)
+}
+
+/// Reads up to `len` bytes from the start of `reader`, fewer only if it ends first.
+async fn read_prefix(reader: &mut (impl AsyncRead + Unpin), len: usize) -> Result<Vec<u8>> {
+    let mut prefix = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut prefix).await?;
+    Ok(prefix)
+}
+
+/// Decodes a downloaded agent payload into the agent binary at `dest`, returning `dest`.
+/// The payload may be gzip-compressed, and may be either the bare binary or a tar archive
+/// containing it.
+pub async fn unpack_agent(
+    mut payload: impl AsyncRead + Unpin + Send,
+    dest: &Path,
+) -> Result<PathBuf> {
+    let prefix = read_prefix(&mut payload, MAGIC_PREFIX_LEN).await?;
+    let payload = prefix.as_slice().chain(payload);
+    let mut decoded: Box<dyn AsyncRead + Unpin + Send + '_> = match detect_payload_format(&prefix)?
+    {
+        PayloadFormat::Plain => Box::new(payload),
+        PayloadFormat::Gzip => Box::new(GzipDecoder::new(BufReader::new(payload))),
+    };
+
+    let header = read_prefix(&mut decoded, TAR_MAGIC_OFFSET + TAR_MAGIC.len())
+        .await
+        .with_context(|| "Unable to unpack Supermaven Agent".to_string())?;
+    let decoded = header.as_slice().chain(decoded);
+    if header.get(TAR_MAGIC_OFFSET..) == Some(TAR_MAGIC) {
+        let mut entries = async_tar::Archive::new(decoded).entries()?;
+        while let Some(entry) = entries.next().await {
+            let mut entry =
+                entry.with_context(|| "Unable to unpack Supermaven Agent".to_string())?;
+            let is_agent = entry
+                .path()?
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| AGENT_BINARY_NAMES.contains(&name));
+            if is_agent {
+                entry
+                    .unpack(dest)
+                    .await
+                    .with_context(|| format!("Unable to unpack Supermaven Agent to {:?}", dest))?;
+                return Ok(dest.to_path_buf());
+            }
+        }
+        return Err(anyhow!(
+            "Unable to unpack Supermaven Agent: archive contains no sm-agent binary"
+        ));
+    }
+
+    let mut file = File::create(dest)
+        .await
+        .with_context(|| format!("Unable to create file at {:?}", dest))?;
+    futures::io::copy(decoded, &mut file)
+        .await
+        .with_context(|| format!("Unable to unpack Supermaven Agent to {:?}", dest))?;
+    file.flush().await?;
+    Ok(dest.to_path_buf())
+}
+
+/// Re-hashes an installed agent binary and checks it against `expected_sha256`, without
+/// touching the network. A mismatch yields `Ok(false)` so that callers can download the
+/// binary again; only failing to read the file is an error.
+pub async fn verify_installed_binary(path: &Path, expected_sha256: &str) -> Result<bool> {
+    let mut hasher = Sha256::new();
+    hash_file(path, &mut hasher).await?;
+    Ok(verify_sha256(expected_sha256, &format!("{:x}", hasher.finalize())).is_ok())
+}
+
+async fn hash_file(path: &Path, hasher: &mut Sha256) -> Result<()> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("Unable to open file at {:?}", path))?;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let bytes_read = file.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(())
+}
+
+fn verify_sha256(expected: &str, actual: &str) -> Result<()> {
+    if expected.eq_ignore_ascii_case(actual) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "sha256 mismatch: expected {expected}, got {actual}"
+        ))
+    }
+}
+
+pub fn get_supermaven_agent_path(
+    client: Arc<dyn HttpClient>,
+) -> impl Future<Output = Result<PathBuf>> {
+    async move {
+        fs::create_dir_all(&*SUPERMAVEN_DIR)
+            .await
+            .with_context(|| {
+                format!(
+                    "Could not create Supermaven Agent Directory at {:?}",
+                    &*SUPERMAVEN_DIR
+                )
+            })?;
+
+        let (platform, arch) = current_platform_arch()?;
+
+        let binary_path = ensure_binary(client, platform, arch, &SUPERMAVEN_DIR).await?;
+        let sidecar_path = sidecar_path(&binary_path);
+
+        let mut old_binary_paths = fs::read_dir(&*SUPERMAVEN_DIR).await?;
+        while let Some(old_binary_path) = old_binary_paths.next().await {
             let old_binary_path = old_binary_path?;
-            if old_binary_path.path() != binary_path {
+            if old_binary_path.path() != binary_path && old_binary_path.path() != sidecar_path {
                 fs::remove_file(old_binary_path.path()).await?;
             }
         }
@@ -289,3 +1706,1911 @@ pub fn get_supermaven_agent_path(
         Ok(binary_path)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Mutex};
+    use util::http::FakeHttpClient;
+
+    /// An in-memory [`AdminApi`] for exercising code that depends on the admin API.
+    #[derive(Default)]
+    struct MockAdminApi {
+        users: Mutex<HashMap<String, SupermavenUser>>,
+    }
+
+    #[async_trait]
+    impl AdminApi for MockAdminApi {
+        async fn try_get_user(
+            &self,
+            request: GetExternalUserRequest,
+        ) -> Result<Option<SupermavenUser>> {
+            Ok(self.users.lock().unwrap().get(&request.id).cloned())
+        }
+
+        async fn try_create_user(
+            &self,
+            request: CreateExternalUserRequest,
+        ) -> Result<CreateExternalUserResponse> {
+            let mut users = self.users.lock().unwrap();
+            if users.contains_key(&request.id) {
+                return Err(SupermavenApiError::new(
+                    AdminApiErrorKind::Conflict,
+                    StatusCode::CONFLICT,
+                    format!("user {} already exists", request.id),
+                )
+                .into());
+            }
+            let api_key = format!("key-{}", request.id);
+            users.insert(
+                request.id.clone(),
+                SupermavenUser {
+                    id: request.id,
+                    email: request.email,
+                    api_key: api_key.clone(),
+                },
+            );
+            Ok(CreateExternalUserResponse { api_key })
+        }
+
+        async fn try_delete_user(&self, request: DeleteExternalUserRequest) -> Result<()> {
+            self.users.lock().unwrap().remove(&request.id);
+            Ok(())
+        }
+    }
+
+    fn recording_client(
+        status: u16,
+        body: &'static str,
+    ) -> (Arc<dyn HttpClient>, Arc<Mutex<Vec<HttpRequest<AsyncBody>>>>) {
+        scripted_client(vec![Some((status, body))])
+    }
+
+    /// Returns a client that answers the nth request with the nth response, repeating the
+    /// last one once the script runs out. `None` simulates a transport failure.
+    fn scripted_client(
+        responses: Vec<Option<(u16, &'static str)>>,
+    ) -> (Arc<dyn HttpClient>, Arc<Mutex<Vec<HttpRequest<AsyncBody>>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let client = FakeHttpClient::create({
+            let requests = requests.clone();
+            move |request| {
+                let mut requests = requests.lock().unwrap();
+                let response = responses[requests.len().min(responses.len() - 1)];
+                requests.push(request);
+                async move {
+                    match response {
+                        Some((status, body)) => Ok(Response::builder()
+                            .status(status)
+                            .header("Content-Type", "application/json")
+                            .body(AsyncBody::from(body))
+                            .unwrap()),
+                        None => Err(util::http::Error::from(std::io::Error::new(
+                            std::io::ErrorKind::ConnectionReset,
+                            "connection reset",
+                        ))),
+                    }
+                }
+            }
+        });
+        (client, requests)
+    }
+
+    fn fake_agent_client(
+        version: u64,
+        binary: Vec<u8>,
+        sha256_hash: String,
+    ) -> Arc<dyn HttpClient> {
+        FakeHttpClient::create(move |request| {
+            let (status, body) = if request.uri().path() == "/api/download-path" {
+                let body = serde_json::json!({
+                    "downloadUrl": format!("https://example.com/sm-agent/{version}"),
+                    "version": version,
+                    "sha256Hash": sha256_hash,
+                });
+                (200, body.to_string().into_bytes())
+            } else if let Some(offset) = range_start(&request) {
+                (206, binary[offset..].to_vec())
+            } else {
+                (200, binary.clone())
+            };
+            async move {
+                Ok(Response::builder()
+                    .status(status)
+                    .header("Content-Length", body.len())
+                    .body(AsyncBody::from(body))
+                    .unwrap())
+            }
+        })
+    }
+
+    fn range_start(request: &HttpRequest<AsyncBody>) -> Option<usize> {
+        request
+            .headers()
+            .get("Range")?
+            .to_str()
+            .ok()?
+            .strip_prefix("bytes=")?
+            .strip_suffix('-')?
+            .parse()
+            .ok()
+    }
+
+    const ADMIN_API_KEY: &str = "5f0c9e2a-7b41-4d3e-9a86-1c2b3d4e5f60";
+
+    #[test]
+    fn test_admin_api_base_url() {
+        const USER: &str = r#"{"id": "1", "email": "user@example.com", "apiKey": "key"}"#;
+
+        for api_url in ["http://localhost:8080/api", "http://localhost:8080/api/"] {
+            let (client, requests) = recording_client(200, USER);
+            let api =
+                SupermavenAdminApi::with_base_url(ADMIN_API_KEY, api_url.into(), client).unwrap();
+
+            smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() })).unwrap();
+
+            let requests = requests.lock().unwrap();
+            assert_eq!(
+                requests[0].uri().to_string(),
+                "http://localhost:8080/api/external-user/1"
+            );
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        let (client, requests) = recording_client(500, r#"{"message": "Database unavailable"}"#);
+        let api = SupermavenAdminApi::builder()
+            .admin_api_key(ADMIN_API_KEY)
+            .base_url("http://localhost:3000/api")
+            .http_client(client)
+            .timeout(Duration::from_secs(1))
+            .max_retries(1)
+            .retry_base_delay(Duration::ZERO)
+            .extra_headers([("Proxy-Authorization", "Basic cHJveHk=")])
+            .build()
+            .unwrap();
+        assert_eq!(api.timeout, Duration::from_secs(1));
+
+        smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() })).unwrap_err();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].uri().to_string(),
+            "http://localhost:3000/api/external-user/1"
+        );
+        assert_eq!(requests[0].headers()["Authorization"], ADMIN_API_KEY);
+        assert_eq!(
+            requests[0].headers()["Proxy-Authorization"],
+            "Basic cHJveHk="
+        );
+    }
+
+    #[test]
+    fn test_builder_requires_key_and_client() {
+        let (client, _) = recording_client(200, "{}");
+        let error = SupermavenAdminApi::builder()
+            .http_client(client)
+            .build()
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Supermaven admin API key is required");
+
+        let error = SupermavenAdminApi::builder()
+            .admin_api_key(ADMIN_API_KEY)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Supermaven admin API HTTP client is required"
+        );
+
+        let (client, _) = recording_client(200, "{}");
+        let api = SupermavenAdminApi::builder()
+            .admin_api_key(ADMIN_API_KEY)
+            .http_client(client)
+            .build()
+            .unwrap();
+        assert_eq!(api.api_url, SUPERMAVEN_API_URL);
+        assert_eq!(api.max_retries, DEFAULT_MAX_RETRIES);
+    }
+
+    #[test]
+    fn test_from_env() {
+        fn env<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+            move |key| {
+                vars.iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, value)| value.to_string())
+            }
+        }
+
+        let (client, requests) = recording_client(200, "{}");
+        let error = SupermavenAdminApi::from_env_with(client.clone(), env(&[])).unwrap_err();
+        assert_eq!(error.to_string(), "SUPERMAVEN_ADMIN_API_KEY is not set");
+
+        let vars = [(ADMIN_API_KEY_ENV_VAR, " \n")];
+        assert!(SupermavenAdminApi::from_env_with(client.clone(), env(&vars)).is_err());
+
+        let vars = [(ADMIN_API_KEY_ENV_VAR, "admin key")];
+        let error = SupermavenAdminApi::from_env_with(client.clone(), env(&vars)).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<InvalidAdminApiKey>(),
+            Some(&InvalidAdminApiKey::ContainsWhitespace)
+        );
+
+        let admin_api_key = format!("{ADMIN_API_KEY}\n");
+        let vars = [(ADMIN_API_KEY_ENV_VAR, admin_api_key.as_str())];
+        let api = SupermavenAdminApi::from_env_with(client.clone(), env(&vars)).unwrap();
+        assert_eq!(api.api_url, SUPERMAVEN_API_URL);
+
+        let vars = [
+            (ADMIN_API_KEY_ENV_VAR, admin_api_key.as_str()),
+            (API_URL_ENV_VAR, "http://localhost:3000/api "),
+        ];
+        let api = SupermavenAdminApi::from_env_with(client, env(&vars)).unwrap();
+        smol::block_on(api.try_delete_user(DeleteExternalUserRequest { id: "1".into() })).unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            requests[0].uri().to_string(),
+            "http://localhost:3000/api/external-user/1"
+        );
+        assert_eq!(requests[0].headers()["Authorization"], ADMIN_API_KEY);
+    }
+
+    #[test]
+    fn test_admin_api_key_is_validated() {
+        let (client, requests) = recording_client(200, "{}");
+
+        assert_eq!(
+            SupermavenAdminApi::new("", client.clone()).unwrap_err(),
+            InvalidAdminApiKey::Empty
+        );
+        assert_eq!(
+            SupermavenAdminApi::new(format!(" {ADMIN_API_KEY}"), client.clone()).unwrap_err(),
+            InvalidAdminApiKey::ContainsWhitespace
+        );
+        assert_eq!(
+            SupermavenAdminApi::new("admin-key", client.clone()).unwrap_err(),
+            InvalidAdminApiKey::WrongLength { len: 9 }
+        );
+        assert_eq!(
+            SupermavenAdminApi::new("a".repeat(MAX_ADMIN_API_KEY_LEN + 1), client.clone())
+                .unwrap_err(),
+            InvalidAdminApiKey::WrongLength {
+                len: MAX_ADMIN_API_KEY_LEN + 1
+            }
+        );
+
+        let api = SupermavenAdminApi::new(ADMIN_API_KEY, client.clone()).unwrap();
+        smol::block_on(api.try_delete_user(DeleteExternalUserRequest { id: "1".into() })).unwrap();
+        assert_eq!(
+            requests.lock().unwrap()[0].headers()["Authorization"],
+            ADMIN_API_KEY
+        );
+
+        let api = SupermavenAdminApi::new_unchecked("", client);
+        assert_eq!(api.admin_api_key.expose(), "");
+    }
+
+    #[test]
+    fn test_admin_api_is_send_sync() {
+        fn _assert_send_sync<T: Send + Sync>() {}
+        _assert_send_sync::<SupermavenAdminApi>();
+        _assert_send_sync::<Arc<SupermavenAdminApi>>();
+    }
+
+    #[test]
+    fn test_admin_api_key_is_redacted() {
+        let (client, requests) = recording_client(200, r#"{"message": "User not found"}"#);
+        let api = SupermavenAdminApi::new_unchecked("super-secret-key".to_string(), client);
+
+        let debug = format!("{:?}", api);
+        assert!(!debug.contains("super-secret-key"), "{debug}");
+        assert!(debug.contains("***"), "{debug}");
+        assert_eq!(SecretString::from("super-secret-key").to_string(), "***");
+
+        smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() })).ok();
+        assert_eq!(
+            requests.lock().unwrap()[0].headers()["Authorization"],
+            "super-secret-key"
+        );
+    }
+
+    #[test]
+    fn test_try_get_user() {
+        let (client, requests) = recording_client(
+            200,
+            r#"{"id": "1", "email": "user@example.com", "apiKey": "key"}"#,
+        );
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+
+        let user =
+            smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() })).unwrap();
+        assert_eq!(
+            user,
+            Some(SupermavenUser {
+                id: "1".into(),
+                email: "user@example.com".into(),
+                api_key: "key".into(),
+            })
+        );
+        assert_eq!(
+            requests.lock().unwrap()[0].headers()["Authorization"],
+            "admin-key"
+        );
+    }
+
+    #[test]
+    fn test_proxy_headers_are_preserved() {
+        let (client, requests) = scripted_client(vec![
+            Some((
+                200,
+                r#"{"id": "1", "email": "user@example.com", "apiKey": "key"}"#,
+            )),
+            Some((200, r#"{"apiKey": "key"}"#)),
+        ]);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+            .with_header("Proxy-Authorization", "Basic cHJveHk6c2VjcmV0")
+            .with_header("Authorization", "overridden");
+
+        smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() })).unwrap();
+        smol::block_on(api.try_create_user(CreateExternalUserRequest {
+            id: "1".into(),
+            email: "user@example.com".into(),
+        }))
+        .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests.iter() {
+            let headers = request.headers();
+            assert_eq!(
+                headers.get_all("Authorization").iter().collect::<Vec<_>>(),
+                ["admin-key"]
+            );
+            assert_eq!(headers["Proxy-Authorization"], "Basic cHJveHk6c2VjcmV0");
+        }
+    }
+
+    #[test]
+    fn test_non_json_response() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let client = FakeHttpClient::create({
+            let requests = requests.clone();
+            move |request| {
+                requests.lock().unwrap().push(request);
+                async move {
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "text/html; charset=utf-8")
+                        .body(AsyncBody::from("<html>Sign in to continue</html>"))
+                        .unwrap())
+                }
+            }
+        });
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+
+        let error = smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() }))
+            .unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            "Supermaven API error (200 OK): expected a JSON response, got text/html; charset=utf-8"
+        );
+        assert_eq!(classify_error(&error), Some(AdminApiErrorKind::Parse));
+
+        let error = smol::block_on(api.try_create_user(CreateExternalUserRequest {
+            id: "1".into(),
+            email: "user@example.com".into(),
+        }))
+        .unwrap_err();
+        assert_eq!(classify_error(&error), Some(AdminApiErrorKind::Parse));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests.iter() {
+            assert_eq!(request.headers()["Accept"], "application/json");
+        }
+    }
+
+    #[test]
+    fn test_is_json() {
+        assert!(is_json("application/json"));
+        assert!(is_json("Application/JSON; charset=utf-8"));
+        assert!(is_json("application/problem+json"));
+        assert!(!is_json("text/html"));
+        assert!(!is_json("text/plain; charset=utf-8"));
+    }
+
+    #[test]
+    fn test_retry_truncated_body() {
+        let user = r#"{"id": "1", "email": "user@example.com", "apiKey": "key"}"#;
+        let (client, requests) =
+            scripted_client(vec![Some((200, r#"{"id": "1", "em"#)), Some((200, user))]);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+            .with_retry_policy(0, Duration::ZERO);
+
+        let user =
+            smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() })).unwrap();
+        assert_eq!(user.unwrap().api_key, "key");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let (client, requests) = scripted_client(vec![Some((200, r#"{"apiKey": "#))]);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+            .with_retry_policy(0, Duration::ZERO);
+
+        let error = smol::block_on(api.try_create_user(CreateExternalUserRequest {
+            id: "1".into(),
+            email: "user@example.com".into(),
+        }))
+        .unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            "Supermaven API error (200 OK): response body ended unexpectedly, the connection may have been reset"
+        );
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        // Malformed but complete JSON isn't retried.
+        let (client, requests) = scripted_client(vec![Some((200, r#"{"apiKey": 1}"#))]);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        smol::block_on(api.try_create_user(CreateExternalUserRequest {
+            id: "1".into(),
+            email: "user@example.com".into(),
+        }))
+        .unwrap_err();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_user_api_key() {
+        let user = serde_json::from_str::<SupermavenUser>(
+            r#"{"id": "1", "email": "user@example.com", "apiKey": "key"}"#,
+        )
+        .unwrap();
+        assert_eq!(user.api_key(), "key");
+        assert_eq!(Some(&user).map(SupermavenUser::api_key), Some("key"));
+        assert_eq!(None.map(SupermavenUser::api_key), None);
+
+        // The not-found body can't be mistaken for a user.
+        assert!(
+            serde_json::from_str::<SupermavenUser>(r#"{"message": "User not found"}"#).is_err()
+        );
+
+        // The server uses camelCase, so a snake_case key isn't recognized.
+        assert!(serde_json::from_str::<SupermavenUser>(
+            r#"{"id": "1", "email": "user@example.com", "api_key": "key"}"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_try_get_user_not_found() {
+        let (client, _) = recording_client(404, r#"{"message": "User not found"}"#);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+
+        let user =
+            smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() })).unwrap();
+        assert!(user.is_none());
+    }
+
+    #[test]
+    fn test_try_get_user_client_error() {
+        let (client, _) = recording_client(401, r#"{"message": "Invalid admin API key"}"#);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+
+        let error = smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() }))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Supermaven API error (401 Unauthorized): Invalid admin API key"
+        );
+
+        let (client, _) = recording_client(401, "Unauthorized");
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+
+        let error = smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() }))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Supermaven API error (401 Unauthorized): Unauthorized"
+        );
+    }
+
+    #[test]
+    fn test_try_create_user_sends_json() {
+        let (client, requests) = recording_client(200, r#"{"apiKey": "key"}"#);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+
+        let response = smol::block_on(api.try_create_user(CreateExternalUserRequest {
+            id: "1".into(),
+            email: "user@example.com".into(),
+        }))
+        .unwrap();
+        assert_eq!(response.api_key, "key");
+
+        let mut requests = requests.lock().unwrap();
+        let request = &mut requests[0];
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.headers()["Content-Type"], "application/json");
+        assert_eq!(request.headers()["Authorization"], "admin-key");
+
+        let mut body = Vec::new();
+        smol::block_on(request.body_mut().read_to_end(&mut body)).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({"id": "1", "email": "user@example.com"})
+        );
+    }
+
+    #[test]
+    fn test_try_create_user_error() {
+        let request = || CreateExternalUserRequest {
+            id: "1".into(),
+            email: "user@example.com".into(),
+        };
+
+        let (client, _) = recording_client(409, r#"{"message": "User already exists"}"#);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        let error = smol::block_on(api.try_create_user(request())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Supermaven API error (409 Conflict): User already exists"
+        );
+
+        let (client, _) = recording_client(500, r#"{"message": "Database unavailable"}"#);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+            .with_retry_policy(0, Duration::ZERO);
+        let error = smol::block_on(api.try_create_user(request())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Supermaven API error (500 Internal Server Error): Database unavailable"
+        );
+    }
+
+    #[test]
+    fn test_try_delete_user() {
+        let request = || DeleteExternalUserRequest { id: "1".into() };
+
+        let (client, requests) = recording_client(200, "{}");
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        smol::block_on(api.try_delete_user(request())).unwrap();
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests[0].method().as_str(), "DELETE");
+            assert_eq!(
+                requests[0].uri().to_string(),
+                "https://supermaven.com/api/external-user/1"
+            );
+            assert_eq!(requests[0].headers()["Authorization"], "admin-key");
+        }
+
+        let (client, _) = recording_client(404, r#"{"message": "User not found"}"#);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        smol::block_on(api.try_delete_user(request())).unwrap();
+
+        let (client, _) = recording_client(403, r#"{"message": "Forbidden"}"#);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        let error = smol::block_on(api.try_delete_user(request())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Supermaven API error (403 Forbidden): Forbidden"
+        );
+    }
+
+    #[test]
+    fn test_list_users() {
+        let (client, requests) = scripted_client(vec![
+            Some((
+                200,
+                r#"{"users": [{"id": "1", "email": "a@example.com", "apiKey": "a"}], "nextCursor": "page 2"}"#,
+            )),
+            Some((
+                200,
+                r#"{"users": [{"id": "2", "email": "b@example.com", "apiKey": "b"}]}"#,
+            )),
+        ]);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+
+        let users = smol::block_on(api.list_all_users()).unwrap();
+        assert_eq!(
+            users
+                .iter()
+                .map(|user| user.id.as_str())
+                .collect::<Vec<_>>(),
+            ["1", "2"]
+        );
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].uri().to_string(),
+            "https://supermaven.com/api/external-user"
+        );
+        assert_eq!(
+            requests[1].uri().to_string(),
+            "https://supermaven.com/api/external-user?cursor=page+2"
+        );
+        assert_eq!(requests[1].headers()["Authorization"], "admin-key");
+    }
+
+    #[test]
+    fn test_list_users_empty() {
+        let (client, requests) = recording_client(200, r#"{"users": []}"#);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+
+        let users = smol::block_on(api.list_all_users()).unwrap();
+        assert!(users.is_empty());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        let (client, _) = recording_client(200, r#"{"users": [], "nextCursor": "same"}"#);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        smol::block_on(api.list_all_users()).unwrap_err();
+    }
+
+    #[test]
+    fn test_retry_server_and_transport_errors() {
+        const USER: &str = r#"{"id": "1", "email": "user@example.com", "apiKey": "key"}"#;
+        let (client, requests) = scripted_client(vec![
+            None,
+            Some((503, r#"{"message": "Service unavailable"}"#)),
+            Some((200, USER)),
+        ]);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+            .with_retry_policy(3, Duration::ZERO);
+
+        let user =
+            smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() })).unwrap();
+        assert!(user.is_some());
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_retry_gives_up_after_max_retries() {
+        let (client, requests) =
+            scripted_client(vec![Some((500, r#"{"message": "Internal error"}"#))]);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+            .with_retry_policy(2, Duration::ZERO);
+
+        let error = smol::block_on(api.try_create_user(CreateExternalUserRequest {
+            id: "1".into(),
+            email: "user@example.com".into(),
+        }))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Supermaven API error (500 Internal Server Error): Internal error"
+        );
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_no_retry_on_client_error() {
+        let (client, requests) = recording_client(401, r#"{"message": "Unauthorized"}"#);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+            .with_retry_policy(3, Duration::ZERO);
+
+        smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() })).unwrap_err();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let client: Arc<dyn HttpClient> = FakeHttpClient::create(|_| futures::future::pending());
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+            .with_retry_policy(0, Duration::ZERO)
+            .with_timeout(Duration::from_millis(10));
+
+        let error = smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() }))
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "Unable to get Supermaven API Key: Supermaven request timed out after 10ms"
+        );
+    }
+
+    #[test]
+    fn test_request_timeout_covers_body() {
+        let client: Arc<dyn HttpClient> = FakeHttpClient::create(|_| async {
+            Ok(Response::builder()
+                .status(200)
+                .header("Content-Type", "application/json")
+                .body(AsyncBody::from_reader(StalledBody(b"{\"id\": ".to_vec())))
+                .unwrap())
+        });
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+            .with_retry_policy(0, Duration::ZERO)
+            .with_timeout(Duration::from_millis(10));
+
+        let error = smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() }))
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "Unable to get Supermaven API Key: Supermaven request timed out after 10ms"
+        );
+    }
+
+    #[test]
+    fn test_try_get_or_create_user() {
+        const USER: &str = r#"{"id": "1", "email": "user@example.com", "apiKey": "existing-key"}"#;
+        const NOT_FOUND: &str = r#"{"message": "User not found"}"#;
+        let request = || CreateExternalUserRequest {
+            id: "1".into(),
+            email: "user@example.com".into(),
+        };
+
+        // The user already exists.
+        let (client, requests) = scripted_client(vec![Some((200, USER))]);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        let response = smol::block_on(api.try_get_or_create_user(request())).unwrap();
+        assert_eq!(response.api_key, "existing-key");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // The user doesn't exist yet and gets created.
+        let (client, requests) = scripted_client(vec![
+            Some((404, NOT_FOUND)),
+            Some((200, r#"{"apiKey": "new-key"}"#)),
+        ]);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        let response = smol::block_on(api.try_get_or_create_user(request())).unwrap();
+        assert_eq!(response.api_key, "new-key");
+        assert_eq!(requests.lock().unwrap()[1].method().as_str(), "POST");
+
+        // A concurrent request created the user after our lookup.
+        let (client, requests) = scripted_client(vec![
+            Some((404, NOT_FOUND)),
+            Some((409, r#"{"message": "User already exists"}"#)),
+            Some((200, USER)),
+        ]);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        let response = smol::block_on(api.try_get_or_create_user(request())).unwrap();
+        assert_eq!(response.api_key, "existing-key");
+        assert_eq!(requests.lock().unwrap().len(), 3);
+
+        // Other create failures are returned without looking the user up again.
+        for (status, body) in [
+            (401, r#"{"message": "Invalid admin API key"}"#),
+            (500, r#"{"message": "Database unavailable"}"#),
+            (400, r#"{"message": "Invalid email"}"#),
+        ] {
+            let (client, requests) =
+                scripted_client(vec![Some((404, NOT_FOUND)), Some((status, body))]);
+            let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+                .with_retry_policy(0, Duration::ZERO);
+            let error = smol::block_on(api.try_get_or_create_user(request())).unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<SupermavenApiError>().unwrap().status,
+                Some(status)
+            );
+            assert_eq!(requests.lock().unwrap().len(), 2);
+        }
+
+        // If the conflicting user can't be found after all, the conflict is returned.
+        let (client, _) = scripted_client(vec![
+            Some((404, NOT_FOUND)),
+            Some((409, r#"{"message": "User already exists"}"#)),
+            Some((404, NOT_FOUND)),
+        ]);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        let error = smol::block_on(api.try_get_or_create_user(request())).unwrap_err();
+        assert_eq!(classify_error(&error), Some(AdminApiErrorKind::Conflict));
+    }
+
+    #[test]
+    fn test_mock_admin_api() {
+        let api: Arc<dyn AdminApi> = Arc::new(MockAdminApi::default());
+        let request = || CreateExternalUserRequest {
+            id: "1".into(),
+            email: "user@example.com".into(),
+        };
+
+        let created = smol::block_on(api.try_get_or_create_user(request())).unwrap();
+        assert_eq!(
+            created,
+            CreateExternalUserResponse {
+                api_key: "key-1".into()
+            }
+        );
+        let fetched = smol::block_on(api.try_get_or_create_user(request())).unwrap();
+        assert_eq!(fetched, created);
+        let error = smol::block_on(api.try_create_user(request())).unwrap_err();
+        assert_eq!(classify_error(&error), Some(AdminApiErrorKind::Conflict));
+
+        smol::block_on(api.try_delete_user(DeleteExternalUserRequest { id: "1".into() })).unwrap();
+        let user =
+            smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() })).unwrap();
+        assert!(user.is_none());
+    }
+
+    #[test]
+    fn test_try_create_user_invalid_email() {
+        for email in [
+            "userexample.com",
+            "@example.com",
+            "user@",
+            "user@@example.com",
+            "",
+        ] {
+            let (client, requests) = recording_client(200, r#"{"apiKey": "key"}"#);
+            let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+
+            let error = smol::block_on(api.try_create_user(CreateExternalUserRequest {
+                id: "1".into(),
+                email: email.into(),
+            }))
+            .unwrap_err();
+            assert_eq!(error.to_string(), format!("invalid email: {email}"));
+            assert!(requests.lock().unwrap().is_empty());
+        }
+
+        let (client, requests) = recording_client(200, r#"{"apiKey": "key"}"#);
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        let response = smol::block_on(api.try_create_user(CreateExternalUserRequest {
+            id: "1".into(),
+            email: "first.last+zed@example.co.uk".into(),
+        }))
+        .unwrap();
+        assert_eq!(response.api_key, "key");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_download_response() {
+        let response = serde_json::from_str::<SupermavenDownloadResponse>(
+            r#"{
+                "downloadUrl": "https://supermaven-public.s3.amazonaws.com/sm-agent/26/linux/amd64/sm-agent",
+                "version": 26,
+                "sha256Hash": "8b70a2a1a1ed6dbf0c7ef4a3c1a1a8c5f1e2a19a0f5c3b1c83dc675a0b0c2c7a"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            response.download_url,
+            "https://supermaven-public.s3.amazonaws.com/sm-agent/26/linux/amd64/sm-agent"
+        );
+        assert_eq!(response.version, 26);
+        assert_eq!(
+            response.sha256_hash,
+            "8b70a2a1a1ed6dbf0c7ef4a3c1a1a8c5f1e2a19a0f5c3b1c83dc675a0b0c2c7a"
+        );
+    }
+
+    #[test]
+    fn test_latest_release() {
+        let (client, requests) = recording_client(
+            200,
+            r#"{
+                "downloadUrl": "https://supermaven-public.s3.amazonaws.com/sm-agent/26/darwin/arm64/sm-agent",
+                "version": 26,
+                "sha256Hash": "8b70a2a1a1ed6dbf0c7ef4a3c1a1a8c5f1e2a19a0f5c3b1c83dc675a0b0c2c7a"
+            }"#,
+        );
+
+        let release =
+            smol::block_on(latest_release(client, Platform::Darwin, Arch::Arm64)).unwrap();
+        assert_eq!(release.version, 26);
+        assert_eq!(
+            release.download_url,
+            "https://supermaven-public.s3.amazonaws.com/sm-agent/26/darwin/arm64/sm-agent"
+        );
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].uri().to_string(),
+            "https://supermaven.com/api/download-path?platform=darwin&arch=arm64"
+        );
+    }
+
+    #[test]
+    fn test_supermaven_api_error() {
+        let mut error =
+            serde_json::from_str::<SupermavenApiError>(r#"{"message": "Rate limited"}"#).unwrap();
+        assert_eq!(error.status, None);
+        assert_eq!(error.to_string(), "Supermaven API error: Rate limited");
+
+        error.status = Some(429);
+        assert_eq!(
+            error.to_string(),
+            "Supermaven API error (429 Too Many Requests): Rate limited"
+        );
+        error.status = Some(999);
+        assert_eq!(
+            error.to_string(),
+            "Supermaven API error (999): Rate limited"
+        );
+
+        let error = anyhow::Error::from(SupermavenApiError {
+            message: "Rate limited".into(),
+            status: Some(429),
+            kind: None,
+        });
+        assert_eq!(
+            error.downcast_ref::<SupermavenApiError>(),
+            Some(&SupermavenApiError {
+                message: "Rate limited".into(),
+                status: Some(429),
+                kind: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_latest_release_unavailable() {
+        for (status, body) in [
+            (404, r#"{"message": "Not found"}"#),
+            (404, ""),
+            (200, ""),
+            (200, "  \n"),
+            (200, "null"),
+            (200, "{}"),
+            (
+                200,
+                r#"{"downloadUrl": "", "version": 26, "sha256Hash": ""}"#,
+            ),
+        ] {
+            let (client, _) = recording_client(status, body);
+            let error =
+                smol::block_on(latest_release(client, Platform::Linux, Arch::Arm64)).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "no Supermaven agent available for linux/arm64",
+                "{status} {body:?}"
+            );
+        }
+
+        let (client, _) = recording_client(200, "{not json");
+        let error =
+            smol::block_on(latest_release(client, Platform::Linux, Arch::Arm64)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unable to parse Supermaven Agent response"
+        );
+
+        let (client, _) = recording_client(500, r#"{"message": "Database unavailable"}"#);
+        let error =
+            smol::block_on(latest_release(client, Platform::Linux, Arch::Arm64)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Supermaven API error (500 Internal Server Error): Database unavailable"
+        );
+        assert_eq!(
+            error.downcast_ref::<SupermavenApiError>().unwrap().status,
+            Some(500)
+        );
+        assert_eq!(classify_error(&error), Some(AdminApiErrorKind::Server));
+    }
+
+    #[test]
+    fn test_platform_arch() {
+        assert_eq!(
+            platform_arch("macos", "aarch64").unwrap(),
+            (Platform::Darwin, Arch::Arm64)
+        );
+        assert_eq!(
+            platform_arch("macos", "x86_64").unwrap(),
+            (Platform::Darwin, Arch::X86_64)
+        );
+        assert_eq!(
+            platform_arch("linux", "x86_64").unwrap(),
+            (Platform::Linux, Arch::X86_64)
+        );
+        assert_eq!(
+            platform_arch("linux", "aarch64").unwrap(),
+            (Platform::Linux, Arch::Arm64)
+        );
+        assert_eq!(
+            platform_arch("windows", "x86_64").unwrap(),
+            (Platform::Windows, Arch::X86_64)
+        );
+        assert!(platform_arch("freebsd", "x86_64").is_err());
+        assert!(platform_arch("linux", "riscv64").is_err());
+    }
+
+    #[test]
+    fn test_platform_arch_tokens() {
+        for platform in [Platform::Darwin, Platform::Linux, Platform::Windows] {
+            assert_eq!(platform.to_string().parse::<Platform>().unwrap(), platform);
+        }
+        for arch in [Arch::Arm64, Arch::X86_64] {
+            assert_eq!(arch.to_string().parse::<Arch>().unwrap(), arch);
+        }
+        assert_eq!(Platform::Darwin.to_string(), "darwin");
+        assert_eq!(Arch::X86_64.to_string(), "amd64");
+
+        assert!("macos".parse::<Platform>().is_err());
+        assert!("Linux".parse::<Platform>().is_err());
+        assert!("x86_64".parse::<Arch>().is_err());
+    }
+
+    #[test]
+    fn test_supported_targets() {
+        assert!(check_supported_target(Platform::Linux, Arch::X86_64).is_ok());
+        assert_eq!(
+            from_platform_string("darwin", "arm64").unwrap(),
+            (Platform::Darwin, Arch::Arm64)
+        );
+
+        assert_eq!(
+            check_supported_target(Platform::Windows, Arch::Arm64)
+                .unwrap_err()
+                .to_string(),
+            "unsupported platform/arch: windows/arm64"
+        );
+        assert!(from_platform_string("windows", "arm64").is_err());
+        assert!(from_platform_string("freebsd", "amd64").is_err());
+        assert!(from_platform_string("linux", "x86_64").is_err());
+
+        let dest_dir = tempfile::tempdir().unwrap();
+        let client: Arc<dyn HttpClient> = FakeHttpClient::create(|request| {
+            futures::future::ready(Err(anyhow!("unexpected request to {}", request.uri())))
+        });
+        let error = smol::block_on(download_binary(
+            client,
+            Platform::Windows,
+            Arch::Arm64,
+            dest_dir.path(),
+        ))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unsupported platform/arch: windows/arm64"
+        );
+    }
+
+    #[test]
+    fn test_check_supported_version() {
+        assert!(check_supported_version(MIN_SUPPORTED_AGENT_VERSION).is_ok());
+        assert!(check_supported_version(MIN_SUPPORTED_AGENT_VERSION + 1).is_ok());
+        assert_eq!(
+            check_supported_version(MIN_SUPPORTED_AGENT_VERSION - 1)
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "Supermaven Agent version {} is older than the minimum supported version {}, \
+                 please update Supermaven",
+                MIN_SUPPORTED_AGENT_VERSION - 1,
+                MIN_SUPPORTED_AGENT_VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn test_download_binary_checks_version() {
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+
+        let dest_dir = tempfile::tempdir().unwrap();
+        let old_version = MIN_SUPPORTED_AGENT_VERSION - 1;
+        let client = fake_agent_client(old_version, binary.clone(), sha256_hash.clone());
+        let error = smol::block_on(download_binary(
+            client.clone(),
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+        ))
+        .unwrap_err();
+        assert!(error.to_string().contains("please update Supermaven"));
+        assert!(smol::block_on(ensure_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path()
+        ))
+        .is_err());
+        assert_eq!(std::fs::read_dir(dest_dir.path()).unwrap().count(), 0);
+
+        for version in [MIN_SUPPORTED_AGENT_VERSION, MIN_SUPPORTED_AGENT_VERSION + 1] {
+            let client = fake_agent_client(version, binary.clone(), sha256_hash.clone());
+            let (downloaded_version, path) = smol::block_on(download_binary(
+                client.clone(),
+                Platform::Linux,
+                Arch::X86_64,
+                dest_dir.path(),
+            ))
+            .unwrap();
+            assert_eq!(downloaded_version, version);
+            assert_eq!(
+                smol::block_on(ensure_binary(
+                    client,
+                    Platform::Linux,
+                    Arch::X86_64,
+                    dest_dir.path()
+                ))
+                .unwrap(),
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_download_binary() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        let client = fake_agent_client(26, binary.clone(), sha256_hash);
+
+        let (version, path) = smol::block_on(download_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+        ))
+        .unwrap();
+
+        assert_eq!(version, 26);
+        assert_eq!(path, dest_dir.path().join("sm-agent-26"));
+        assert_eq!(std::fs::read(&path).unwrap(), binary);
+    }
+
+    /// A response body that yields `data` and then fails, like a connection reset partway
+    /// through a download.
+    struct TruncatedBody(Vec<u8>);
+
+    impl futures::AsyncRead for TruncatedBody {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            if self.0.is_empty() {
+                return std::task::Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "connection reset",
+                )));
+            }
+            let len = buf.len().min(self.0.len());
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0.drain(..len);
+            std::task::Poll::Ready(Ok(len))
+        }
+    }
+
+    /// A response body that yields `data` and then never makes progress again, like a stalled
+    /// connection.
+    struct StalledBody(Vec<u8>);
+
+    impl futures::AsyncRead for StalledBody {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            if self.0.is_empty() {
+                return std::task::Poll::Pending;
+            }
+            let len = buf.len().min(self.0.len());
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0.drain(..len);
+            std::task::Poll::Ready(Ok(len))
+        }
+    }
+
+    fn no_delay(max_retries: u32) -> DownloadRetryPolicy {
+        DownloadRetryPolicy {
+            max_retries,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            idle_timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    #[test]
+    fn test_download_binary_idle_timeout() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        let downloads = Arc::new(Mutex::new(0));
+        let client: Arc<dyn HttpClient> = FakeHttpClient::create({
+            let downloads = downloads.clone();
+            move |request| {
+                let response = if request.uri().path() == "/api/download-path" {
+                    Response::builder().status(200).body(AsyncBody::from(
+                        serde_json::json!({
+                            "downloadUrl": "https://example.com/sm-agent/26",
+                            "version": 26,
+                            "sha256Hash": "0".repeat(64),
+                        })
+                        .to_string(),
+                    ))
+                } else {
+                    *downloads.lock().unwrap() += 1;
+                    Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from_reader(StalledBody(vec![42; 1024])))
+                };
+                async move { Ok(response.unwrap()) }
+            }
+        });
+
+        let policy = DownloadRetryPolicy {
+            idle_timeout: Duration::from_millis(10),
+            ..no_delay(1)
+        };
+        let error = smol::block_on(download_binary_with_retry_policy(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+            policy,
+        ))
+        .unwrap_err();
+        assert!(
+            error
+                .chain()
+                .any(|cause| cause.to_string() == "Supermaven request timed out after 10ms"),
+            "{error:#}"
+        );
+        assert_eq!(*downloads.lock().unwrap(), 2);
+        assert!(!binary_path(dest_dir.path(), 26).exists());
+    }
+
+    #[test]
+    fn test_download_binary_retries_transient_failures() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        let downloads = Arc::new(Mutex::new(Vec::new()));
+        let client: Arc<dyn HttpClient> = FakeHttpClient::create({
+            let binary = binary.clone();
+            let downloads = downloads.clone();
+            move |request| {
+                let response = if request.uri().path() == "/api/download-path" {
+                    Response::builder().status(200).body(AsyncBody::from(
+                        serde_json::json!({
+                            "downloadUrl": "https://example.com/sm-agent/26",
+                            "version": 26,
+                            "sha256Hash": sha256_hash,
+                        })
+                        .to_string(),
+                    ))
+                } else {
+                    let mut downloads = downloads.lock().unwrap();
+                    downloads.push(range_start(&request));
+                    match downloads.len() {
+                        1 => Response::builder().status(503).body(AsyncBody::default()),
+                        2 => Response::builder()
+                            .status(200)
+                            .body(AsyncBody::from_reader(TruncatedBody(binary[..10].to_vec()))),
+                        _ => {
+                            let offset = range_start(&request).unwrap_or(0);
+                            Response::builder()
+                                .status(206)
+                                .body(AsyncBody::from(binary[offset..].to_vec()))
+                        }
+                    }
+                };
+                async move { Ok(response.unwrap()) }
+            }
+        });
+
+        let (_, path) = smol::block_on(download_binary_with_retry_policy(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+            no_delay(3),
+        ))
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), binary);
+        assert_eq!(*downloads.lock().unwrap(), [None, None, Some(10)]);
+    }
+
+    #[test]
+    fn test_download_binary_gives_up_after_max_retries() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        let downloads = Arc::new(Mutex::new(0));
+        let client = |status: u16| -> Arc<dyn HttpClient> {
+            let downloads = downloads.clone();
+            FakeHttpClient::create(move |request| {
+                let (status, body) = if request.uri().path() == "/api/download-path" {
+                    let body = serde_json::json!({
+                        "downloadUrl": "https://example.com/sm-agent/26",
+                        "version": 26,
+                        "sha256Hash": "0",
+                    });
+                    (200, body.to_string())
+                } else {
+                    *downloads.lock().unwrap() += 1;
+                    (status, String::new())
+                };
+                async move {
+                    Ok(Response::builder()
+                        .status(status)
+                        .body(AsyncBody::from(body))
+                        .unwrap())
+                }
+            })
+        };
+
+        let error = smol::block_on(download_binary_with_retry_policy(
+            client(503),
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+            no_delay(2),
+        ))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unable to download Supermaven Agent after 3 attempts"
+        );
+        assert_eq!(
+            error.root_cause().to_string(),
+            "Unable to download Supermaven Agent: status 503 Service Unavailable"
+        );
+        assert_eq!(*downloads.lock().unwrap(), 3);
+
+        *downloads.lock().unwrap() = 0;
+        let error = smol::block_on(download_binary_with_retry_policy(
+            client(404),
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+            no_delay(2),
+        ))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unable to download Supermaven Agent: status 404 Not Found"
+        );
+        assert_eq!(*downloads.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_download_retry_delay_is_capped() {
+        let policy = DownloadRetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            idle_timeout: DEFAULT_TIMEOUT,
+        };
+        assert_eq!(policy.delay(0), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(4));
+        assert_eq!(policy.delay(3), Duration::from_secs(5));
+        assert_eq!(policy.delay(40), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_download_binary_checks_dest_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        let client = fake_agent_client(26, binary.clone(), sha256_hash);
+
+        // Missing directories are created.
+        let dest_dir = dir.path().join("agents").join("supermaven");
+        let (_, path) = smol::block_on(download_binary(
+            client.clone(),
+            Platform::Linux,
+            Arch::X86_64,
+            &dest_dir,
+        ))
+        .unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), binary);
+        assert!(!dest_dir.join(".sm-agent-write-check").exists());
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let dest_dir = file.join("supermaven");
+        let error = smol::block_on(download_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            &dest_dir,
+        ))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Unable to create Supermaven Agent directory {:?}", dest_dir)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_download_binary_read_only_dest_dir() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dest_dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        let client = fake_agent_client(26, binary, sha256_hash);
+        std::fs::set_permissions(dest_dir.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+        // Permissions don't restrict privileged users, e.g. when running as root.
+        if std::fs::write(dest_dir.path().join("probe"), b"").is_ok() {
+            return;
+        }
+
+        let error = smol::block_on(download_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+        ))
+        .unwrap_err();
+        std::fs::set_permissions(dest_dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Supermaven Agent directory {:?} is not writable",
+                dest_dir.path()
+            )
+        );
+    }
+
+    #[test]
+    fn test_download_binary_gzip() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        let client = fake_agent_client(26, gzip(&binary), sha256_hash);
+
+        let (_, path) = smol::block_on(download_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+        ))
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), binary);
+    }
+
+    #[test]
+    fn test_download_binary_unsupported_format() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        let archive = b"PK\x03\x04sm-agent".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&archive));
+        let client = fake_agent_client(26, archive, sha256_hash);
+
+        let error = smol::block_on(download_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+        ))
+        .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Unable to unpack Supermaven Agent: unsupported zip payload"
+        );
+        assert!(!dest_dir.path().join("sm-agent-26").exists());
+    }
+
+    #[test]
+    fn test_detect_payload_format() {
+        let elf = b"\x7fELF\x02\x01";
+        assert_eq!(detect_payload_format(elf).unwrap(), PayloadFormat::Plain);
+        assert_eq!(
+            detect_payload_format(&[0x1f, 0x8b, 0x08, 0x00]).unwrap(),
+            PayloadFormat::Gzip
+        );
+        assert_eq!(
+            detect_payload_format(&[0x28, 0xb5, 0x2f, 0xfd, 0x00])
+                .unwrap_err()
+                .to_string(),
+            "Unable to unpack Supermaven Agent: unsupported zstd payload"
+        );
+
+        assert!(check_content_encoding(None).is_ok());
+        assert!(check_content_encoding(Some("identity")).is_ok());
+        assert!(check_content_encoding(Some("GZIP")).is_ok());
+        assert_eq!(
+            check_content_encoding(Some("br")).unwrap_err().to_string(),
+            "Unable to unpack Supermaven Agent: unsupported Content-Encoding \"br\""
+        );
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        use async_compression::futures::bufread::GzipEncoder;
+
+        let mut compressed = Vec::new();
+        smol::block_on(GzipEncoder::new(bytes).read_to_end(&mut compressed)).unwrap();
+        compressed
+    }
+
+    fn tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        smol::block_on(async {
+            let mut builder = async_tar::Builder::new(Vec::new());
+            for (path, contents) in entries {
+                let mut header = async_tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o755);
+                header.set_cksum();
+                builder
+                    .append_data(&mut header, path, *contents)
+                    .await
+                    .unwrap();
+            }
+            builder.into_inner().await.unwrap()
+        })
+    }
+
+    #[test]
+    fn test_unpack_agent() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("sm-agent-26");
+        let binary = b"#!/bin/sh\necho sm-agent\n";
+
+        for payload in [binary.to_vec(), gzip(binary)] {
+            let path = smol::block_on(unpack_agent(payload.as_slice(), &dest)).unwrap();
+            assert_eq!(path, dest);
+            assert_eq!(std::fs::read(&dest).unwrap(), binary);
+            std::fs::remove_file(&dest).unwrap();
+        }
+
+        let archive = tar(&[("README.md", b"docs"), ("bin/sm-agent", binary)]);
+        for payload in [archive.clone(), gzip(&archive)] {
+            smol::block_on(unpack_agent(payload.as_slice(), &dest)).unwrap();
+            assert_eq!(std::fs::read(&dest).unwrap(), binary);
+            std::fs::remove_file(&dest).unwrap();
+        }
+
+        let archive = tar(&[("README.md", b"docs")]);
+        let error = smol::block_on(unpack_agent(gzip(&archive).as_slice(), &dest)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unable to unpack Supermaven Agent: archive contains no sm-agent binary"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_download_binary_is_executable() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dest_dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        let client = fake_agent_client(26, binary, sha256_hash);
+
+        let (_, path) = smol::block_on(download_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+        ))
+        .unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o111, 0o111);
+    }
+
+    #[test]
+    fn test_download_binary_sha256_mismatch() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        let mut corrupted = binary.clone();
+        corrupted[0] ^= 0xff;
+        let client = fake_agent_client(26, corrupted, sha256_hash.clone());
+
+        let error = smol::block_on(download_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+        ))
+        .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .starts_with(&format!("sha256 mismatch: expected {sha256_hash}, got ")));
+        assert!(!dest_dir.path().join("sm-agent-26").exists());
+        assert!(!dest_dir.path().join("sm-agent-26.partial").exists());
+    }
+
+    #[test]
+    fn test_download_binary_resumes_partial_download() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        std::fs::write(dest_dir.path().join("sm-agent-26.partial"), &binary[..10]).unwrap();
+        let client = fake_agent_client(26, binary.clone(), sha256_hash);
+
+        let (_, path) = smol::block_on(download_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+        ))
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), binary);
+        assert!(!dest_dir.path().join("sm-agent-26.partial").exists());
+    }
+
+    #[test]
+    fn test_download_binary_restarts_when_range_is_ignored() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        std::fs::write(dest_dir.path().join("sm-agent-26.partial"), b"garbage").unwrap();
+        let client: Arc<dyn HttpClient> = FakeHttpClient::create({
+            let binary = binary.clone();
+            move |request| {
+                let body = if request.uri().path() == "/api/download-path" {
+                    AsyncBody::from(
+                        serde_json::json!({
+                            "downloadUrl": "https://example.com/sm-agent/26",
+                            "version": 26,
+                            "sha256Hash": sha256_hash,
+                        })
+                        .to_string(),
+                    )
+                } else {
+                    AsyncBody::from(binary.clone())
+                };
+                async move { Ok(Response::builder().status(200).body(body).unwrap()) }
+            }
+        });
+
+        let (_, path) = smol::block_on(download_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+        ))
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), binary);
+    }
+
+    #[test]
+    fn test_verify_installed_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sm-agent-26");
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        std::fs::write(&path, &binary).unwrap();
+
+        assert!(smol::block_on(verify_installed_binary(&path, &sha256_hash)).unwrap());
+        assert!(
+            smol::block_on(verify_installed_binary(&path, &sha256_hash.to_uppercase())).unwrap()
+        );
+
+        std::fs::write(&path, b"corrupt").unwrap();
+        assert!(!smol::block_on(verify_installed_binary(&path, &sha256_hash)).unwrap());
+
+        let missing = dir.path().join("sm-agent-27");
+        assert!(smol::block_on(verify_installed_binary(&missing, &sha256_hash)).is_err());
+    }
+
+    #[test]
+    fn test_ensure_binary_uses_installed_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        let installed = smol::block_on(download_binary(
+            fake_agent_client(26, binary.clone(), sha256_hash.clone()),
+            Platform::Linux,
+            Arch::X86_64,
+            dir.path(),
+        ))
+        .unwrap()
+        .1;
+
+        // The server now serves corrupt bytes, so any download would fail the sha256 check.
+        let mut corrupted = binary.clone();
+        corrupted[0] ^= 0xff;
+        let client = fake_agent_client(26, corrupted, sha256_hash);
+
+        let path = smol::block_on(ensure_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dir.path(),
+        ))
+        .unwrap();
+
+        assert_eq!(path, installed);
+        assert_eq!(std::fs::read(&path).unwrap(), binary);
+    }
+
+    #[test]
+    fn test_ensure_binary_downloads_without_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        std::fs::write(dir.path().join("sm-agent-26"), b"stale").unwrap();
+        let client = fake_agent_client(26, binary.clone(), sha256_hash);
+
+        let path = smol::block_on(ensure_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dir.path(),
+        ))
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), binary);
+        assert!(dir.path().join("sm-agent-26.json").exists());
+        assert_eq!(smol::block_on(installed_agent_version(&path)), Some(26));
+    }
+
+    #[test]
+    fn test_ensure_binary_redownloads_corrupt_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = b"#!/bin/sh\necho sm-agent\n".to_vec();
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        let client = fake_agent_client(26, binary.clone(), sha256_hash);
+        let path = smol::block_on(ensure_binary(
+            client.clone(),
+            Platform::Linux,
+            Arch::X86_64,
+            dir.path(),
+        ))
+        .unwrap();
+        std::fs::write(&path, b"corrupt").unwrap();
+
+        let path = smol::block_on(ensure_binary(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dir.path(),
+        ))
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), binary);
+    }
+
+    #[test]
+    fn test_download_binary_cancellation() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        let client: Arc<dyn HttpClient> = FakeHttpClient::create(|request| {
+            let response = if request.uri().path() == "/api/download-path" {
+                Response::builder().status(200).body(AsyncBody::from(
+                    serde_json::json!({
+                        "downloadUrl": "https://example.com/sm-agent/26",
+                        "version": 26,
+                        "sha256Hash": "0".repeat(64),
+                    })
+                    .to_string(),
+                ))
+            } else {
+                Response::builder()
+                    .status(200)
+                    .body(AsyncBody::from_reader(StalledBody(vec![42; 1024])))
+            };
+            async move { Ok(response.unwrap()) }
+        });
+        let (progress_tx, mut progress_rx) = mpsc::channel(16);
+
+        let error = smol::block_on(smol::future::or(
+            download_binary_with_progress(
+                client,
+                Platform::Linux,
+                Arch::X86_64,
+                dest_dir.path(),
+                progress_tx,
+            ),
+            async {
+                progress_rx.next().await;
+                Err(anyhow!("cancelled"))
+            },
+        ))
+        .unwrap_err();
+        assert_eq!(error.to_string(), "cancelled");
+
+        let partial_path = binary_path(dest_dir.path(), 26).with_extension("partial");
+        assert!(!partial_path.exists());
+        assert!(!binary_path(dest_dir.path(), 26).exists());
+        assert_eq!(std::fs::read_dir(dest_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest_path = dir.path().join("sm-agent-26");
+
+        let temp_file = TempFile::new(dir.path().join("sm-agent-26.unpacked"));
+        std::fs::write(temp_file.path(), b"agent").unwrap();
+        let temp_path = temp_file.path().to_path_buf();
+        drop(temp_file);
+        assert!(!temp_path.exists());
+
+        let temp_file = TempFile::new(temp_path.clone());
+        std::fs::write(temp_file.path(), b"agent").unwrap();
+        smol::block_on(temp_file.persist(&dest_path)).unwrap();
+        assert!(!temp_path.exists());
+        assert_eq!(std::fs::read(&dest_path).unwrap(), b"agent");
+
+        let temp_file = TempFile::new(temp_path.clone());
+        std::fs::write(temp_file.path(), b"partial").unwrap();
+        temp_file.keep();
+        assert_eq!(std::fs::read(&temp_path).unwrap(), b"partial");
+        std::fs::remove_file(&temp_path).unwrap();
+
+        // Dropping a guard whose file was never created is fine.
+        drop(TempFile::new(temp_path));
+    }
+
+    #[test]
+    fn test_download_binary_with_progress() {
+        let dest_dir = tempfile::tempdir().unwrap();
+        let binary = vec![42; 100 * 1024];
+        let sha256_hash = format!("{:x}", Sha256::digest(&binary));
+        let client = fake_agent_client(26, binary.clone(), sha256_hash);
+        let (progress_tx, progress_rx) = mpsc::channel(16);
+
+        smol::block_on(download_binary_with_progress(
+            client,
+            Platform::Linux,
+            Arch::X86_64,
+            dest_dir.path(),
+            progress_tx,
+        ))
+        .unwrap();
+
+        let progress = smol::block_on(progress_rx.collect::<Vec<_>>());
+        assert!(progress.len() > 1);
+        assert!(progress
+            .windows(2)
+            .all(|pair| pair[0].bytes_downloaded < pair[1].bytes_downloaded));
+        assert_eq!(
+            progress.last(),
+            Some(&DownloadProgress {
+                bytes_downloaded: binary.len() as u64,
+                total_bytes: Some(binary.len() as u64),
+            })
+        );
+    }
+
+    #[test]
+    fn test_classify_error() {
+        fn classify(responses: Vec<Option<(u16, &'static str)>>) -> Option<AdminApiErrorKind> {
+            let (client, _) = scripted_client(responses);
+            let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+                .with_retry_policy(0, Duration::ZERO);
+            let error = smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() }))
+                .unwrap_err();
+            classify_error(&error)
+        }
+
+        assert_eq!(
+            classify(vec![Some((401, r#"{"message": "Invalid admin API key"}"#))]),
+            Some(AdminApiErrorKind::Auth)
+        );
+        assert_eq!(
+            classify(vec![Some((403, "Forbidden"))]),
+            Some(AdminApiErrorKind::Auth)
+        );
+        assert_eq!(
+            classify(vec![Some((400, r#"{"message": "Bad request"}"#))]),
+            Some(AdminApiErrorKind::Client)
+        );
+        assert_eq!(
+            classify(vec![Some((409, r#"{"message": "Conflict"}"#))]),
+            Some(AdminApiErrorKind::Conflict)
+        );
+        assert_eq!(
+            classify(vec![Some((502, "Bad gateway"))]),
+            Some(AdminApiErrorKind::Server)
+        );
+        assert_eq!(classify(vec![None]), Some(AdminApiErrorKind::Network));
+        assert_eq!(
+            classify(vec![Some((200, "not json"))]),
+            Some(AdminApiErrorKind::Parse)
+        );
+
+        let client: Arc<dyn HttpClient> = FakeHttpClient::create(|_| futures::future::pending());
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client)
+            .with_retry_policy(0, Duration::ZERO)
+            .with_timeout(Duration::from_millis(10));
+        let error = smol::block_on(api.try_get_user(GetExternalUserRequest { id: "1".into() }))
+            .unwrap_err();
+        assert_eq!(classify_error(&error), Some(AdminApiErrorKind::Network));
+        assert!(AdminApiErrorKind::Network.is_retryable());
+        assert!(!AdminApiErrorKind::Auth.is_retryable());
+
+        let (client, _) = recording_client(200, "{}");
+        let api = SupermavenAdminApi::new_unchecked("admin-key", client);
+        let error = smol::block_on(api.try_create_user(CreateExternalUserRequest {
+            id: "1".into(),
+            email: "not an email".into(),
+        }))
+        .unwrap_err();
+        assert_eq!(classify_error(&error), None);
+    }
+}
@@ -8,6 +8,7 @@ use gpui::{AnyWindowHandle, AppContext, Context, ViewContext, WeakView};
 use language::language_settings::all_language_settings;
 use settings::SettingsStore;
 use supermaven::{Supermaven, SupermavenCompletionProvider};
+use workspace::Workspace;
 
 pub fn init(telemetry: Arc<Telemetry>, cx: &mut AppContext) {
     let editors: Rc<RefCell<HashMap<WeakView<Editor>, AnyWindowHandle>>> = Rc::default();
@@ -39,6 +40,14 @@ pub fn init(telemetry: Arc<Telemetry>, cx: &mut AppContext) {
     })
     .detach();
 
+    // Opening a project creates a new workspace, whose files the agent's state doesn't cover.
+    cx.observe_new_views(|_: &mut Workspace, cx: &mut ViewContext<Workspace>| {
+        if let Some(supermaven) = Supermaven::global(cx) {
+            supermaven.update(cx, |supermaven, _cx| supermaven.reset());
+        }
+    })
+    .detach();
+
     let mut provider = all_language_settings(None, cx).inline_completions.provider;
     for (editor, window) in editors.borrow().iter() {
         _ = window.update(cx, |_window, cx| {